@@ -0,0 +1,513 @@
+//! Adaptive data rate.
+//!
+//! With ADR, the network tunes the data rate, output power and number of transmissions of each device from the
+//! link margin it observes, and sends the result in `LinkADRReq` commands. [`Adr`] holds the settings of the
+//! device and applies those commands, and runs the device side of the protocol: after [`ADR_ACK_LIMIT`] uplinks
+//! without any downlink it requests an answer from the network, and then progressively falls back to more
+//! robust settings until it hears from the network again.
+//!
+//! Attached to a [`LorawanRadio`](crate::lorawan::LorawanRadio), the `LinkADRReq` commands found in the FOpts
+//! of the downlinks are applied, and the uplinks are sent at the data rate and output power of [`Adr`] instead of
+//! the ones picked by the MAC. The channel mask and the number of transmissions are left to the MAC, which picks
+//! the channel and repeats the uplinks.
+//!
+//! Networks that do not drive ADR, e.g. private networks without a capable network server, can let the device
+//! adapt on its own with [`AdrMode::Device`], from the margins of the downlinks it receives recorded in
+//! [`LinkStats`].
+
+use lora_phy::mod_params::{Bandwidth, SpreadingFactor};
+
+use crate::region::RegionConfig;
+
+/// Uplinks without downlink after which the device requests an answer from the network.
+pub const ADR_ACK_LIMIT: u16 = 64;
+/// Uplinks without answer between two steps of the fallback to more robust settings.
+pub const ADR_ACK_DELAY: u16 = 32;
+
+/// Margin kept on top of the demodulation floor by the device-side algorithm, in dB.
+const INSTALLATION_MARGIN_DB: i16 = 10;
+/// Downlinks over which the margin is estimated.
+const HISTORY: usize = 8;
+
+/// Signal-to-noise ratio below which a spreading factor cannot be demodulated, in dB.
+pub fn demodulation_floor_db(spreading_factor: SpreadingFactor) -> i8 {
+    match spreading_factor {
+        SpreadingFactor::_5 => -2,
+        SpreadingFactor::_6 => -5,
+        SpreadingFactor::_7 => -7,
+        SpreadingFactor::_8 => -10,
+        SpreadingFactor::_9 => -12,
+        SpreadingFactor::_10 => -15,
+        SpreadingFactor::_11 => -17,
+        SpreadingFactor::_12 => -20,
+    }
+}
+
+/// Statistics of the link, as seen from the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LinkStats {
+    margins: [i8; HISTORY],
+    downlinks: u32,
+    uplinks_since_downlink: u16,
+}
+
+impl LinkStats {
+    /// Create empty statistics.
+    pub const fn new() -> Self {
+        Self {
+            margins: [0; HISTORY],
+            downlinks: 0,
+            uplinks_since_downlink: 0,
+        }
+    }
+
+    /// Record an uplink.
+    pub fn on_uplink(&mut self) {
+        self.uplinks_since_downlink = self.uplinks_since_downlink.saturating_add(1);
+    }
+
+    /// Record a downlink received with the given SNR and spreading factor.
+    pub fn on_downlink(&mut self, snr: i8, spreading_factor: SpreadingFactor) {
+        let margin = snr.saturating_sub(demodulation_floor_db(spreading_factor));
+        self.margins[self.downlinks as usize % HISTORY] = margin;
+        self.downlinks = self.downlinks.wrapping_add(1);
+        self.uplinks_since_downlink = 0;
+    }
+
+    /// Number of downlinks received.
+    pub fn downlinks(&self) -> u32 {
+        self.downlinks
+    }
+
+    /// Number of uplinks sent since the last downlink, the ADR_ACK_CNT of the specification.
+    pub fn uplinks_since_downlink(&self) -> u16 {
+        self.uplinks_since_downlink
+    }
+
+    /// Best SNR margin over the demodulation floor among the last downlinks, in dB, or `None` before any
+    /// downlink.
+    pub fn max_margin_db(&self) -> Option<i8> {
+        let recorded = (self.downlinks as usize).min(HISTORY);
+        self.margins[..recorded].iter().copied().max()
+    }
+}
+
+impl Default for LinkStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Who adapts the data rate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AdrMode {
+    /// ADR is disabled, the settings only change when the application sets them.
+    Off,
+    /// The network drives ADR with `LinkADRReq` commands.
+    Network,
+    /// The device adapts on its own, from the margin of the downlinks it receives.
+    Device,
+}
+
+/// Settings accepted by the region of the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct AdrLimits {
+    /// Lowest, most robust, data rate.
+    pub min_data_rate: u8,
+    /// Highest data rate.
+    pub max_data_rate: u8,
+    /// Highest TX power index, the lowest output power. Index 0 is the maximum output power.
+    pub max_tx_power: u8,
+    /// Number of channels of the region, at most 80.
+    pub channels: u8,
+}
+
+/// A `LinkADRReq` MAC command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LinkAdrReq {
+    /// Data rate, 0xF to keep the current one.
+    pub data_rate: u8,
+    /// TX power index, 0xF to keep the current one.
+    pub tx_power: u8,
+    /// Mask of the channels enabled within the block selected by `ch_mask_cntl`.
+    pub ch_mask: u16,
+    /// Channel mask control.
+    pub ch_mask_cntl: u8,
+    /// Number of transmissions of each uplink, 0 to keep the current one.
+    pub nb_trans: u8,
+}
+
+impl LinkAdrReq {
+    /// Parse the 4-byte payload of the command, following its command identifier.
+    pub fn parse(payload: &[u8]) -> Option<Self> {
+        match payload {
+            [dr_power, mask_lo, mask_hi, redundancy, ..] => Some(Self {
+                data_rate: dr_power >> 4,
+                tx_power: dr_power & 0x0f,
+                ch_mask: u16::from_le_bytes([*mask_lo, *mask_hi]),
+                ch_mask_cntl: (redundancy >> 4) & 0x07,
+                nb_trans: redundancy & 0x0f,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// `LinkADRReq` commands carried in the FOpts of a LoRaWAN 1.0 downlink PHY payload, where they are not encrypted.
+///
+/// The commands are returned in order, and parsing stops at the first unknown command.
+pub fn link_adr_reqs(phy_payload: &[u8]) -> impl Iterator<Item = LinkAdrReq> + '_ {
+    // Unconfirmed or confirmed data down: MHDR, DevAddr, FCtrl, FCnt, then FOpts
+    let fopts = match phy_payload {
+        [mhdr, _, _, _, _, fctrl, _, _, rest @ ..] if matches!(mhdr >> 5, 3 | 5) => {
+            rest.get(..(fctrl & 0x0f) as usize).unwrap_or(&[])
+        }
+        _ => &[],
+    };
+    let mut rest = fopts;
+    core::iter::from_fn(move || loop {
+        let (cid, params) = rest.split_first()?;
+        let len = match cid {
+            0x03 | 0x05 | 0x0a => 4,
+            0x02 => 2,
+            0x04 | 0x08 | 0x09 => 1,
+            0x06 => 0,
+            0x07 | 0x0d => 5,
+            _ => return None,
+        };
+        let params = params.get(..len)?;
+        rest = &rest[1 + len..];
+        if *cid == 0x03 {
+            return LinkAdrReq::parse(params);
+        }
+    })
+}
+
+/// Status bit of a `LinkADRAns` acknowledging the channel mask.
+pub const CHANNEL_MASK_ACK: u8 = 0x01;
+/// Status bit of a `LinkADRAns` acknowledging the data rate.
+pub const DATA_RATE_ACK: u8 = 0x02;
+/// Status bit of a `LinkADRAns` acknowledging the TX power.
+pub const POWER_ACK: u8 = 0x04;
+
+/// ADR settings of a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Adr {
+    mode: AdrMode,
+    region: RegionConfig,
+    limits: AdrLimits,
+    data_rate: u8,
+    tx_power: u8,
+    nb_trans: u8,
+    channel_mask: [u16; 5],
+    evaluated_downlinks: u32,
+}
+
+impl Adr {
+    /// Start at the most robust data rate and maximum output power of `region`, with every channel enabled.
+    pub fn new(mode: AdrMode, region: RegionConfig) -> Self {
+        let limits = region.adr_limits();
+        let mut adr = Self {
+            mode,
+            region,
+            limits,
+            data_rate: limits.min_data_rate,
+            tx_power: 0,
+            nb_trans: 1,
+            channel_mask: [0; 5],
+            evaluated_downlinks: 0,
+        };
+        adr.enable_all_channels();
+        adr
+    }
+
+    /// Who adapts the data rate.
+    pub fn mode(&self) -> AdrMode {
+        self.mode
+    }
+
+    /// Change who adapts the data rate. The ADR bit of the uplinks must be set in [`AdrMode::Network`].
+    pub fn set_mode(&mut self, mode: AdrMode) {
+        self.mode = mode;
+    }
+
+    /// Data rate of the next uplinks.
+    pub fn data_rate(&self) -> u8 {
+        self.data_rate
+    }
+
+    /// TX power index of the next uplinks.
+    pub fn tx_power(&self) -> u8 {
+        self.tx_power
+    }
+
+    /// Modulation of the data rate of the next uplinks, or `None` if it is not a LoRa data rate.
+    pub fn modulation(&self) -> Option<(SpreadingFactor, Bandwidth)> {
+        self.region.data_rate(self.data_rate)
+    }
+
+    /// Output power of the next uplinks in dBm.
+    pub fn tx_power_dbm(&self) -> i32 {
+        unwrap!(self.region.tx_power_dbm(self.tx_power))
+    }
+
+    /// Number of transmissions of each uplink.
+    pub fn nb_trans(&self) -> u8 {
+        self.nb_trans
+    }
+
+    /// Mask of the enabled channels, channel `n` being bit `n % 16` of word `n / 16`.
+    pub fn channel_mask(&self) -> [u16; 5] {
+        self.channel_mask
+    }
+
+    /// Set the channels enabled, e.g. to restrict the device to a sub-band at startup.
+    pub fn set_channel_mask(&mut self, channel_mask: [u16; 5]) {
+        self.channel_mask = channel_mask;
+    }
+
+    /// Set the data rate and TX power, e.g. when ADR is off.
+    pub fn set(&mut self, data_rate: u8, tx_power: u8) {
+        self.data_rate = data_rate.clamp(self.limits.min_data_rate, self.limits.max_data_rate);
+        self.tx_power = tx_power.min(self.limits.max_tx_power);
+    }
+
+    /// Apply a `LinkADRReq`, returning the status of the `LinkADRAns` to send back.
+    ///
+    /// The command is only applied if every part of it is acknowledged.
+    pub fn apply(&mut self, req: &LinkAdrReq) -> u8 {
+        let data_rate = match req.data_rate {
+            0xf => Some(self.data_rate),
+            dr if (self.limits.min_data_rate..=self.limits.max_data_rate).contains(&dr) => Some(dr),
+            _ => None,
+        };
+        let tx_power = match req.tx_power {
+            0xf => Some(self.tx_power),
+            power if power <= self.limits.max_tx_power => Some(power),
+            _ => None,
+        };
+        let channel_mask = self.masked(req.ch_mask_cntl, req.ch_mask);
+
+        let status = channel_mask.map_or(0, |_| CHANNEL_MASK_ACK)
+            | data_rate.map_or(0, |_| DATA_RATE_ACK)
+            | tx_power.map_or(0, |_| POWER_ACK);
+        if let (Some(data_rate), Some(tx_power), Some(channel_mask)) = (data_rate, tx_power, channel_mask) {
+            self.data_rate = data_rate;
+            self.tx_power = tx_power;
+            self.channel_mask = channel_mask;
+            if req.nb_trans != 0 {
+                self.nb_trans = req.nb_trans;
+            }
+            debug!(
+                "ADR: DR{}, TX power {}, {} transmissions",
+                data_rate, tx_power, self.nb_trans
+            );
+        } else {
+            warn!("LinkADRReq rejected, status {}", status);
+        }
+        status
+    }
+
+    /// Update the settings before an uplink, returning whether the ADRACKReq bit must be set.
+    ///
+    /// Call this with the statistics of the link before each uplink, its data rate and TX power may change.
+    pub fn on_uplink(&mut self, stats: &LinkStats) -> bool {
+        match self.mode {
+            AdrMode::Off => false,
+            AdrMode::Network => self.back_off(stats.uplinks_since_downlink()),
+            AdrMode::Device => {
+                if stats.downlinks() >= self.evaluated_downlinks.wrapping_add(HISTORY as u32) {
+                    self.evaluated_downlinks = stats.downlinks();
+                    if let Some(margin) = stats.max_margin_db() {
+                        self.adapt(margin);
+                    }
+                }
+                self.back_off(stats.uplinks_since_downlink());
+                false
+            }
+        }
+    }
+
+    /// Fall back to more robust settings while the network does not answer.
+    fn back_off(&mut self, uplinks_since_downlink: u16) -> bool {
+        if uplinks_since_downlink < ADR_ACK_LIMIT {
+            return false;
+        }
+        let excess = uplinks_since_downlink - ADR_ACK_LIMIT;
+        if excess > 0 && excess % ADR_ACK_DELAY == 0 {
+            // Restore the output power first, then lower the data rate step by step
+            if self.tx_power > 0 {
+                self.tx_power = 0;
+            } else if self.data_rate > self.limits.min_data_rate {
+                self.data_rate -= 1;
+            } else {
+                self.nb_trans = 1;
+                self.enable_all_channels();
+            }
+            debug!("ADR backoff: DR{}, TX power {}", self.data_rate, self.tx_power);
+        }
+        true
+    }
+
+    /// Trade the margin over the installation margin for data rate first, then for output power, and make up for
+    /// a missing margin with output power first, then with data rate.
+    fn adapt(&mut self, margin_db: i8) {
+        let mut steps = (margin_db as i16 - INSTALLATION_MARGIN_DB) / 3;
+        while steps > 0 && self.data_rate < self.limits.max_data_rate {
+            self.data_rate += 1;
+            steps -= 1;
+        }
+        while steps > 0 && self.tx_power < self.limits.max_tx_power {
+            self.tx_power += 1;
+            steps -= 1;
+        }
+        while steps < 0 && self.tx_power > 0 {
+            self.tx_power -= 1;
+            steps += 1;
+        }
+        while steps < 0 && self.data_rate > self.limits.min_data_rate {
+            self.data_rate -= 1;
+            steps += 1;
+        }
+        debug!(
+            "ADR: {} dB margin, DR{}, TX power {}",
+            margin_db, self.data_rate, self.tx_power
+        );
+    }
+
+    /// The channel mask after applying `ch_mask` to the block `ch_mask_cntl`, or `None` if invalid.
+    fn masked(&self, ch_mask_cntl: u8, ch_mask: u16) -> Option<[u16; 5]> {
+        let mut channel_mask = self.channel_mask;
+        match ch_mask_cntl {
+            block @ 0..=4 if (block as usize) * 16 < self.limits.channels as usize => {
+                channel_mask[block as usize] = ch_mask & Self::block_mask(self.limits.channels, block);
+            }
+            // All 125 kHz channels on or off, the mask then applies to the 500 kHz channels 64 to 71
+            6 | 7 if self.limits.channels > 64 => {
+                let all = if ch_mask_cntl == 6 { 0xffff } else { 0 };
+                channel_mask[..4].fill(all);
+                channel_mask[4] = ch_mask & Self::block_mask(self.limits.channels, 4);
+            }
+            // All defined channels on, whatever the mask
+            6 if self.limits.channels <= 16 => {
+                channel_mask = [Self::block_mask(self.limits.channels, 0), 0, 0, 0, 0];
+            }
+            _ => return None,
+        }
+        channel_mask.iter().any(|block| *block != 0).then_some(channel_mask)
+    }
+
+    fn block_mask(channels: u8, block: u8) -> u16 {
+        match channels.saturating_sub(block * 16) {
+            0 => 0,
+            n if n >= 16 => 0xffff,
+            n => (1 << n) - 1,
+        }
+    }
+
+    fn enable_all_channels(&mut self) {
+        for (block, mask) in self.channel_mask.iter_mut().enumerate() {
+            *mask = Self::block_mask(self.limits.channels, block as u8);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::region::Region;
+
+    const EU868: RegionConfig = RegionConfig::new(Region::Eu868);
+
+    #[test]
+    fn link_adr_req() {
+        let mut adr = Adr::new(AdrMode::Network, EU868);
+        let req = LinkAdrReq::parse(&[0x53, 0x07, 0x00, 0x02]).unwrap();
+        assert_eq!(adr.apply(&req), CHANNEL_MASK_ACK | DATA_RATE_ACK | POWER_ACK);
+        assert_eq!((adr.data_rate(), adr.tx_power(), adr.nb_trans()), (5, 3, 2));
+        assert_eq!(adr.channel_mask()[0], 0x0007);
+
+        // An invalid data rate rejects the whole command
+        let req = LinkAdrReq::parse(&[0x71, 0xff, 0x00, 0x00]).unwrap();
+        assert_eq!(adr.apply(&req), CHANNEL_MASK_ACK | POWER_ACK);
+        assert_eq!((adr.data_rate(), adr.tx_power()), (5, 3));
+        // So does disabling every channel
+        let req = LinkAdrReq::parse(&[0xff, 0x00, 0x00, 0x00]).unwrap();
+        assert_eq!(adr.apply(&req), DATA_RATE_ACK | POWER_ACK);
+        // All channels on, the mask being ignored
+        let req = LinkAdrReq::parse(&[0x53, 0x00, 0x00, 0x61]).unwrap();
+        assert_eq!(adr.apply(&req), CHANNEL_MASK_ACK | DATA_RATE_ACK | POWER_ACK);
+        assert_eq!(adr.channel_mask()[0], 0xffff);
+    }
+
+    #[test]
+    fn backoff_without_downlinks() {
+        let mut adr = Adr::new(AdrMode::Network, EU868);
+        adr.set(5, 3);
+        let mut stats = LinkStats::new();
+        let mut requested = 0;
+        for _ in 0..ADR_ACK_LIMIT + 3 * ADR_ACK_DELAY {
+            stats.on_uplink();
+            if adr.on_uplink(&stats) {
+                requested += 1;
+            }
+        }
+        assert_eq!(requested, 3 * ADR_ACK_DELAY + 1);
+        assert_eq!((adr.data_rate(), adr.tx_power()), (3, 0));
+
+        stats.on_downlink(0, SpreadingFactor::_9);
+        assert!(!adr.on_uplink(&stats));
+    }
+
+    #[test]
+    fn device_side_adaptation() {
+        let mut adr = Adr::new(AdrMode::Device, EU868);
+        let mut stats = LinkStats::new();
+        for _ in 0..HISTORY {
+            stats.on_uplink();
+            stats.on_downlink(5, SpreadingFactor::_12);
+            adr.on_uplink(&stats);
+        }
+        // 25 dB over the floor leaves 15 dB over the installation margin, 5 steps
+        assert_eq!(stats.max_margin_db(), Some(25));
+        assert_eq!((adr.data_rate(), adr.tx_power()), (5, 0));
+        assert_eq!(adr.modulation(), Some((SpreadingFactor::_7, Bandwidth::_125KHz)));
+    }
+
+    #[test]
+    fn device_side_recovery() {
+        let mut adr = Adr::new(AdrMode::Device, EU868);
+        adr.set(5, 2);
+        let mut stats = LinkStats::new();
+        for _ in 0..HISTORY {
+            stats.on_uplink();
+            stats.on_downlink(-15, SpreadingFactor::_7);
+            adr.on_uplink(&stats);
+        }
+        // 8 dB under the floor is 18 dB short of the installation margin, 6 steps: power first, then data rate
+        assert_eq!(stats.max_margin_db(), Some(-8));
+        assert_eq!((adr.data_rate(), adr.tx_power()), (1, 0));
+        assert_eq!(adr.tx_power_dbm(), 16);
+    }
+
+    #[test]
+    fn link_adr_reqs_in_fopts() {
+        // Unconfirmed downlink with a DevStatusReq and a LinkADRReq in its FOpts
+        let downlink = [
+            0x60, 0x01, 0x02, 0x03, 0x04, 0x06, 0x00, 0x00, 0x06, 0x03, 0x53, 0x07, 0x00, 0x02, 0x01,
+        ];
+        let mut reqs = link_adr_reqs(&downlink);
+        assert_eq!(reqs.next().map(|req| (req.data_rate, req.tx_power)), Some((5, 3)));
+        assert_eq!(reqs.next(), None);
+        // Uplinks carry none
+        assert_eq!(
+            link_adr_reqs(&[0x40, 1, 2, 3, 4, 0x05, 0, 0, 0x03, 0x53, 7, 0, 2]).count(),
+            0
+        );
+    }
+}
@@ -0,0 +1,121 @@
+//! Time-on-air calculations for LoRa packets.
+//!
+//! The formulas follow the Semtech SX127x/SX126x datasheets. Results are in microseconds, which is precise
+//! enough for dwell time and duty cycle accounting while still fitting the longest SF12 packets in a `u32`.
+
+use lora_phy::mod_params::{Bandwidth, CodingRate, SpreadingFactor};
+
+/// Bandwidth in Hz.
+pub fn bandwidth_in_hz(bandwidth: Bandwidth) -> u32 {
+    match bandwidth {
+        Bandwidth::_7KHz => 7_810,
+        Bandwidth::_10KHz => 10_420,
+        Bandwidth::_15KHz => 15_630,
+        Bandwidth::_20KHz => 20_830,
+        Bandwidth::_31KHz => 31_250,
+        Bandwidth::_41KHz => 41_670,
+        Bandwidth::_62KHz => 62_500,
+        Bandwidth::_125KHz => 125_000,
+        Bandwidth::_250KHz => 250_000,
+        Bandwidth::_500KHz => 500_000,
+    }
+}
+
+fn spreading_factor_value(spreading_factor: SpreadingFactor) -> u32 {
+    match spreading_factor {
+        SpreadingFactor::_5 => 5,
+        SpreadingFactor::_6 => 6,
+        SpreadingFactor::_7 => 7,
+        SpreadingFactor::_8 => 8,
+        SpreadingFactor::_9 => 9,
+        SpreadingFactor::_10 => 10,
+        SpreadingFactor::_11 => 11,
+        SpreadingFactor::_12 => 12,
+    }
+}
+
+fn coding_rate_value(coding_rate: CodingRate) -> u32 {
+    match coding_rate {
+        CodingRate::_4_5 => 1,
+        CodingRate::_4_6 => 2,
+        CodingRate::_4_7 => 3,
+        CodingRate::_4_8 => 4,
+    }
+}
+
+/// Duration of a single LoRa symbol in microseconds.
+pub fn symbol_time_us(spreading_factor: SpreadingFactor, bandwidth: Bandwidth) -> u32 {
+    ((1u64 << spreading_factor_value(spreading_factor)) * 1_000_000 / bandwidth_in_hz(bandwidth) as u64) as u32
+}
+
+/// Time on air of a LoRa packet in microseconds.
+///
+/// Low data rate optimization is assumed to be enabled whenever the symbol time reaches 16 ms, which is what
+/// the radio drivers do.
+pub fn time_on_air_us(
+    spreading_factor: SpreadingFactor,
+    bandwidth: Bandwidth,
+    coding_rate: CodingRate,
+    preamble_length: u16,
+    implicit_header: bool,
+    crc_on: bool,
+    payload_len: usize,
+) -> u32 {
+    let sf = spreading_factor_value(spreading_factor) as i64;
+    let cr = coding_rate_value(coding_rate) as i64;
+    let ldro = symbol_time_us(spreading_factor, bandwidth) >= 16_000;
+
+    let numerator =
+        8 * payload_len as i64 - 4 * sf + 28 + if crc_on { 16 } else { 0 } - if implicit_header { 20 } else { 0 };
+    let denominator = 4 * (sf - if ldro { 2 } else { 0 });
+    let payload_symbols = 8 + (numerator.max(0) + denominator - 1) / denominator * (cr + 4);
+
+    // Work in quarter symbols to account for the 4.25 symbols of the sync word and SFD.
+    let quarter_symbols = (preamble_length as u64 * 4 + 17) + payload_symbols as u64 * 4;
+    let bw = bandwidth_in_hz(bandwidth) as u64;
+    ((quarter_symbols << sf) * 1_000_000 / (4 * bw)) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn symbol_time() {
+        assert_eq!(symbol_time_us(SpreadingFactor::_7, Bandwidth::_125KHz), 1_024);
+        assert_eq!(symbol_time_us(SpreadingFactor::_12, Bandwidth::_125KHz), 32_768);
+        assert_eq!(symbol_time_us(SpreadingFactor::_12, Bandwidth::_500KHz), 8_192);
+    }
+
+    #[test]
+    fn time_on_air() {
+        let toa = |sf, bw, len| time_on_air_us(sf, bw, CodingRate::_4_5, 8, false, true, len);
+        assert_eq!(toa(SpreadingFactor::_7, Bandwidth::_125KHz, 10), 41_216);
+        assert_eq!(toa(SpreadingFactor::_12, Bandwidth::_125KHz, 51), 2_465_792);
+        assert_eq!(toa(SpreadingFactor::_9, Bandwidth::_125KHz, 0), 103_424);
+        assert_eq!(toa(SpreadingFactor::_7, Bandwidth::_500KHz, 222), 87_104);
+    }
+
+    #[test]
+    fn implicit_header_without_crc_is_shorter() {
+        let explicit = time_on_air_us(
+            SpreadingFactor::_7,
+            Bandwidth::_125KHz,
+            CodingRate::_4_5,
+            8,
+            false,
+            true,
+            20,
+        );
+        let implicit = time_on_air_us(
+            SpreadingFactor::_7,
+            Bandwidth::_125KHz,
+            CodingRate::_4_5,
+            8,
+            true,
+            false,
+            20,
+        );
+        assert!(implicit < explicit);
+    }
+}
@@ -0,0 +1,507 @@
+//! LoRaWAN Class B beacon tracking and ping slots.
+//!
+//! Class B devices open short receive windows, the ping slots, at times derived from beacons broadcast by the
+//! gateways every 128 seconds. [`ClassB`] drives a [`LorawanRadio`] through that schedule:
+//!
+//! - without a time reference, it searches for a beacon by listening continuously,
+//! - once aligned to the GPS time, either by a beacon or by the network time given to [`ClassB::align`], e.g.
+//!   from a `DeviceTimeAns` or a GPS receiver, or by an [`AppClock`] given to [`ClassB::align_to`], it only wakes
+//!   up for the beacons and the ping slots of the device,
+//! - the beacons keep the alignment up to date, and it is lost after two hours without any.
+//!
+//! lorawan-device has no Class B MAC: the application requests the ping slot periodicity with a
+//! `PingSlotInfoReq`, and hands the downlinks returned by [`ClassB::next_event`] to its MAC.
+
+use aes::cipher::{BlockEncrypt, KeyInit};
+use aes::Aes128;
+use embassy_time::{Duration, Instant, Timer};
+use embedded_hal_async::delay::DelayUs;
+use lora_phy::mod_params::RadioError;
+use lora_phy::mod_traits::RadioKind;
+use lorawan_device::async_device::radio::RfConfig;
+
+use crate::airtime;
+use crate::clock_sync::AppClock;
+use crate::lorawan::{self, LorawanRadio};
+use crate::p2p::CarrierSense;
+
+/// Period of the beacons in seconds.
+pub const BEACON_PERIOD_SECS: u32 = 128;
+/// Time reserved for the beacon at the start of each beacon period.
+pub const BEACON_RESERVED: Duration = Duration::from_millis(2_120);
+/// Length of a ping slot.
+pub const PING_SLOT_LENGTH: Duration = Duration::from_millis(30);
+/// Number of ping slots in a beacon period.
+pub const PING_SLOTS: u16 = 4096;
+/// Beacons that can be missed before the alignment is lost, two hours worth.
+pub const MAX_MISSED_BEACONS: u8 = 56;
+
+// Beacon and ping slot channels of US915 and AU915
+const HOPPING_FIRST_HZ: u32 = 923_300_000;
+const HOPPING_STEP_HZ: u32 = 600_000;
+const HOPPING_CHANNELS: u32 = 8;
+
+const MAX_BEACON_LENGTH: usize = 23;
+const BEACON_PREAMBLE_LENGTH: u16 = 10;
+// How early the beacon window opens, per beacon missed since the last one received
+const BEACON_MARGIN_MS: u64 = 20;
+
+/// Errors reported by the Class B scheduler.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The radio reported an error.
+    Radio(lorawan::Error),
+    /// The frame received is not a valid beacon.
+    InvalidBeacon,
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::Radio(err) => write!(f, "{}", err),
+            Error::InvalidBeacon => write!(f, "invalid beacon"),
+        }
+    }
+}
+
+impl core::error::Error for Error {}
+
+impl From<lorawan::Error> for Error {
+    fn from(err: lorawan::Error) -> Self {
+        Error::Radio(err)
+    }
+}
+
+/// Layout and channels of the beacons of a region, which differ by the size of their reserved fields and by
+/// whether beacons and ping slots hop between channels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct BeaconFormat {
+    rfu1: usize,
+    rfu2: usize,
+    hopping: bool,
+}
+
+impl BeaconFormat {
+    /// Beacons of EU868, AS923 and the other regions using 17-byte beacons on a single channel.
+    pub const EU868: Self = Self {
+        rfu1: 2,
+        rfu2: 0,
+        hopping: false,
+    };
+    /// Beacons of US915 and AU915, hopping over 8 channels from 923.3 MHz along with the ping slots.
+    pub const US915: Self = Self {
+        rfu1: 5,
+        rfu2: 3,
+        hopping: true,
+    };
+
+    /// Size of a beacon in bytes.
+    pub const fn size(&self) -> usize {
+        self.rfu1 + 4 + 2 + 7 + self.rfu2 + 2
+    }
+
+    /// Frequency of the beacon sent at `beacon_time`, or `None` if the beacons do not hop.
+    pub fn beacon_frequency(&self, beacon_time: u32) -> Option<u32> {
+        self.hopping
+            .then(|| hopping_frequency(beacon_time / BEACON_PERIOD_SECS))
+    }
+
+    /// Frequency of the ping slots of `dev_addr` in the beacon period starting at `beacon_time`, or `None` if the
+    /// ping slots do not hop.
+    pub fn ping_frequency(&self, beacon_time: u32, dev_addr: u32) -> Option<u32> {
+        self.hopping
+            .then(|| hopping_frequency(dev_addr.wrapping_add(beacon_time / BEACON_PERIOD_SECS)))
+    }
+}
+
+/// Frequency of the hopping channel `n`, modulo the number of channels.
+fn hopping_frequency(n: u32) -> u32 {
+    HOPPING_FIRST_HZ + HOPPING_STEP_HZ * (n % HOPPING_CHANNELS)
+}
+
+/// A beacon received from a gateway.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Beacon {
+    /// GPS time of the start of the beacon, in seconds modulo 2^32.
+    pub time: u32,
+    /// Gateway-specific part, e.g. the gateway coordinates, or `None` if its CRC failed.
+    pub gw_specific: Option<[u8; 7]>,
+}
+
+impl Beacon {
+    /// Parse a beacon, checking the CRC of its network common part.
+    pub fn parse(format: BeaconFormat, frame: &[u8]) -> Result<Self, Error> {
+        if frame.len() != format.size() {
+            return Err(Error::InvalidBeacon);
+        }
+        let (common, gw) = frame.split_at(format.rfu1 + 4 + 2);
+        let (common, crc) = common.split_at(format.rfu1 + 4);
+        if crc16(common).to_le_bytes() != crc {
+            return Err(Error::InvalidBeacon);
+        }
+        let time = u32::from_le_bytes(unwrap!(common[format.rfu1..].try_into()));
+
+        let (gw, crc) = gw.split_at(7 + format.rfu2);
+        let gw_specific = (crc16(gw).to_le_bytes() == crc).then(|| unwrap!(gw[..7].try_into()));
+        Ok(Self { time, gw_specific })
+    }
+}
+
+/// CRC-16/CCITT with a zero initial value, as used by the beacons.
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0, |crc, byte| {
+        (0..8).fold(crc ^ (*byte as u16) << 8, |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// Ping slots of a device, derived from its address and the periodicity agreed with the network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PingSlots {
+    dev_addr: u32,
+    periodicity: u8,
+}
+
+impl PingSlots {
+    /// Ping slots of `dev_addr`, opening every 2^`periodicity` seconds, `periodicity` being at most 7.
+    pub fn new(dev_addr: u32, periodicity: u8) -> Self {
+        Self {
+            dev_addr,
+            periodicity: periodicity.min(7),
+        }
+    }
+
+    /// Number of ping slots opened per beacon period.
+    pub fn ping_nb(&self) -> u16 {
+        1 << (7 - self.periodicity)
+    }
+
+    /// Number of slots between two ping slots of the device.
+    pub fn ping_period(&self) -> u16 {
+        PING_SLOTS / self.ping_nb()
+    }
+
+    /// Pseudo-random offset of the first ping slot in the beacon period starting at `beacon_time`.
+    pub fn offset(&self, beacon_time: u32) -> u16 {
+        let mut block = [0; 16];
+        block[..4].copy_from_slice(&beacon_time.to_le_bytes());
+        block[4..8].copy_from_slice(&self.dev_addr.to_le_bytes());
+        let block = aes::Block::from_mut_slice(&mut block);
+        Aes128::new(&[0; 16].into()).encrypt_block(block);
+        u16::from_le_bytes([block[0], block[1]]) % self.ping_period()
+    }
+
+    /// Start of ping slot `n`, relative to the start of the beacon period starting at `beacon_time`.
+    pub fn slot(&self, beacon_time: u32, n: u16) -> Duration {
+        let slot = self.offset(beacon_time) as u32 + n as u32 * self.ping_period() as u32;
+        BEACON_RESERVED + PING_SLOT_LENGTH * slot
+    }
+}
+
+/// Alignment of the local clock to the GPS time, kept up to date by the beacons.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BeaconClock {
+    reference: Option<(u64, Instant)>,
+    missed: u8,
+}
+
+impl BeaconClock {
+    /// Create a clock without any time reference.
+    pub const fn new() -> Self {
+        Self {
+            reference: None,
+            missed: 0,
+        }
+    }
+
+    /// Align the clock to the GPS time `gps_time_ms`, in milliseconds, observed at `at`.
+    pub fn align(&mut self, gps_time_ms: u64, at: Instant) {
+        self.reference = Some((gps_time_ms, at));
+    }
+
+    /// Align the clock to a beacon whose transmission started at `started_at`.
+    pub fn on_beacon(&mut self, beacon: &Beacon, started_at: Instant) {
+        self.align(beacon.time as u64 * 1000, started_at);
+        self.missed = 0;
+    }
+
+    /// Record a beacon missed, dropping the alignment after [`MAX_MISSED_BEACONS`].
+    pub fn on_beacon_missed(&mut self) {
+        self.missed = self.missed.saturating_add(1);
+        if self.missed > MAX_MISSED_BEACONS {
+            warn!("beacon alignment lost");
+            self.reference = None;
+            self.missed = 0;
+        }
+    }
+
+    /// Whether the clock is aligned to the GPS time.
+    pub fn is_aligned(&self) -> bool {
+        self.reference.is_some()
+    }
+
+    /// Number of beacons missed since the last one received.
+    pub fn missed(&self) -> u8 {
+        self.missed
+    }
+
+    /// GPS time in seconds and local start of the first beacon after `now`.
+    pub fn next_beacon(&self, now: Instant) -> Option<(u32, Instant)> {
+        let (gps_time_ms, at) = self.reference?;
+        let elapsed_ms = now.checked_duration_since(at).map_or(0, |elapsed| elapsed.as_millis());
+        let period_ms = BEACON_PERIOD_SECS as u64 * 1000;
+        let next_ms = ((gps_time_ms + elapsed_ms) / period_ms + 1) * period_ms;
+        Some((
+            (next_ms / 1000) as u32,
+            at + Duration::from_millis(next_ms - gps_time_ms),
+        ))
+    }
+}
+
+/// What happened during a call to [`ClassB::next_event`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Event {
+    /// A beacon was received and the clock aligned to it.
+    Beacon(Beacon),
+    /// The beacon expected was not received.
+    BeaconMissed,
+    /// A downlink was received in a ping slot, in the first `len` bytes of the buffer.
+    Downlink { len: usize, rssi: i16, snr: i8 },
+    /// A ping slot passed without downlink.
+    Idle,
+}
+
+/// A LoRaWAN radio operated as a Class B device.
+pub struct ClassB<'a, RK, DLY, CS>
+where
+    RK: RadioKind,
+    DLY: DelayUs,
+    CS: CarrierSense,
+{
+    radio: &'a mut LorawanRadio<RK, DLY, CS>,
+    format: BeaconFormat,
+    beacon_config: RfConfig,
+    ping_config: RfConfig,
+    slots: PingSlots,
+    clock: BeaconClock,
+}
+
+impl<'a, RK, DLY, CS> ClassB<'a, RK, DLY, CS>
+where
+    RK: RadioKind,
+    DLY: DelayUs,
+    CS: CarrierSense,
+{
+    /// Operate `radio` in Class B, receiving beacons of `format` with `beacon_config` and listening in `slots`
+    /// with `ping_config`.
+    ///
+    /// In EU868, beacons and ping slots both default to 869.525 MHz at SF9, 125 kHz. With a hopping format, the
+    /// frequencies of the configurations are replaced by the channel of each beacon period once aligned, and the
+    /// beacon search listens on the frequency of `beacon_config`.
+    pub fn new(
+        radio: &'a mut LorawanRadio<RK, DLY, CS>,
+        format: BeaconFormat,
+        beacon_config: RfConfig,
+        ping_config: RfConfig,
+        slots: PingSlots,
+    ) -> Self {
+        Self {
+            radio,
+            format,
+            beacon_config,
+            ping_config,
+            slots,
+            clock: BeaconClock::new(),
+        }
+    }
+
+    /// Change the ping slots, after the network answered a `PingSlotInfoReq` or changed the ping slot channel.
+    pub fn set_ping_slots(&mut self, slots: PingSlots, ping_config: RfConfig) {
+        self.slots = slots;
+        self.ping_config = ping_config;
+    }
+
+    /// Align to the GPS time `gps_time_ms`, in milliseconds, observed at `at`, which skips the beacon search.
+    pub fn align(&mut self, gps_time_ms: u64, at: Instant) {
+        self.clock.align(gps_time_ms, at);
+    }
+
+    /// Align to an application clock synchronized with `AppTimeReq`, if it is.
+    pub fn align_to(&mut self, clock: &AppClock) {
+        if clock.is_synced() {
+            let now = Instant::now();
+            self.clock.align(clock.gps_time_ms(now), now);
+        }
+    }
+
+    /// The alignment to the GPS time.
+    pub fn clock(&self) -> &BeaconClock {
+        &self.clock
+    }
+
+    /// Wait for the next beacon or ping slot and listen for it.
+    ///
+    /// Without a time reference, this listens until a beacon is received. Call this in a loop, and race it
+    /// against uplinks: dropping it is safe, the radio is stopped before the next uplink.
+    pub async fn next_event(&mut self, buf: &mut [u8]) -> Result<Event, Error> {
+        let Some((beacon_time, beacon_at)) = self.clock.next_beacon(Instant::now()) else {
+            return self.search_beacon().await;
+        };
+
+        let period_time = beacon_time.wrapping_sub(BEACON_PERIOD_SECS);
+        let period_start = beacon_at - Duration::from_secs(BEACON_PERIOD_SECS as u64);
+        let first_slot = period_start + self.slots.slot(period_time, 0);
+        let ping_period = PING_SLOT_LENGTH * self.slots.ping_period() as u32;
+        let now = Instant::now();
+        let slot = (0..self.slots.ping_nb() as u32)
+            .map(|n| first_slot + ping_period * n)
+            .find(|slot| *slot >= now);
+
+        match slot {
+            Some(slot) => {
+                let mut ping_config = self.ping_config;
+                if let Some(frequency) = self.format.ping_frequency(period_time, self.slots.dev_addr) {
+                    ping_config.frequency = frequency;
+                }
+                Timer::at(slot).await;
+                match self.radio.receive_ping_slot(ping_config, buf).await? {
+                    Some((len, quality)) => Ok(Event::Downlink {
+                        len,
+                        rssi: quality.rssi(),
+                        snr: quality.snr(),
+                    }),
+                    None => Ok(Event::Idle),
+                }
+            }
+            None => self.track_beacon(beacon_time, beacon_at).await,
+        }
+    }
+
+    /// Listen until a beacon is received.
+    async fn search_beacon(&mut self) -> Result<Event, Error> {
+        let mut frame = [0; MAX_BEACON_LENGTH];
+        let frame = &mut frame[..self.format.size()];
+        loop {
+            self.radio.receive_beacon(self.beacon_config, None, frame).await?;
+            let received_at = Instant::now();
+            match Beacon::parse(self.format, frame) {
+                Ok(beacon) => {
+                    debug!("beacon acquired at GPS time {}", beacon.time);
+                    self.clock.on_beacon(&beacon, received_at - self.beacon_time_on_air());
+                    return Ok(Event::Beacon(beacon));
+                }
+                Err(_) => trace!("invalid beacon"),
+            }
+        }
+    }
+
+    /// Listen for the beacon of `beacon_time` expected at `beacon_at`, in a window widening as beacons are missed.
+    async fn track_beacon(&mut self, beacon_time: u32, beacon_at: Instant) -> Result<Event, Error> {
+        let margin = Duration::from_millis(BEACON_MARGIN_MS * (self.clock.missed() as u64 + 1));
+        let window = margin * 2 + self.beacon_time_on_air();
+        let window_in_secs = (window.as_millis().div_ceil(1000)).clamp(1, u8::MAX as u64) as u8;
+        let mut beacon_config = self.beacon_config;
+        if let Some(frequency) = self.format.beacon_frequency(beacon_time) {
+            beacon_config.frequency = frequency;
+        }
+        Timer::at(beacon_at - margin).await;
+
+        let mut frame = [0; MAX_BEACON_LENGTH];
+        let frame = &mut frame[..self.format.size()];
+        match self
+            .radio
+            .receive_beacon(beacon_config, Some(window_in_secs), frame)
+            .await
+        {
+            Ok(_) => {
+                let received_at = Instant::now();
+                if let Ok(beacon) = Beacon::parse(self.format, frame) {
+                    self.clock.on_beacon(&beacon, received_at - self.beacon_time_on_air());
+                    return Ok(Event::Beacon(beacon));
+                }
+            }
+            Err(lorawan::Error::Radio(RadioError::ReceiveTimeout)) => (),
+            Err(err) => return Err(err.into()),
+        }
+        debug!("beacon missed");
+        self.clock.on_beacon_missed();
+        Ok(Event::BeaconMissed)
+    }
+
+    fn beacon_time_on_air(&self) -> Duration {
+        let bb = &self.beacon_config.bb;
+        Duration::from_micros(airtime::time_on_air_us(
+            lorawan::spreading_factor(bb.sf),
+            lorawan::bandwidth(bb.bw),
+            lorawan::coding_rate(bb.cr),
+            BEACON_PREAMBLE_LENGTH,
+            true,
+            false,
+            self.format.size(),
+        ) as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_beacon() {
+        // Example beacon of the LoRaWAN Class B specification
+        let frame = [
+            0x00, 0x00, 0x00, 0x00, 0x02, 0xcc, 0xa2, 0x7e, 0x00, 0x01, 0x20, 0x00, 0x00, 0x81, 0x03, 0xde, 0x55,
+        ];
+        let beacon = Beacon::parse(BeaconFormat::EU868, &frame).unwrap();
+        assert_eq!(beacon.time, 0xcc02_0000);
+        assert_eq!(beacon.gw_specific, Some([0x00, 0x01, 0x20, 0x00, 0x00, 0x81, 0x03]));
+
+        let mut corrupted = frame;
+        corrupted[16] ^= 1;
+        assert_eq!(
+            Beacon::parse(BeaconFormat::EU868, &corrupted).unwrap().gw_specific,
+            None
+        );
+        corrupted[3] ^= 1;
+        assert_eq!(
+            Beacon::parse(BeaconFormat::EU868, &corrupted),
+            Err(Error::InvalidBeacon)
+        );
+    }
+
+    #[test]
+    fn us915_channels_hop() {
+        let format = BeaconFormat::US915;
+        assert_eq!(format.beacon_frequency(0), Some(923_300_000));
+        assert_eq!(format.beacon_frequency(128 * 3), Some(925_100_000));
+        assert_eq!(format.beacon_frequency(128 * 9), Some(923_900_000));
+        // The address is 7 modulo 8
+        assert_eq!(format.ping_frequency(128 * 3, 0x2601_1bd7), Some(924_500_000));
+        assert_eq!(format.ping_frequency(128, 0x2601_1bd7), Some(923_300_000));
+        assert_eq!(BeaconFormat::EU868.beacon_frequency(128), None);
+        assert_eq!(BeaconFormat::EU868.ping_frequency(128, 0x2601_1bd7), None);
+    }
+
+    #[test]
+    fn ping_slots_fit_the_beacon_period() {
+        for periodicity in 0..8 {
+            let slots = PingSlots::new(0x2601_1bd7, periodicity);
+            assert_eq!(slots.ping_nb() as u32 * slots.ping_period() as u32, PING_SLOTS as u32);
+            for beacon_time in [0, 128, 0xcc02_0000] {
+                assert!(slots.offset(beacon_time) < slots.ping_period());
+                let last = slots.slot(beacon_time, slots.ping_nb() - 1) + PING_SLOT_LENGTH;
+                assert!(last <= Duration::from_secs(BEACON_PERIOD_SECS as u64));
+            }
+        }
+    }
+}
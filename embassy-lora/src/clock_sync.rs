@@ -0,0 +1,238 @@
+//! LoRaWAN application layer clock synchronization (TS003).
+//!
+//! The device sends its GPS time in an `AppTimeReq` on port [`PORT`], and the network answers with the correction
+//! to apply, with a one second resolution. [`AppClock`] keeps the resulting offset between the local
+//! embassy-time clock and the GPS time, exposes the corrected time as GPS or Unix time, and handles the other
+//! package commands: the network can ask for periodic synchronizations or force a few requests in a row.
+//!
+//! A synchronized clock also skips the Class B beacon search, see `ClassB::align_to`.
+
+use embassy_time::{Duration, Instant};
+
+/// Port of the clock synchronization package.
+pub const PORT: u8 = 202;
+
+/// Seconds from the Unix epoch to the GPS epoch, 1980-01-06.
+pub const GPS_EPOCH_UNIX_SECS: u64 = 315_964_800;
+/// Leap seconds between the GPS time and UTC, as of 2017.
+pub const LEAP_SECONDS: u64 = 18;
+
+const PACKAGE_IDENTIFIER: u8 = 1;
+const PACKAGE_VERSION: u8 = 1;
+
+const PACKAGE_VERSION_REQ: u8 = 0x00;
+const APP_TIME_REQ: u8 = 0x01;
+const DEVICE_APP_TIME_PERIODICITY_REQ: u8 = 0x02;
+const FORCE_DEVICE_RESYNC_REQ: u8 = 0x03;
+
+/// Errors reported by the clock synchronization package.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// A command is truncated or unknown.
+    Malformed,
+    /// The buffer is too small to hold the request or the answers.
+    BufferTooSmall,
+}
+
+/// Outcome of [`AppClock::handle`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Response {
+    /// Length of the answers written to the buffer, to send uplink on [`PORT`] if not zero.
+    pub len: usize,
+    /// Correction applied to the clock in seconds, if the downlink held an `AppTimeAns`.
+    pub correction: Option<i32>,
+}
+
+/// The local clock and its offset to the GPS time.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AppClock {
+    /// GPS time in milliseconds minus the local time in milliseconds.
+    offset_ms: i64,
+    synced: bool,
+    token: u8,
+    period: Option<Duration>,
+    last_request: Option<Instant>,
+    forced: u8,
+}
+
+impl AppClock {
+    /// Create a clock that is not synchronized, reading the local time as GPS time.
+    pub const fn new() -> Self {
+        Self {
+            offset_ms: 0,
+            synced: false,
+            token: 0,
+            period: None,
+            last_request: None,
+            forced: 0,
+        }
+    }
+
+    /// Whether an `AppTimeAns` was received.
+    pub fn is_synced(&self) -> bool {
+        self.synced
+    }
+
+    /// GPS time in milliseconds at local time `at`.
+    pub fn gps_time_ms(&self, at: Instant) -> u64 {
+        (at.as_millis() as i64 + self.offset_ms).max(0) as u64
+    }
+
+    /// Unix time in milliseconds at local time `at`.
+    pub fn unix_time_ms(&self, at: Instant) -> u64 {
+        (self.gps_time_ms(at) + GPS_EPOCH_UNIX_SECS * 1000).saturating_sub(LEAP_SECONDS * 1000)
+    }
+
+    /// Local time at which the clock reaches the GPS time `gps_time_ms`, e.g. to schedule a timer.
+    pub fn local_time(&self, gps_time_ms: u64) -> Instant {
+        Instant::from_millis((gps_time_ms as i64 - self.offset_ms).max(0) as u64)
+    }
+
+    /// Period of the synchronizations requested by the network, if any.
+    pub fn period(&self) -> Option<Duration> {
+        self.period
+    }
+
+    /// Whether an `AppTimeReq` should be sent at `now`, because the network forced a resynchronization or the
+    /// requested period elapsed.
+    pub fn is_request_due(&self, now: Instant) -> bool {
+        if self.forced > 0 {
+            return true;
+        }
+        match (self.period, self.last_request) {
+            (Some(period), Some(last)) => now >= last + period,
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+
+    /// Write an `AppTimeReq` sent at `now` to `buf`, returning its length.
+    ///
+    /// With `ans_required`, the network answers even if the clock is already correct.
+    pub fn app_time_req(&mut self, now: Instant, ans_required: bool, buf: &mut [u8]) -> Result<usize, Error> {
+        let buf = buf.get_mut(..6).ok_or(Error::BufferTooSmall)?;
+        let device_time = (self.gps_time_ms(now) / 1000) as u32;
+        buf[0] = APP_TIME_REQ;
+        buf[1..5].copy_from_slice(&device_time.to_le_bytes());
+        buf[5] = (ans_required as u8) << 4 | self.token;
+        self.last_request = Some(now);
+        self.forced = self.forced.saturating_sub(1);
+        Ok(6)
+    }
+
+    /// Handle a downlink received on [`PORT`] at `now`, writing the answers to `answer`.
+    pub fn handle(&mut self, payload: &[u8], now: Instant, answer: &mut [u8]) -> Result<Response, Error> {
+        let mut response = Response {
+            len: 0,
+            correction: None,
+        };
+        let mut rest = payload;
+        while let [cid, params @ ..] = rest {
+            let mut out = [0; 6];
+            let (out_len, consumed) = match *cid {
+                PACKAGE_VERSION_REQ => {
+                    out[..3].copy_from_slice(&[PACKAGE_VERSION_REQ, PACKAGE_IDENTIFIER, PACKAGE_VERSION]);
+                    (3, 0)
+                }
+                APP_TIME_REQ => {
+                    let [c0, c1, c2, c3, param, ..] = *params else {
+                        return Err(Error::Malformed);
+                    };
+                    // Answers to an older request are stale
+                    if param & 0x0f == self.token {
+                        let correction = i32::from_le_bytes([c0, c1, c2, c3]);
+                        self.offset_ms += correction as i64 * 1000;
+                        self.synced = true;
+                        self.token = (self.token + 1) & 0x0f;
+                        response.correction = Some(correction);
+                        debug!("clock corrected by {} s", correction);
+                    }
+                    (0, 5)
+                }
+                DEVICE_APP_TIME_PERIODICITY_REQ => {
+                    let param = *params.first().ok_or(Error::Malformed)?;
+                    self.period = Some(Duration::from_secs(128 << (param & 0x0f)));
+                    out[0] = DEVICE_APP_TIME_PERIODICITY_REQ;
+                    out[1] = 0;
+                    out[2..6].copy_from_slice(&((self.gps_time_ms(now) / 1000) as u32).to_le_bytes());
+                    (6, 1)
+                }
+                FORCE_DEVICE_RESYNC_REQ => {
+                    self.forced = params.first().ok_or(Error::Malformed)? & 0x07;
+                    (0, 1)
+                }
+                _ => return Err(Error::Malformed),
+            };
+            answer
+                .get_mut(response.len..response.len + out_len)
+                .ok_or(Error::BufferTooSmall)?
+                .copy_from_slice(&out[..out_len]);
+            response.len += out_len;
+            rest = &params[consumed..];
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn app_time_correction() {
+        let mut clock = AppClock::new();
+        let mut buf = [0; 8];
+        let sent_at = Instant::from_secs(100);
+        assert_eq!(clock.app_time_req(sent_at, true, &mut buf), Ok(6));
+        assert_eq!(buf[..6], [0x01, 100, 0, 0, 0, 0x10]);
+
+        // Stale token, then the answer to the request
+        let now = Instant::from_secs(102);
+        let answer = [0x01, 0x00, 0x00, 0x00, 0x50, 0x0f];
+        assert_eq!(clock.handle(&answer, now, &mut buf).unwrap().correction, None);
+        let answer = [0x01, 0x00, 0x00, 0x00, 0x50, 0x00];
+        assert_eq!(
+            clock.handle(&answer, now, &mut buf).unwrap().correction,
+            Some(0x5000_0000)
+        );
+        assert!(clock.is_synced());
+        assert_eq!(clock.gps_time_ms(now), (0x5000_0000 + 102) * 1000);
+        assert_eq!(clock.local_time(clock.gps_time_ms(now)), now);
+        assert_eq!(
+            clock.unix_time_ms(now),
+            (0x5000_0000 + 102 + GPS_EPOCH_UNIX_SECS - LEAP_SECONDS) * 1000
+        );
+
+        // The next request carries the next token
+        clock.app_time_req(now, false, &mut buf).unwrap();
+        assert_eq!(buf[5], 0x01);
+    }
+
+    #[test]
+    fn periodicity_and_resync() {
+        let mut clock = AppClock::new();
+        let mut buf = [0; 16];
+        let now = Instant::from_secs(10);
+        assert!(!clock.is_request_due(now));
+
+        // Package version and a period of 128 * 2^2 s
+        let response = clock.handle(&[0x00, 0x02, 0x02], now, &mut buf).unwrap();
+        assert_eq!(buf[..response.len], [0x00, 1, 1, 0x02, 0x00, 10, 0, 0, 0]);
+        assert_eq!(clock.period(), Some(Duration::from_secs(512)));
+        assert!(clock.is_request_due(now));
+        clock.app_time_req(now, false, &mut buf).unwrap();
+        assert!(!clock.is_request_due(now + Duration::from_secs(511)));
+        assert!(clock.is_request_due(now + Duration::from_secs(512)));
+
+        // Forced resynchronization with two requests
+        assert_eq!(clock.handle(&[0x03, 0x02], now, &mut buf).unwrap().len, 0);
+        for _ in 0..2 {
+            assert!(clock.is_request_due(now));
+            clock.app_time_req(now, false, &mut buf).unwrap();
+        }
+        assert!(!clock.is_request_due(now));
+        assert_eq!(clock.handle(&[0x03], now, &mut buf), Err(Error::Malformed));
+    }
+}
@@ -0,0 +1,399 @@
+//! Framing of datagrams sent over point-to-point links.
+//!
+//! A datagram starts with a [`Header`] identifying its source, destination and sequence number. The header is
+//! versioned and encoded field by field in little-endian order, so that nodes running different firmware
+//! versions in the same network keep understanding each other.
+//!
+//! The radio CRC is only 16 bits wide, and at very low SNR a corrupted packet occasionally passes it. The
+//! helpers in this module can add an application-level CRC-32 to a frame and verify it on reception.
+//!
+//! Payloads can also be compressed with a [`Codec`], such as the built-in [`Lzss`], so that verbose telemetry
+//! takes less airtime. A compressed frame is marked with the [`Header::COMPRESSED`] flag, and a payload that does
+//! not shrink is sent as is.
+
+/// Number of bytes added to a payload by [`append_crc`].
+pub const CRC_LENGTH: usize = 4;
+
+/// Errors reported when framing or unframing a datagram.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The buffer is too small to hold the framed datagram.
+    BufferTooSmall,
+    /// The frame is shorter than its fixed fields.
+    Truncated,
+    /// The frame does not match its CRC.
+    Crc,
+    /// The frame was encoded with an incompatible version of the header.
+    UnsupportedVersion(u8),
+    /// The compressed payload is malformed.
+    Decompression,
+}
+
+/// Version of the header written by [`Header::encode`].
+///
+/// The high nibble is the major version and the low nibble the minor version. Minor versions only ever append
+/// fields to the header, which older decoders skip thanks to the header length byte. Frames with a different
+/// major version are rejected.
+pub const HEADER_VERSION: u8 = 0x10;
+
+/// Length of the header written by [`Header::encode`].
+pub const HEADER_LENGTH: usize = 9;
+
+/// Header of a datagram.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Header {
+    /// Flags, application-defined except for [`Header::COMPRESSED`].
+    pub flags: u8,
+    /// Address of the sender.
+    pub source: u16,
+    /// Address of the recipient.
+    pub destination: u16,
+    /// Sequence number of the datagram, incremented by the sender for every new datagram.
+    pub sequence: u16,
+}
+
+impl Header {
+    /// Address used as the destination of datagrams meant for every node.
+    pub const BROADCAST: u16 = 0xffff;
+
+    /// Flag marking a compressed payload, set by [`encode_with`](Self::encode_with).
+    pub const COMPRESSED: u8 = 0x80;
+
+    /// Write the header followed by `payload` to `buf`, and return the length of the frame.
+    ///
+    /// The payload is sent as is, so [`Header::COMPRESSED`] is cleared from the flags.
+    pub fn encode(&self, payload: &[u8], buf: &mut [u8]) -> Result<usize, Error> {
+        self.encode_flags(self.flags & !Header::COMPRESSED, payload, buf)
+    }
+
+    fn encode_flags(&self, flags: u8, payload: &[u8], buf: &mut [u8]) -> Result<usize, Error> {
+        let len = HEADER_LENGTH + payload.len();
+        if buf.len() < len {
+            return Err(Error::BufferTooSmall);
+        }
+        buf[0] = HEADER_VERSION;
+        buf[1] = HEADER_LENGTH as u8;
+        buf[2] = flags;
+        buf[3..5].copy_from_slice(&self.source.to_le_bytes());
+        buf[5..7].copy_from_slice(&self.destination.to_le_bytes());
+        buf[7..9].copy_from_slice(&self.sequence.to_le_bytes());
+        buf[HEADER_LENGTH..len].copy_from_slice(payload);
+        Ok(len)
+    }
+
+    /// Parse the header of `frame`, and return it along with the payload that follows.
+    pub fn decode(frame: &[u8]) -> Result<(Header, &[u8]), Error> {
+        if frame.len() < 2 {
+            return Err(Error::Truncated);
+        }
+        let (version, header_len) = (frame[0], frame[1] as usize);
+        if version >> 4 != HEADER_VERSION >> 4 {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        if header_len < HEADER_LENGTH || frame.len() < header_len {
+            return Err(Error::Truncated);
+        }
+        let field = |i: usize| u16::from_le_bytes([frame[i], frame[i + 1]]);
+        let header = Header {
+            flags: frame[2],
+            source: field(3),
+            destination: field(5),
+            sequence: field(7),
+        };
+        Ok((header, &frame[header_len..]))
+    }
+
+    /// Write the header followed by `payload`, compressed with `codec` if that makes it shorter, to `buf`, and
+    /// return the length of the frame.
+    pub fn encode_with(&self, codec: &impl Codec, payload: &[u8], buf: &mut [u8]) -> Result<usize, Error> {
+        if buf.len() > HEADER_LENGTH {
+            if let Some(len) = codec.compress(payload, &mut buf[HEADER_LENGTH..]) {
+                self.encode_flags(self.flags | Header::COMPRESSED, &[], buf)?;
+                return Ok(HEADER_LENGTH + len);
+            }
+        }
+        self.encode(payload, buf)
+    }
+
+    /// Parse the header of `frame`, and return it along with the payload that follows, decompressed with `codec`
+    /// into `buf` if needed.
+    pub fn decode_with<'a>(
+        codec: &impl Codec,
+        frame: &'a [u8],
+        buf: &'a mut [u8],
+    ) -> Result<(Header, &'a [u8]), Error> {
+        let (header, payload) = Header::decode(frame)?;
+        if header.flags & Header::COMPRESSED == 0 {
+            return Ok((header, payload));
+        }
+        let len = codec.decompress(payload, buf)?;
+        Ok((header, &buf[..len]))
+    }
+}
+
+/// Payload compression algorithm.
+pub trait Codec {
+    /// Compress `input` into `out`, and return the compressed length, or `None` if the result would not be
+    /// shorter than `input` or does not fit in `out`.
+    fn compress(&self, input: &[u8], out: &mut [u8]) -> Option<usize>;
+
+    /// Decompress `input` into `out`, and return the decompressed length.
+    fn decompress(&self, input: &[u8], out: &mut [u8]) -> Result<usize, Error>;
+}
+
+/// LZSS compression, suited to small, repetitive payloads such as text or JSON telemetry.
+///
+/// Every group of up to 8 items is preceded by a byte whose bits tell, from the least significant one, whether
+/// the item is a literal byte (1) or a 2-byte back-reference (0). A back-reference holds a 12-bit distance and a
+/// 4-bit length, repeating 3 to 18 bytes found up to 4095 bytes back. Compression needs no memory besides its
+/// input and output.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Lzss;
+
+impl Lzss {
+    const MIN_MATCH: usize = 3;
+    const MAX_MATCH: usize = Self::MIN_MATCH + 0x0f;
+    const MAX_DISTANCE: usize = 0x0fff;
+
+    /// Longest earlier occurrence of the bytes at `pos`, as a distance and length.
+    fn longest_match(input: &[u8], pos: usize) -> (usize, usize) {
+        let max_len = Self::MAX_MATCH.min(input.len() - pos);
+        let mut best = (0, 0);
+        for distance in 1..=pos.min(Self::MAX_DISTANCE) {
+            let len = (0..max_len)
+                .take_while(|&i| input[pos - distance + i] == input[pos + i])
+                .count();
+            if len > best.1 {
+                best = (distance, len);
+                if len == max_len {
+                    break;
+                }
+            }
+        }
+        best
+    }
+}
+
+impl Codec for Lzss {
+    fn compress(&self, input: &[u8], out: &mut [u8]) -> Option<usize> {
+        let limit = out.len().min(input.len().checked_sub(1)?);
+        let (mut pos, mut len) = (0, 0);
+        while pos < input.len() {
+            let flags = len;
+            if len >= limit {
+                return None;
+            }
+            out[flags] = 0;
+            len += 1;
+            for bit in 0..8 {
+                if pos >= input.len() {
+                    break;
+                }
+                let (distance, match_len) = Self::longest_match(input, pos);
+                if match_len >= Self::MIN_MATCH {
+                    if len + 2 > limit {
+                        return None;
+                    }
+                    out[len] = (distance >> 4) as u8;
+                    out[len + 1] = ((distance & 0x0f) << 4) as u8 | (match_len - Self::MIN_MATCH) as u8;
+                    len += 2;
+                    pos += match_len;
+                } else {
+                    if len + 1 > limit {
+                        return None;
+                    }
+                    out[flags] |= 1 << bit;
+                    out[len] = input[pos];
+                    len += 1;
+                    pos += 1;
+                }
+            }
+        }
+        Some(len)
+    }
+
+    fn decompress(&self, input: &[u8], out: &mut [u8]) -> Result<usize, Error> {
+        let (mut pos, mut len) = (0, 0);
+        while pos < input.len() {
+            let flags = input[pos];
+            pos += 1;
+            for bit in 0..8 {
+                if pos >= input.len() {
+                    break;
+                }
+                if flags & (1 << bit) != 0 {
+                    *out.get_mut(len).ok_or(Error::BufferTooSmall)? = input[pos];
+                    len += 1;
+                    pos += 1;
+                } else {
+                    let reference = input.get(pos..pos + 2).ok_or(Error::Decompression)?;
+                    let distance = (reference[0] as usize) << 4 | (reference[1] >> 4) as usize;
+                    let match_len = (reference[1] & 0x0f) as usize + Self::MIN_MATCH;
+                    if distance == 0 || distance > len {
+                        return Err(Error::Decompression);
+                    }
+                    if len + match_len > out.len() {
+                        return Err(Error::BufferTooSmall);
+                    }
+                    // Byte by byte, as the reference may overlap the bytes it produces
+                    for i in len..len + match_len {
+                        out[i] = out[i - distance];
+                    }
+                    len += match_len;
+                    pos += 2;
+                }
+            }
+        }
+        Ok(len)
+    }
+}
+
+const CRC_TABLE: [u32; 16] = [
+    0x00000000, 0x1db71064, 0x3b6e20c8, 0x26d930ac, 0x76dc4190, 0x6b6b51f4, 0x4db26158, 0x5005713c, 0xedb88320,
+    0xf00f9344, 0xd6d6a3e8, 0xcb61b38c, 0x9b64c2b0, 0x86d3d2d4, 0xa00ae278, 0xbdbdf21c,
+];
+
+/// CRC-32 (IEEE 802.3) of `data`.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc = CRC_TABLE[((crc ^ byte as u32) & 0x0f) as usize] ^ (crc >> 4);
+        crc = CRC_TABLE[((crc ^ (byte as u32 >> 4)) & 0x0f) as usize] ^ (crc >> 4);
+    }
+    !crc
+}
+
+/// Append the CRC of the first `len` bytes of `buf` after them, and return the length of the frame.
+pub fn append_crc(buf: &mut [u8], len: usize) -> Result<usize, Error> {
+    if buf.len() < len + CRC_LENGTH {
+        return Err(Error::BufferTooSmall);
+    }
+    let crc = crc32(&buf[..len]);
+    buf[len..len + CRC_LENGTH].copy_from_slice(&crc.to_le_bytes());
+    Ok(len + CRC_LENGTH)
+}
+
+/// Verify the CRC at the end of `frame`, and return the payload it protects.
+pub fn check_crc(frame: &[u8]) -> Result<&[u8], Error> {
+    if frame.len() < CRC_LENGTH {
+        return Err(Error::Truncated);
+    }
+    let (payload, crc) = frame.split_at(frame.len() - CRC_LENGTH);
+    if crc32(payload).to_le_bytes() != crc {
+        return Err(Error::Crc);
+    }
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_round_trip() {
+        let header = Header {
+            flags: 0x01,
+            source: 0x1234,
+            destination: Header::BROADCAST,
+            sequence: 7,
+        };
+        let mut buf = [0; 16];
+        let len = header.encode(b"abc", &mut buf).unwrap();
+        assert_eq!(
+            &buf[..len],
+            &[0x10, 9, 0x01, 0x34, 0x12, 0xff, 0xff, 7, 0, b'a', b'b', b'c']
+        );
+        assert_eq!(Header::decode(&buf[..len]), Ok((header, &b"abc"[..])));
+        assert_eq!(Header::decode(&buf[..5]), Err(Error::Truncated));
+        assert_eq!(header.encode(&[0; 8], &mut buf), Err(Error::BufferTooSmall));
+
+        // An uncompressed payload is never marked as compressed
+        let header = Header { flags: 0x81, ..header };
+        let len = header.encode(b"abc", &mut buf).unwrap();
+        assert_eq!(Header::decode(&buf[..len]).unwrap().0.flags, 0x01);
+    }
+
+    #[test]
+    fn header_versions() {
+        // A newer minor version with an extra field
+        let frame = [0x11, 10, 0, 1, 0, 2, 0, 3, 0, 0xaa, b'x'];
+        let (header, payload) = Header::decode(&frame).unwrap();
+        assert_eq!(header.sequence, 3);
+        assert_eq!(payload, b"x");
+
+        let frame = [0x20, 9, 0, 1, 0, 2, 0, 3, 0];
+        assert_eq!(Header::decode(&frame), Err(Error::UnsupportedVersion(0x20)));
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+        assert_eq!(crc32(&[]), 0);
+    }
+
+    #[test]
+    fn crc_round_trip() {
+        let mut buf = [0; 9];
+        buf[..5].copy_from_slice(b"hello");
+        let len = append_crc(&mut buf, 5).unwrap();
+        assert_eq!(check_crc(&buf[..len]), Ok(&b"hello"[..]));
+
+        buf[1] ^= 0x04;
+        assert_eq!(check_crc(&buf[..len]), Err(Error::Crc));
+        assert_eq!(check_crc(&buf[..3]), Err(Error::Truncated));
+        assert_eq!(append_crc(&mut buf, 6), Err(Error::BufferTooSmall));
+    }
+
+    #[test]
+    fn lzss_round_trip() {
+        let telemetry = br#"{"temp":21.5,"hum":40.1},{"temp":21.6,"hum":40.0},{"temp":21.6,"hum":39.9}"#;
+        let mut compressed = [0; 128];
+        let len = Lzss.compress(telemetry, &mut compressed).unwrap();
+        assert!(len < telemetry.len() * 2 / 3);
+        let mut out = [0; 128];
+        let out_len = Lzss.decompress(&compressed[..len], &mut out).unwrap();
+        assert_eq!(&out[..out_len], &telemetry[..]);
+
+        // Runs are encoded with overlapping references
+        let len = Lzss.compress(&[0xaa; 64], &mut compressed).unwrap();
+        assert_eq!(Lzss.decompress(&compressed[..len], &mut out), Ok(64));
+        assert_eq!(
+            Lzss.decompress(&compressed[..len], &mut out[..10]),
+            Err(Error::BufferTooSmall)
+        );
+
+        assert_eq!(Lzss.compress(b"abcdefgh", &mut compressed), None);
+        assert_eq!(
+            Lzss.decompress(&[0x00, 0x00, 0x10], &mut out),
+            Err(Error::Decompression)
+        );
+    }
+
+    #[test]
+    fn compressed_frames() {
+        let header = Header {
+            flags: 0x01,
+            source: 1,
+            destination: 2,
+            sequence: 3,
+        };
+        let mut frame = [0; 64];
+        let mut buf = [0; 64];
+
+        let payload = [b'x'; 40];
+        let len = header.encode_with(&Lzss, &payload, &mut frame).unwrap();
+        assert!(len < HEADER_LENGTH + payload.len());
+        let (decoded, decompressed) = Header::decode_with(&Lzss, &frame[..len], &mut buf).unwrap();
+        assert_eq!(decoded.flags, 0x01 | Header::COMPRESSED);
+        assert_eq!(decompressed, &payload[..]);
+
+        let len = header.encode_with(&Lzss, b"abc", &mut frame).unwrap();
+        assert_eq!(len, HEADER_LENGTH + 3);
+        assert_eq!(
+            Header::decode_with(&Lzss, &frame[..len], &mut buf),
+            Ok((header, &b"abc"[..]))
+        );
+    }
+}
@@ -0,0 +1,92 @@
+//! Deduplication of repeated downlinks.
+//!
+//! A device in Class C listens continuously, so when the network server schedules a downlink through several
+//! gateways, the device may receive it more than once. [`DownlinkFilter`] remembers the address, frame counter and
+//! frequency of the last downlinks received and flags the repetitions, so the application only processes a
+//! command once. The address keeps apart the unicast and multicast downlinks, whose frame counters are separate.
+
+/// A downlink as identified by the filter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Seen {
+    dev_addr: u32,
+    fcnt: u32,
+    frequency_in_hz: u32,
+}
+
+/// Filter remembering the last `N` downlinks received.
+pub struct DownlinkFilter<const N: usize> {
+    seen: [Option<Seen>; N],
+    next: usize,
+}
+
+impl<const N: usize> DownlinkFilter<N> {
+    /// Create an empty filter.
+    pub const fn new() -> Self {
+        Self {
+            seen: [None; N],
+            next: 0,
+        }
+    }
+
+    /// Record a downlink to `dev_addr`, the device or a multicast group, returning true if the same downlink was
+    /// already received recently.
+    pub fn is_duplicate(&mut self, dev_addr: u32, fcnt: u32, frequency_in_hz: u32) -> bool {
+        let downlink = Seen {
+            dev_addr,
+            fcnt,
+            frequency_in_hz,
+        };
+        if self.seen.contains(&Some(downlink)) {
+            trace!("dropping duplicate downlink to {:x}, fcnt {}", dev_addr, fcnt);
+            return true;
+        }
+        if N > 0 {
+            self.seen[self.next] = Some(downlink);
+            self.next = (self.next + 1) % N;
+        }
+        false
+    }
+
+    /// Forget all downlinks, e.g. after joining a network again since frame counters restart from zero.
+    pub fn clear(&mut self) {
+        self.seen = [None; N];
+        self.next = 0;
+    }
+}
+
+impl<const N: usize> Default for DownlinkFilter<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEV_ADDR: u32 = 0x2601_1bd7;
+    const MC_ADDR: u32 = 0x0123_4567;
+
+    #[test]
+    fn filters_recent_duplicates() {
+        let mut filter = DownlinkFilter::<2>::new();
+        assert!(!filter.is_duplicate(DEV_ADDR, 1, 869_525_000));
+        assert!(filter.is_duplicate(DEV_ADDR, 1, 869_525_000));
+        assert!(!filter.is_duplicate(DEV_ADDR, 1, 868_100_000));
+        // The first downlink has been evicted
+        assert!(!filter.is_duplicate(DEV_ADDR, 2, 869_525_000));
+        assert!(!filter.is_duplicate(DEV_ADDR, 1, 869_525_000));
+
+        filter.clear();
+        assert!(!filter.is_duplicate(DEV_ADDR, 2, 869_525_000));
+    }
+
+    #[test]
+    fn unicast_and_multicast_counters_are_separate() {
+        let mut filter = DownlinkFilter::<4>::new();
+        assert!(!filter.is_duplicate(DEV_ADDR, 7, 869_525_000));
+        assert!(!filter.is_duplicate(MC_ADDR, 7, 869_525_000));
+        assert!(filter.is_duplicate(MC_ADDR, 7, 869_525_000));
+        assert!(filter.is_duplicate(DEV_ADDR, 7, 869_525_000));
+    }
+}
@@ -0,0 +1,250 @@
+//! Duty cycle enforcement.
+//!
+//! In Europe, ETSI EN 300 220 limits the fraction of time a device may transmit in each sub-band, to 0.1%, 1%
+//! or 10% depending on the band. [`DutyCycle`] accounts the time on air of each transmission to the sub-band of
+//! the [`Region`] it falls in, and tells when the next transmission in that band is allowed. It follows the
+//! LoRaWAN approach: after transmitting for `T` in a band limited to a duty cycle `d`, the band stays closed for
+//! `T / d - T`. This is stricter than the hourly average of the regulations, but guarantees the limit is met
+//! over any observation window.
+//!
+//! Bands without duty cycle limit, such as the whole of US915, are never closed.
+//!
+//! A share of the duty cycle of a band can be kept for [`Priority::High`] transmissions: routine transmissions
+//! are then held to the rest of the duty cycle, so that an alarm can go out while routine traffic has to wait.
+
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::queue::Priority;
+use crate::regulatory::Region;
+
+/// Maximum number of sub-bands accounted.
+const MAX_BANDS: usize = 8;
+
+/// What to do with a transmission while its sub-band is closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DutyCyclePolicy {
+    /// Wait until the sub-band opens again.
+    Delay,
+    /// Reject the transmission.
+    Reject,
+}
+
+/// Airtime accounting of the sub-bands of a region.
+#[derive(Debug, Clone, Copy)]
+pub struct DutyCycle {
+    region: Region,
+    available_at: [Instant; MAX_BANDS],
+    routine_available_at: [Instant; MAX_BANDS],
+    reserve_permille: [u16; MAX_BANDS],
+}
+
+impl DutyCycle {
+    /// Start accounting with every sub-band of `region` open.
+    pub fn new(region: Region) -> Self {
+        Self {
+            region,
+            available_at: [Instant::from_ticks(0); MAX_BANDS],
+            routine_available_at: [Instant::from_ticks(0); MAX_BANDS],
+            reserve_permille: [0; MAX_BANDS],
+        }
+    }
+
+    /// Keep `reserve_permille` of the duty cycle of a sub-band for high priority transmissions, up to 999.
+    ///
+    /// `band` is the index of the sub-band in [`Region::bands`]. Routine transmissions close the band for routine
+    /// traffic as if its duty cycle was reduced by the reserve, while the band reopens for high priority
+    /// transmissions at the regulatory limit. Every transmission draws from the reserve, whatever its priority.
+    pub fn set_high_priority_reserve(&mut self, band: usize, reserve_permille: u16) {
+        if let Some(reserve) = self.reserve_permille.get_mut(band) {
+            *reserve = reserve_permille.min(999);
+        }
+    }
+
+    /// The region accounted.
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    fn band_index(&self, frequency_in_hz: u32, bandwidth_in_hz: u32) -> Option<usize> {
+        self.region
+            .bands()
+            .iter()
+            .take(MAX_BANDS)
+            .position(|band| band.contains(frequency_in_hz, bandwidth_in_hz))
+    }
+
+    /// Time from which a transmission on the channel is allowed, or `None` if the channel lies outside every
+    /// band of the region and is not accounted.
+    ///
+    /// This is the regulatory limit, which applies to high priority transmissions.
+    pub fn next_allowed(&self, frequency_in_hz: u32, bandwidth_in_hz: u32) -> Option<Instant> {
+        self.next_allowed_with_priority(Priority::High, frequency_in_hz, bandwidth_in_hz)
+    }
+
+    /// Time from which a transmission of `priority` on the channel is allowed, see [`next_allowed`].
+    ///
+    /// [`next_allowed`]: Self::next_allowed
+    pub fn next_allowed_with_priority(
+        &self,
+        priority: Priority,
+        frequency_in_hz: u32,
+        bandwidth_in_hz: u32,
+    ) -> Option<Instant> {
+        self.band_index(frequency_in_hz, bandwidth_in_hz)
+            .map(|index| match priority {
+                Priority::High => self.available_at[index],
+                Priority::Routine => self.available_at[index].max(self.routine_available_at[index]),
+            })
+    }
+
+    /// Whether a transmission on the channel is allowed at `now`.
+    pub fn is_allowed(&self, frequency_in_hz: u32, bandwidth_in_hz: u32, now: Instant) -> bool {
+        self.is_allowed_with_priority(Priority::High, frequency_in_hz, bandwidth_in_hz, now)
+    }
+
+    /// Whether a transmission of `priority` on the channel is allowed at `now`.
+    pub fn is_allowed_with_priority(
+        &self,
+        priority: Priority,
+        frequency_in_hz: u32,
+        bandwidth_in_hz: u32,
+        now: Instant,
+    ) -> bool {
+        self.next_allowed_with_priority(priority, frequency_in_hz, bandwidth_in_hz)
+            .map_or(true, |available_at| available_at <= now)
+    }
+
+    /// Account a transmission of `time_on_air_us` on the channel, which ended at `end`.
+    pub fn record(&mut self, frequency_in_hz: u32, bandwidth_in_hz: u32, time_on_air_us: u32, end: Instant) {
+        let Some(index) = self.band_index(frequency_in_hz, bandwidth_in_hz) else {
+            return;
+        };
+        let permille = self.region.bands()[index].duty_cycle_permille as u64;
+        if permille >= 1000 {
+            return;
+        }
+        let off_time_us = time_on_air_us as u64 * (1000 - permille) / permille.max(1);
+        // A short transmission must not reopen a band closed by an earlier, longer one
+        self.available_at[index] = self.available_at[index].max(end + Duration::from_micros(off_time_us));
+        // Duty cycle left to routine transmissions, in millionths
+        let routine_ppm = permille * (1000 - self.reserve_permille[index] as u64);
+        let routine_off_time_us = time_on_air_us as u64 * (1_000_000 - routine_ppm) / routine_ppm.max(1);
+        self.routine_available_at[index] =
+            self.routine_available_at[index].max(end + Duration::from_micros(routine_off_time_us));
+        trace!(
+            "sub-band {} closed for {} ms",
+            index,
+            Duration::from_micros(off_time_us).as_millis()
+        );
+    }
+
+    /// Wait until a transmission on the channel is allowed.
+    pub async fn wait(&self, frequency_in_hz: u32, bandwidth_in_hz: u32) {
+        self.wait_with_priority(Priority::High, frequency_in_hz, bandwidth_in_hz)
+            .await
+    }
+
+    /// Wait until a transmission of `priority` on the channel is allowed.
+    pub async fn wait_with_priority(&self, priority: Priority, frequency_in_hz: u32, bandwidth_in_hz: u32) {
+        if let Some(available_at) = self.next_allowed_with_priority(priority, frequency_in_hz, bandwidth_in_hz) {
+            Timer::at(available_at).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn eu868_sub_bands() {
+        let mut duty_cycle = DutyCycle::new(Region::Eu868);
+        let end = Instant::from_secs(100);
+        assert!(duty_cycle.is_allowed(868_100_000, 125_000, end));
+
+        // 1% band: closed for 99 times the time on air, other bands stay open
+        duty_cycle.record(868_100_000, 125_000, 100_000, end);
+        assert_eq!(
+            duty_cycle.next_allowed(868_300_000, 125_000),
+            Some(end + Duration::from_millis(9_900))
+        );
+        assert!(!duty_cycle.is_allowed(868_500_000, 125_000, end + Duration::from_secs(9)));
+        assert!(duty_cycle.is_allowed(868_500_000, 125_000, end + Duration::from_secs(10)));
+        assert!(duty_cycle.is_allowed(869_525_000, 125_000, end));
+
+        // 10% band
+        duty_cycle.record(869_525_000, 125_000, 100_000, end);
+        assert_eq!(
+            duty_cycle.next_allowed(869_525_000, 125_000),
+            Some(end + Duration::from_millis(900))
+        );
+        // 0.1% band
+        duty_cycle.record(864_100_000, 125_000, 100_000, end);
+        assert_eq!(
+            duty_cycle.next_allowed(864_100_000, 125_000),
+            Some(end + Duration::from_millis(99_900))
+        );
+    }
+
+    #[test]
+    fn high_priority_reserve() {
+        let mut duty_cycle = DutyCycle::new(Region::Eu868);
+        let band = Region::Eu868
+            .bands()
+            .iter()
+            .position(|band| band.contains(868_100_000, 125_000))
+            .unwrap();
+        duty_cycle.set_high_priority_reserve(band, 500);
+        let end = Instant::from_secs(100);
+        duty_cycle.record(868_100_000, 125_000, 100_000, end);
+
+        // Routine traffic is held to 0.5%, alarms to the regulatory 1%
+        assert_eq!(
+            duty_cycle.next_allowed_with_priority(Priority::Routine, 868_100_000, 125_000),
+            Some(end + Duration::from_millis(19_900))
+        );
+        let at = end + Duration::from_secs(10);
+        assert!(duty_cycle.is_allowed_with_priority(Priority::High, 868_100_000, 125_000, at));
+        assert!(!duty_cycle.is_allowed_with_priority(Priority::Routine, 868_100_000, 125_000, at));
+    }
+
+    #[test]
+    fn high_priority_after_routine() {
+        let mut duty_cycle = DutyCycle::new(Region::Eu868);
+        let band = Region::Eu868
+            .bands()
+            .iter()
+            .position(|band| band.contains(868_100_000, 125_000))
+            .unwrap();
+        duty_cycle.set_high_priority_reserve(band, 500);
+        duty_cycle.record(868_100_000, 125_000, 1_000_000, Instant::from_secs(1));
+        let routine_at = Instant::from_secs(200);
+        assert_eq!(
+            duty_cycle.next_allowed_with_priority(Priority::Routine, 868_100_000, 125_000),
+            Some(routine_at)
+        );
+
+        // An alarm in the reserve leaves routine traffic closed
+        let alarm_end = Instant::from_secs(150);
+        assert!(duty_cycle.is_allowed_with_priority(Priority::High, 868_100_000, 125_000, alarm_end));
+        duty_cycle.record(868_100_000, 125_000, 10_000, alarm_end);
+        assert_eq!(
+            duty_cycle.next_allowed_with_priority(Priority::Routine, 868_100_000, 125_000),
+            Some(routine_at)
+        );
+        assert_eq!(
+            duty_cycle.next_allowed_with_priority(Priority::High, 868_100_000, 125_000),
+            Some(alarm_end + Duration::from_millis(990))
+        );
+    }
+
+    #[test]
+    fn unlimited_bands() {
+        let mut duty_cycle = DutyCycle::new(Region::Us915);
+        let end = Instant::from_secs(100);
+        duty_cycle.record(902_300_000, 125_000, 400_000, end);
+        assert!(duty_cycle.is_allowed(902_300_000, 125_000, end));
+        assert_eq!(DutyCycle::new(Region::Eu868).next_allowed(915_000_000, 125_000), None);
+    }
+}
@@ -0,0 +1,521 @@
+//! Receiver of the LoRaWAN fragmented data block transport (TS004).
+//!
+//! Firmware updates over LoRaWAN (FUOTA) send the image as a series of fragments on port [`PORT`], usually to a
+//! multicast group. The image is split into `M` uncoded fragments, followed by coded fragments each combining
+//! about half of the uncoded ones, so that a device missing some fragments can rebuild them from any set of
+//! coded fragments of about the same size.
+//!
+//! [`FragmentationReceiver`] answers the package commands and rebuilds the image into a [`FragmentStore`],
+//! typically the staging partition of a bootloader. Lost fragments are recovered by incremental Gaussian
+//! elimination over GF(2): coded fragments are reduced as they arrive and stored in place of the fragments they
+//! stand for, so that beyond the store only a `L`×`L` bit matrix is kept in RAM, `L` being the number of lost
+//! fragments that can be recovered, at most 128. A receiver handles one fragmentation session at a time.
+
+/// Port of the fragmentation package.
+pub const PORT: u8 = 201;
+
+const PACKAGE_IDENTIFIER: u8 = 3;
+const PACKAGE_VERSION: u8 = 1;
+
+const PACKAGE_VERSION_REQ: u8 = 0x00;
+const FRAG_SESSION_STATUS_REQ: u8 = 0x01;
+const FRAG_SESSION_SETUP_REQ: u8 = 0x02;
+const FRAG_SESSION_DELETE_REQ: u8 = 0x03;
+const DATA_FRAGMENT: u8 = 0x08;
+
+const MAX_FRAGMENT_SIZE: usize = 255;
+
+/// Storage the image is rebuilt into.
+///
+/// The receiver overwrites fragments while recovering lost ones, so the store has to support rewriting data,
+/// e.g. RAM, or a flash driver erasing and rewriting pages through a RAM buffer.
+pub trait FragmentStore {
+    /// Error returned by the store.
+    type Error;
+
+    /// Size of the store in bytes.
+    fn capacity(&self) -> usize;
+
+    /// Read `buf.len()` bytes at `offset`.
+    async fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), Self::Error>;
+
+    /// Write `data` at `offset`, replacing what was there.
+    async fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), Self::Error>;
+}
+
+/// Errors reported by the fragmentation receiver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error<E> {
+    /// The store reported an error.
+    Store(E),
+    /// A command is truncated or unknown.
+    Malformed,
+    /// The buffer is too small to hold the answers.
+    BufferTooSmall,
+}
+
+/// A fragmentation session set up by the network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FragSession {
+    /// Index of the session, from 0 to 3.
+    pub index: u8,
+    /// Multicast groups the session is sent to, one bit per group.
+    pub mc_group_mask: u8,
+    /// Number of uncoded fragments.
+    pub nb_frag: u16,
+    /// Size of each fragment in bytes.
+    pub frag_size: u8,
+    /// Maximum random delay of the answers to `FragSessionStatusReq`, as the exponent of a number of seconds.
+    pub block_ack_delay: u8,
+    /// Padding bytes at the end of the last uncoded fragment.
+    pub padding: u8,
+    /// Application-defined description of the data, e.g. a firmware version.
+    pub descriptor: [u8; 4],
+}
+
+impl FragSession {
+    /// Size of the data block in bytes.
+    pub fn size(&self) -> usize {
+        (self.nb_frag as usize * self.frag_size as usize).saturating_sub(self.padding as usize)
+    }
+}
+
+/// Outcome of [`FragmentationReceiver::handle`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Response {
+    /// Length of the answers written to the buffer, to send uplink on [`PORT`] if not zero.
+    pub len: usize,
+    /// The session whose data block was completed by this downlink, which is now entirely in the store.
+    pub completed: Option<FragSession>,
+}
+
+/// Receiver rebuilding fragmented data blocks, tracking up to `8 * B` fragments and recovering up to `L` lost
+/// ones.
+pub struct FragmentationReceiver<S: FragmentStore, const B: usize, const L: usize> {
+    store: S,
+    session: Option<FragSession>,
+    received: [u8; B],
+    row: [u8; B],
+    nb_received: u16,
+    coded: bool,
+    lost: [u16; L],
+    nb_lost: usize,
+    matrix: [u128; L],
+    pivots: u128,
+    completed: bool,
+}
+
+impl<S: FragmentStore, const B: usize, const L: usize> FragmentationReceiver<S, B, L> {
+    /// Create a receiver rebuilding data blocks into `store`.
+    pub fn new(store: S) -> Self {
+        assert!(L <= 128);
+        Self {
+            store,
+            session: None,
+            received: [0; B],
+            row: [0; B],
+            nb_received: 0,
+            coded: false,
+            lost: [0; L],
+            nb_lost: 0,
+            matrix: [0; L],
+            pivots: 0,
+            completed: false,
+        }
+    }
+
+    /// The current session, if any.
+    pub fn session(&self) -> Option<&FragSession> {
+        self.session.as_ref()
+    }
+
+    /// Release the store.
+    pub fn release(self) -> S {
+        self.store
+    }
+
+    /// Handle a downlink received on [`PORT`], writing the answers to `answer`.
+    pub async fn handle(&mut self, payload: &[u8], answer: &mut [u8]) -> Result<Response, Error<S::Error>> {
+        let mut response = Response {
+            len: 0,
+            completed: None,
+        };
+        let mut rest = payload;
+        while let [cid, params @ ..] = rest {
+            let mut out = [0; 5];
+            let (out_len, consumed) = match *cid {
+                PACKAGE_VERSION_REQ => {
+                    out[..3].copy_from_slice(&[PACKAGE_VERSION_REQ, PACKAGE_IDENTIFIER, PACKAGE_VERSION]);
+                    (3, 0)
+                }
+                FRAG_SESSION_STATUS_REQ => {
+                    let param = *params.first().ok_or(Error::Malformed)?;
+                    (self.status(param, &mut out), 1)
+                }
+                FRAG_SESSION_SETUP_REQ => {
+                    let params = params.get(..10).ok_or(Error::Malformed)?;
+                    out[..2].copy_from_slice(&[FRAG_SESSION_SETUP_REQ, self.setup(params)]);
+                    (2, 10)
+                }
+                FRAG_SESSION_DELETE_REQ => {
+                    let index = params.first().ok_or(Error::Malformed)? & 0x03;
+                    let status = match self.session {
+                        Some(session) if session.index == index => {
+                            self.session = None;
+                            index
+                        }
+                        _ => index | 0x04,
+                    };
+                    out[..2].copy_from_slice(&[FRAG_SESSION_DELETE_REQ, status]);
+                    (2, 1)
+                }
+                // A data fragment takes the rest of the downlink
+                DATA_FRAGMENT => {
+                    let [lo, hi, data @ ..] = params else {
+                        return Err(Error::Malformed);
+                    };
+                    let index_and_n = u16::from_le_bytes([*lo, *hi]);
+                    if self
+                        .fragment((index_and_n >> 14) as u8, index_and_n & 0x3fff, data)
+                        .await?
+                    {
+                        response.completed = self.session;
+                    }
+                    (0, params.len())
+                }
+                _ => return Err(Error::Malformed),
+            };
+            answer
+                .get_mut(response.len..response.len + out_len)
+                .ok_or(Error::BufferTooSmall)?
+                .copy_from_slice(&out[..out_len]);
+            response.len += out_len;
+            rest = &params[consumed..];
+        }
+        Ok(response)
+    }
+
+    fn setup(&mut self, params: &[u8]) -> u8 {
+        let session = FragSession {
+            index: (params[0] >> 4) & 0x03,
+            mc_group_mask: params[0] & 0x0f,
+            nb_frag: u16::from_le_bytes([params[1], params[2]]),
+            frag_size: params[3],
+            block_ack_delay: (params[4] >> 3) & 0x07,
+            padding: params[5],
+            descriptor: [params[6], params[7], params[8], params[9]],
+        };
+        let mut status = session.index << 6;
+        // Only the LDPC-like algorithm 0 is defined
+        if params[4] & 0x07 != 0 {
+            status |= 0x01;
+        }
+        let size = session.nb_frag as usize * session.frag_size as usize;
+        if session.nb_frag == 0
+            || session.frag_size == 0
+            || session.nb_frag as usize > 8 * B
+            || size > self.store.capacity()
+        {
+            status |= 0x02;
+        }
+        if status & 0x0f == 0 {
+            debug!(
+                "fragmentation session {}: {} fragments of {} bytes",
+                session.index, session.nb_frag, session.frag_size
+            );
+            self.session = Some(session);
+            self.received = [0; B];
+            self.nb_received = 0;
+            self.coded = false;
+            self.nb_lost = 0;
+            self.pivots = 0;
+            self.completed = false;
+        }
+        status
+    }
+
+    fn status(&self, param: u8, out: &mut [u8; 5]) -> usize {
+        let Some(session) = &self.session else {
+            return 0;
+        };
+        let index = (param >> 1) & 0x03;
+        // Without the participants bit, only devices still missing fragments answer
+        if index != session.index || (param & 0x01 == 0 && self.completed) {
+            return 0;
+        }
+        let missing = if self.completed {
+            0
+        } else if self.coded {
+            self.nb_lost - self.pivots.count_ones() as usize
+        } else {
+            (session.nb_frag - self.nb_received) as usize
+        };
+        let received_and_index = (session.index as u16) << 14 | self.nb_received.min(0x3fff);
+        out[0] = FRAG_SESSION_STATUS_REQ;
+        out[1..3].copy_from_slice(&received_and_index.to_le_bytes());
+        out[3] = missing.min(u8::MAX as usize) as u8;
+        out[4] = (self.coded && self.nb_lost > L) as u8;
+        5
+    }
+
+    /// Handle fragment `n`, numbered from 1, returning true if it completed the data block.
+    async fn fragment(&mut self, index: u8, n: u16, data: &[u8]) -> Result<bool, Error<S::Error>> {
+        let Some(session) = self.session else {
+            return Ok(false);
+        };
+        let frag_size = session.frag_size as usize;
+        if index != session.index || self.completed || n == 0 || data.len() != frag_size {
+            return Ok(false);
+        }
+        self.nb_received = self.nb_received.saturating_add(1);
+
+        let nb_frag = session.nb_frag as usize;
+        let n = n as usize - 1;
+        if n < nb_frag {
+            // Uncoded fragments are all sent before the coded ones, late ones are ignored
+            if self.coded || self.is_received(n) {
+                return Ok(false);
+            }
+            self.write(n, data).await?;
+            self.received[n / 8] |= 1 << (n % 8);
+            if (0..nb_frag).all(|n| self.is_received(n)) {
+                self.completed = true;
+            }
+            return Ok(self.completed);
+        }
+
+        if !self.coded {
+            self.coded = true;
+            self.nb_lost = 0;
+            for n in 0..nb_frag {
+                if self.received[n / 8] & (1 << (n % 8)) != 0 {
+                    continue;
+                }
+                if let Some(lost) = self.lost.get_mut(self.nb_lost) {
+                    *lost = n as u16;
+                }
+                self.nb_lost += 1;
+            }
+            if self.nb_lost > L {
+                warn!("{} fragments lost, only {} can be recovered", self.nb_lost, L);
+            }
+        }
+        if self.nb_lost > L {
+            return Ok(false);
+        }
+
+        // Reduce the coded fragment to the lost fragments it combines
+        let mut buf = [0; MAX_FRAGMENT_SIZE];
+        let mut other = [0; MAX_FRAGMENT_SIZE];
+        let buf = &mut buf[..frag_size];
+        let other = &mut other[..frag_size];
+        buf.copy_from_slice(data);
+        parity_row(n - nb_frag + 1, nb_frag, &mut self.row);
+        let mut row = 0u128;
+        for m in 0..nb_frag {
+            if self.row[m / 8] & (1 << (m % 8)) == 0 {
+                continue;
+            }
+            if self.is_received(m) {
+                self.read(m, other).await?;
+                xor(buf, other);
+            } else {
+                let rank = unwrap!(self.lost[..self.nb_lost].binary_search(&(m as u16)).ok());
+                row |= 1 << rank;
+            }
+        }
+
+        // Eliminate the known pivots, and keep the row if it brings a new one
+        while row != 0 {
+            let k = row.trailing_zeros() as usize;
+            if self.pivots & (1 << k) == 0 {
+                self.matrix[k] = row;
+                self.pivots |= 1 << k;
+                self.write(self.lost[k] as usize, buf).await?;
+                break;
+            }
+            row ^= self.matrix[k];
+            self.read(self.lost[k] as usize, other).await?;
+            xor(buf, other);
+        }
+        if self.pivots.count_ones() as usize != self.nb_lost {
+            return Ok(false);
+        }
+
+        // Back substitution, from the last lost fragment whose row only holds its own pivot
+        for k in (0..self.nb_lost).rev() {
+            let mut row = self.matrix[k] & !(1 << k);
+            if row == 0 {
+                continue;
+            }
+            self.read(self.lost[k] as usize, buf).await?;
+            while row != 0 {
+                let j = row.trailing_zeros() as usize;
+                row &= row - 1;
+                self.read(self.lost[j] as usize, other).await?;
+                xor(buf, other);
+            }
+            self.write(self.lost[k] as usize, buf).await?;
+            self.matrix[k] = 1 << k;
+        }
+        debug!("{} lost fragments recovered", self.nb_lost);
+        self.completed = true;
+        Ok(true)
+    }
+
+    fn is_received(&self, n: usize) -> bool {
+        self.received[n / 8] & (1 << (n % 8)) != 0
+    }
+
+    fn offset(&self, n: usize) -> u32 {
+        (n * self.session.map_or(0, |session| session.frag_size as usize)) as u32
+    }
+
+    async fn read(&mut self, n: usize, buf: &mut [u8]) -> Result<(), Error<S::Error>> {
+        self.store.read(self.offset(n), buf).await.map_err(Error::Store)
+    }
+
+    async fn write(&mut self, n: usize, data: &[u8]) -> Result<(), Error<S::Error>> {
+        self.store.write(self.offset(n), data).await.map_err(Error::Store)
+    }
+}
+
+fn xor(buf: &mut [u8], other: &[u8]) {
+    for (byte, other) in buf.iter_mut().zip(other) {
+        *byte ^= other;
+    }
+}
+
+fn prbs23(x: u32) -> u32 {
+    let b0 = x & 1;
+    let b1 = (x >> 5) & 1;
+    (x >> 1) | ((b0 ^ b1) << 22)
+}
+
+/// Uncoded fragments combined by coded fragment `n`, numbered from 1, as a bitmap over the `m` uncoded ones.
+fn parity_row(n: usize, m: usize, row: &mut [u8]) {
+    row.fill(0);
+    let m_power_of_two = m.is_power_of_two() as u32;
+    let mut x = 1 + 1001 * n as u32;
+    for _ in 0..m / 2 {
+        let mut r = 1 << 16;
+        while r >= m as u32 {
+            x = prbs23(x);
+            r = x % (m as u32 + m_power_of_two);
+        }
+        row[r as usize / 8] |= 1 << (r % 8);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::convert::Infallible;
+
+    use futures_executor::block_on;
+
+    use super::*;
+
+    struct Ram([u8; 256]);
+
+    impl FragmentStore for Ram {
+        type Error = Infallible;
+
+        fn capacity(&self) -> usize {
+            self.0.len()
+        }
+
+        async fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), Infallible> {
+            buf.copy_from_slice(&self.0[offset as usize..][..buf.len()]);
+            Ok(())
+        }
+
+        async fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), Infallible> {
+            self.0[offset as usize..][..data.len()].copy_from_slice(data);
+            Ok(())
+        }
+    }
+
+    const NB_FRAG: usize = 20;
+    const FRAG_SIZE: usize = 8;
+
+    fn image() -> [u8; NB_FRAG * FRAG_SIZE] {
+        core::array::from_fn(|i| (i * 7 + 3) as u8)
+    }
+
+    /// Data fragment command carrying fragment `n` of `image`, coded ones included.
+    fn data_fragment(image: &[u8], n: usize) -> [u8; 3 + FRAG_SIZE] {
+        let mut frame = [0; 3 + FRAG_SIZE];
+        frame[0] = DATA_FRAGMENT;
+        frame[1..3].copy_from_slice(&(n as u16).to_le_bytes());
+        if n <= NB_FRAG {
+            frame[3..].copy_from_slice(&image[(n - 1) * FRAG_SIZE..][..FRAG_SIZE]);
+        } else {
+            let mut row = [0; 4];
+            parity_row(n - NB_FRAG, NB_FRAG, &mut row);
+            for m in (0..NB_FRAG).filter(|m| row[m / 8] & (1 << (m % 8)) != 0) {
+                xor(&mut frame[3..], &image[m * FRAG_SIZE..][..FRAG_SIZE]);
+            }
+        }
+        frame
+    }
+
+    #[test]
+    fn session_commands() {
+        let mut receiver = FragmentationReceiver::<_, 4, 8>::new(Ram([0; 256]));
+        let mut answer = [0; 16];
+        // Package version, then a session of 20 fragments of 8 bytes with 4 bytes of padding
+        let setup = [0x00, 0x02, 0x10, 20, 0, 8, 0, 4, 1, 2, 3, 4];
+        let response = block_on(receiver.handle(&setup, &mut answer)).unwrap();
+        assert_eq!(&answer[..response.len], &[0x00, 3, 1, 0x02, 0x40]);
+        assert_eq!(receiver.session().unwrap().size(), 156);
+
+        // Too large for the store
+        let response = block_on(receiver.handle(&[0x02, 0x00, 64, 0, 8, 0, 0, 0, 0, 0, 0], &mut answer)).unwrap();
+        assert_eq!(&answer[..response.len], &[0x02, 0x02]);
+
+        let mut fragment = data_fragment(&image(), 1);
+        fragment[2] |= 0x40;
+        let response = block_on(receiver.handle(&fragment, &mut answer)).unwrap();
+        assert_eq!(response.len, 0);
+        // Fragments were lost between the first and the status request
+        let response = block_on(receiver.handle(&[0x01, 0x03], &mut answer)).unwrap();
+        assert_eq!(&answer[..response.len], &[0x01, 0x01, 0x40, 19, 0]);
+
+        let response = block_on(receiver.handle(&[0x03, 0x01], &mut answer)).unwrap();
+        assert_eq!(&answer[..response.len], &[0x03, 0x01]);
+        assert_eq!(receiver.session(), None);
+        assert_eq!(block_on(receiver.handle(&[0x05], &mut answer)), Err(Error::Malformed));
+    }
+
+    #[test]
+    fn lost_fragments_are_recovered() {
+        let image = image();
+        let mut receiver = FragmentationReceiver::<_, 4, 8>::new(Ram([0; 256]));
+        let mut answer = [0; 16];
+        block_on(receiver.handle(
+            &[0x02, 0x00, NB_FRAG as u8, 0, FRAG_SIZE as u8, 0, 0, 0, 0, 0, 0],
+            &mut answer,
+        ))
+        .unwrap();
+
+        let lost = [2, 3, 9, 14, 20];
+        for n in (1..=NB_FRAG).filter(|n| !lost.contains(n)) {
+            let response = block_on(receiver.handle(&data_fragment(&image, n), &mut answer)).unwrap();
+            assert_eq!(response.completed, None);
+        }
+        // Coded fragments until the lost ones are rebuilt
+        let mut completed = None;
+        for n in NB_FRAG + 1..=2 * NB_FRAG {
+            let response = block_on(receiver.handle(&data_fragment(&image, n), &mut answer)).unwrap();
+            completed = response.completed;
+            if completed.is_some() {
+                break;
+            }
+        }
+        assert_eq!(completed.unwrap().size(), image.len());
+        assert_eq!(&receiver.release().0[..image.len()], &image[..]);
+    }
+}
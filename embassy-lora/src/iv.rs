@@ -1,3 +1,10 @@
+#[cfg(feature = "stm32wl")]
+use core::future::poll_fn;
+#[cfg(feature = "stm32wl")]
+use core::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "stm32wl")]
+use core::task::Poll;
+
 #[cfg(feature = "stm32wl")]
 use embassy_stm32::interrupt;
 #[cfg(feature = "stm32wl")]
@@ -23,6 +30,8 @@ pub struct InterruptHandler {}
 impl interrupt::typelevel::Handler<interrupt::typelevel::SUBGHZ_RADIO> for InterruptHandler {
     unsafe fn on_interrupt() {
         interrupt::SUBGHZ_RADIO.disable();
+        #[cfg(feature = "latency-audit")]
+        crate::latency::irq_raised();
         IRQ_SIGNAL.signal(());
     }
 }
@@ -30,48 +39,311 @@ impl interrupt::typelevel::Handler<interrupt::typelevel::SUBGHZ_RADIO> for Inter
 #[cfg(feature = "stm32wl")]
 static IRQ_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
 
+#[cfg(feature = "stm32wl")]
+static SPI_ACTIVE: AtomicBool = AtomicBool::new(false);
+#[cfg(feature = "stm32wl")]
+static BUSY_WAIT: AtomicBool = AtomicBool::new(false);
+#[cfg(feature = "stm32wl")]
+static RADIO_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Flag raised for as long as the guard lives, so that a cancelled radio operation cannot leave it raised.
+///
+/// With the `low-power` feature, the guard also keeps the low-power executor of embassy-stm32 out of Stop2.
+#[cfg(feature = "stm32wl")]
+struct FlagGuard(&'static AtomicBool);
+
+#[cfg(feature = "stm32wl")]
+impl FlagGuard {
+    fn raise(flag: &'static AtomicBool) -> Self {
+        flag.store(true, Ordering::Relaxed);
+        #[cfg(feature = "low-power")]
+        embassy_stm32::low_power::block_stop2();
+        Self(flag)
+    }
+
+    /// Raise the flag into `guard`, unless it already holds it.
+    fn raise_into(guard: &mut Option<Self>, flag: &'static AtomicBool) {
+        if guard.is_none() {
+            *guard = Some(Self::raise(flag));
+        }
+    }
+}
+
+#[cfg(feature = "stm32wl")]
+impl Drop for FlagGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Relaxed);
+        #[cfg(feature = "low-power")]
+        embassy_stm32::low_power::allow_stop2();
+    }
+}
+
+/// State of the stm32wl radio that matters to entering Stop2, see [`stop2_state`].
+#[cfg(feature = "stm32wl")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Stop2State {
+    /// A command is being transferred over SUBGHZSPI.
+    pub spi_active: bool,
+    /// The driver is waiting for the radio to release BUSY.
+    pub busy_wait: bool,
+    /// A radio interrupt was raised and its task has not run yet.
+    pub irq_pending: bool,
+    /// The radio neither transmits nor receives. lora-phy 2 does not tell the interface variant whether the radio
+    /// is in standby or asleep, only that its RF switch is off, which it turns off before either.
+    pub radio_idle: bool,
+}
+
+#[cfg(feature = "stm32wl")]
+impl Stop2State {
+    /// Whether the MCU may enter Stop2 without disrupting the radio: no SUBGHZSPI transfer, BUSY wait or raised
+    /// interrupt is in progress, and the radio is asleep or in standby.
+    pub fn ready(&self) -> bool {
+        !self.spi_active && !self.busy_wait && !self.irq_pending && self.radio_idle
+    }
+}
+
+/// State of the stm32wl radio that decides whether the MCU may enter Stop2.
+///
+/// With the `low-power` feature, the stm32wl interface variant blocks Stop2 in the low-power executor of
+/// embassy-stm32 whenever [`Stop2State::ready`] does not hold. A raised interrupt wakes its task, which the
+/// executor polls before it goes to sleep.
+#[cfg(feature = "stm32wl")]
+pub fn stop2_state() -> Stop2State {
+    Stop2State {
+        spi_active: SPI_ACTIVE.load(Ordering::Relaxed),
+        busy_wait: BUSY_WAIT.load(Ordering::Relaxed),
+        irq_pending: IRQ_SIGNAL.signaled() || interrupt::SUBGHZ_RADIO.is_pending(),
+        radio_idle: !RADIO_ACTIVE.load(Ordering::Relaxed),
+    }
+}
+
+/// Whether the MCU may enter Stop2 without disrupting the stm32wl radio, see [`stop2_state`].
+#[cfg(feature = "stm32wl")]
+pub fn stop2_ready() -> bool {
+    stop2_state().ready()
+}
+
+/// Number of BUSY polls after which the stm32wl interface variant starts yielding to the executor.
+#[cfg(feature = "stm32wl")]
+const DEFAULT_BUSY_SPIN_LIMIT: u32 = 1000;
+
+/// Let other tasks run, resuming the current one on the next executor poll.
+#[cfg(feature = "stm32wl")]
+async fn yield_now() {
+    let mut yielded = false;
+    poll_fn(|cx| {
+        if yielded {
+            Poll::Ready(())
+        } else {
+            yielded = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    })
+    .await
+}
+
+/// State requested from an RF switch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RfSwitchState {
+    /// Radio in standby or asleep, antenna disconnected, lowest consumption.
+    Sleep,
+    /// Antenna connected to the receive path, receiver in power saving mode.
+    RxLowPower,
+    /// Antenna connected to the receive path, receiver in boosted gain mode.
+    RxHighPower,
+    /// Antenna connected to the output of the low power amplifier, e.g. RFO_LP of the stm32wl.
+    TxLowPower,
+    /// Antenna connected to the output of the high power amplifier, e.g. RFO_HP of the stm32wl.
+    TxHighPower,
+}
+
+/// Receiver mode and power amplifier the radio is configured with, which select the states of the RF switch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RfPaths {
+    /// The receiver runs in boosted gain mode.
+    pub rx_boosted: bool,
+    /// Transmissions go through the high power amplifier.
+    pub tx_high_power: bool,
+}
+
+impl RfPaths {
+    /// Paths used by lora-phy on `board_type`.
+    ///
+    /// The sx1261 only has the low power amplifier, the other radios transmit through the high power one. The
+    /// receiver is assumed boosted, as `LorawanRadio` always configures it; point-to-point
+    /// links without `rx_boosted` should override this.
+    pub fn for_board(board_type: BoardType) -> Self {
+        Self {
+            rx_boosted: true,
+            tx_high_power: board_type != BoardType::GenericSx1261,
+        }
+    }
+
+    /// Switch state for receiving.
+    pub fn rx(&self) -> RfSwitchState {
+        if self.rx_boosted {
+            RfSwitchState::RxHighPower
+        } else {
+            RfSwitchState::RxLowPower
+        }
+    }
+
+    /// Switch state for transmitting.
+    pub fn tx(&self) -> RfSwitchState {
+        if self.tx_high_power {
+            RfSwitchState::TxHighPower
+        } else {
+            RfSwitchState::TxLowPower
+        }
+    }
+}
+
+/// RF switch of a board, set by the interface variant around radio operations.
+///
+/// Boards drive their RF switch with one to three control lines, with truth tables of their own. Implementing
+/// this trait for the board lets the interface variant drive any of them.
+pub trait RfSwitch {
+    /// Set the switch to `state`.
+    fn set(&mut self, state: RfSwitchState) -> Result<(), RadioError>;
+}
+
+/// RF switch with an optional control line for each of the receive and transmit paths, driven high to select
+/// the path. Both receiver modes share the receive line, and both power amplifiers the transmit line.
+pub struct PinRfSwitch<CTRL> {
+    rx: Option<CTRL>,
+    tx: Option<CTRL>,
+}
+
+impl<CTRL> PinRfSwitch<CTRL>
+where
+    CTRL: OutputPin,
+{
+    /// Create an RF switch from its receive and transmit control lines.
+    pub fn new(rx: Option<CTRL>, tx: Option<CTRL>) -> Self {
+        Self { rx, tx }
+    }
+}
+
+impl<CTRL> RfSwitch for PinRfSwitch<CTRL>
+where
+    CTRL: OutputPin,
+{
+    fn set(&mut self, state: RfSwitchState) -> Result<(), RadioError> {
+        let (rx, tx) = match state {
+            RfSwitchState::Sleep => (false, false),
+            RfSwitchState::RxLowPower | RfSwitchState::RxHighPower => (true, false),
+            RfSwitchState::TxLowPower | RfSwitchState::TxHighPower => (false, true),
+        };
+        // Release the path being left before selecting the other one
+        if !rx {
+            if let Some(pin) = &mut self.rx {
+                set_level(pin, false, false).map_err(|_| RfSwitchRx)?;
+            }
+        }
+        if let Some(pin) = &mut self.tx {
+            set_level(pin, tx, false).map_err(|_| RfSwitchTx)?;
+        }
+        if rx {
+            if let Some(pin) = &mut self.rx {
+                set_level(pin, true, false).map_err(|_| RfSwitchRx)?;
+            }
+        }
+        Ok(())
+    }
+}
+
 #[cfg(feature = "stm32wl")]
 /// Base for the InterfaceVariant implementation for an stm32wl/sx1262 combination
-pub struct Stm32wlInterfaceVariant<CTRL> {
+pub struct Stm32wlInterfaceVariant<SW> {
     board_type: BoardType,
-    rf_switch_rx: Option<CTRL>,
-    rf_switch_tx: Option<CTRL>,
+    rf_switch: SW,
+    rf_paths: Option<RfPaths>,
+    busy_spin_limit: u32,
+    // Held from NSS low to NSS high. A transfer cancelled halfway leaves NSS low, and so the flag raised, until
+    // the next command completes.
+    spi_active: Option<FlagGuard>,
+    // Held while the RF switch is on, from the start of a transmission or reception until standby or sleep.
+    radio_active: Option<FlagGuard>,
 }
 
 #[cfg(feature = "stm32wl")]
-impl<'a, CTRL> Stm32wlInterfaceVariant<CTRL>
+impl<CTRL> Stm32wlInterfaceVariant<PinRfSwitch<CTRL>>
 where
     CTRL: OutputPin,
 {
     /// Create an InterfaceVariant instance for an stm32wl/sx1262 combination
     pub fn new(
-        _irq: impl interrupt::typelevel::Binding<interrupt::typelevel::SUBGHZ_RADIO, InterruptHandler>,
+        irq: impl interrupt::typelevel::Binding<interrupt::typelevel::SUBGHZ_RADIO, InterruptHandler>,
         rf_switch_rx: Option<CTRL>,
         rf_switch_tx: Option<CTRL>,
+    ) -> Result<Self, RadioError> {
+        Self::with_rf_switch(irq, PinRfSwitch::new(rf_switch_rx, rf_switch_tx))
+    }
+}
+
+#[cfg(feature = "stm32wl")]
+impl<SW> Stm32wlInterfaceVariant<SW>
+where
+    SW: RfSwitch,
+{
+    /// Create an InterfaceVariant instance for an stm32wl/sx1262 combination, on a board whose RF switch is
+    /// driven by `rf_switch`
+    pub fn with_rf_switch(
+        _irq: impl interrupt::typelevel::Binding<interrupt::typelevel::SUBGHZ_RADIO, InterruptHandler>,
+        rf_switch: SW,
     ) -> Result<Self, RadioError> {
         interrupt::SUBGHZ_RADIO.disable();
         Ok(Self {
             board_type: BoardType::Stm32wlSx1262, // updated when associated with a specific LoRa board
-            rf_switch_rx,
-            rf_switch_tx,
+            rf_switch,
+            rf_paths: None,
+            busy_spin_limit: DEFAULT_BUSY_SPIN_LIMIT,
+            spi_active: None,
+            radio_active: None,
         })
     }
+
+    /// Set the receiver mode and power amplifier selecting the states of the RF switch, or follow the board type
+    /// with `None`, see [`RfPaths::for_board`].
+    pub fn set_rf_paths(&mut self, rf_paths: Option<RfPaths>) {
+        self.rf_paths = rf_paths;
+    }
+
+    fn rf_paths(&self) -> RfPaths {
+        self.rf_paths.unwrap_or(RfPaths::for_board(self.board_type))
+    }
+
+    /// Set the number of times the BUSY flag is polled before yielding to the executor.
+    ///
+    /// The radio has no interrupt on BUSY, so it has to be polled. Short BUSY periods, which are the majority,
+    /// are waited for by spinning, avoiding the cost of a context switch. Once the limit is reached, the task
+    /// yields between polls so that long BUSY periods, such as the oscillator startup, do not starve other
+    /// tasks. A limit of 0 always yields, `u32::MAX` always spins.
+    pub fn set_busy_spin_limit(&mut self, polls: u32) {
+        self.busy_spin_limit = polls;
+    }
 }
 
 #[cfg(feature = "stm32wl")]
-impl<CTRL> InterfaceVariant for Stm32wlInterfaceVariant<CTRL>
+impl<SW> InterfaceVariant for Stm32wlInterfaceVariant<SW>
 where
-    CTRL: OutputPin,
+    SW: RfSwitch,
 {
     fn set_board_type(&mut self, board_type: BoardType) {
         self.board_type = board_type;
     }
     async fn set_nss_low(&mut self) -> Result<(), RadioError> {
+        FlagGuard::raise_into(&mut self.spi_active, &SPI_ACTIVE);
         pac::PWR.subghzspicr().modify(|w| w.set_nss(false));
         Ok(())
     }
     async fn set_nss_high(&mut self) -> Result<(), RadioError> {
         pac::PWR.subghzspicr().modify(|w| w.set_nss(true));
+        self.spi_active = None;
         Ok(())
     }
     async fn reset(&mut self, _delay: &mut impl DelayUs) -> Result<(), RadioError> {
@@ -80,53 +352,125 @@ where
         Ok(())
     }
     async fn wait_on_busy(&mut self) -> Result<(), RadioError> {
-        while pac::PWR.sr2().read().rfbusys() {}
+        #[cfg(feature = "latency-audit")]
+        let start = crate::latency::now();
+        let busy_wait = FlagGuard::raise(&BUSY_WAIT);
+        let mut polls = 0;
+        while pac::PWR.sr2().read().rfbusys() {
+            if polls < self.busy_spin_limit {
+                polls += 1;
+            } else {
+                yield_now().await;
+            }
+        }
+        drop(busy_wait);
+        #[cfg(feature = "latency-audit")]
+        crate::latency::busy_released(start);
         Ok(())
     }
 
     async fn await_irq(&mut self) -> Result<(), RadioError> {
         unsafe { interrupt::SUBGHZ_RADIO.enable() };
         IRQ_SIGNAL.wait().await;
+        #[cfg(feature = "latency-audit")]
+        crate::latency::irq_handled();
         Ok(())
     }
 
     async fn enable_rf_switch_rx(&mut self) -> Result<(), RadioError> {
-        match &mut self.rf_switch_tx {
-            Some(pin) => pin.set_low().map_err(|_| RfSwitchTx)?,
-            None => (),
-        };
-        match &mut self.rf_switch_rx {
-            Some(pin) => pin.set_high().map_err(|_| RfSwitchRx),
-            None => Ok(()),
-        }
+        FlagGuard::raise_into(&mut self.radio_active, &RADIO_ACTIVE);
+        self.rf_switch.set(self.rf_paths().rx())
     }
     async fn enable_rf_switch_tx(&mut self) -> Result<(), RadioError> {
-        match &mut self.rf_switch_rx {
-            Some(pin) => pin.set_low().map_err(|_| RfSwitchRx)?,
-            None => (),
-        };
-        match &mut self.rf_switch_tx {
-            Some(pin) => pin.set_high().map_err(|_| RfSwitchTx),
-            None => Ok(()),
-        }
+        FlagGuard::raise_into(&mut self.radio_active, &RADIO_ACTIVE);
+        self.rf_switch.set(self.rf_paths().tx())
     }
     async fn disable_rf_switch(&mut self) -> Result<(), RadioError> {
-        match &mut self.rf_switch_rx {
-            Some(pin) => pin.set_low().map_err(|_| RfSwitchRx)?,
-            None => (),
-        };
-        match &mut self.rf_switch_tx {
-            Some(pin) => pin.set_low().map_err(|_| RfSwitchTx),
-            None => Ok(()),
+        // lora-phy turns the RF switch off before putting the radio in standby or to sleep
+        self.radio_active = None;
+        self.rf_switch.set(RfSwitchState::Sleep)
+    }
+}
+
+/// Timing of the hardware reset of the radio, for the interface variants driving a reset pin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ResetTiming {
+    /// Time to wait before asserting reset, in milliseconds.
+    pub pre_delay_ms: u32,
+    /// Width of the reset pulse, in milliseconds.
+    pub pulse_ms: u32,
+    /// Time to wait after releasing reset, before the first command, in milliseconds.
+    pub post_delay_ms: u32,
+}
+
+/// Drive `pin` to the logical level `high`, which is inverted when the line goes through an inverting buffer.
+fn set_level<P: OutputPin>(pin: &mut P, high: bool, inverted: bool) -> Result<(), P::Error> {
+    if high != inverted {
+        pin.set_high()
+    } else {
+        pin.set_low()
+    }
+}
+
+/// Reset and NSS lines of the interface variants driving them from GPIOs.
+pub struct ControlPins<CTRL> {
+    nss: CTRL,
+    reset: CTRL,
+    reset_timing: Option<ResetTiming>,
+    reset_inverted: bool,
+    nss_inverted: bool,
+}
+
+impl<CTRL> ControlPins<CTRL>
+where
+    CTRL: OutputPin,
+{
+    fn new(nss: CTRL, reset: CTRL, reset_timing: ResetTiming) -> Self {
+        Self {
+            nss,
+            reset,
+            reset_timing: Some(reset_timing),
+            reset_inverted: false,
+            nss_inverted: false,
         }
     }
+
+    /// Set the timing of the reset sequence, or `None` if the radio is reset externally, e.g. through a reset
+    /// line shared with other devices, in which case the reset pin is left untouched.
+    pub fn set_reset_timing(&mut self, timing: Option<ResetTiming>) {
+        self.reset_timing = timing;
+    }
+
+    /// Declare that the reset or NSS lines go through inverting level shifters, so that the driver drives the
+    /// pins to the opposite levels while keeping the timing of its sequences.
+    pub fn set_inverted_pins(&mut self, reset: bool, nss: bool) {
+        self.reset_inverted = reset;
+        self.nss_inverted = nss;
+    }
+
+    fn set_nss(&mut self, high: bool) -> Result<(), RadioError> {
+        set_level(&mut self.nss, high, self.nss_inverted).map_err(|_| NSS)
+    }
+
+    /// Pulse the reset pin, unless the reset is handled externally.
+    async fn reset(&mut self, delay: &mut impl DelayUs) -> Result<(), RadioError> {
+        let Some(timing) = self.reset_timing else {
+            return Ok(());
+        };
+        delay.delay_ms(timing.pre_delay_ms).await;
+        set_level(&mut self.reset, false, self.reset_inverted).map_err(|_| Reset)?;
+        delay.delay_ms(timing.pulse_ms).await;
+        set_level(&mut self.reset, true, self.reset_inverted).map_err(|_| Reset)?;
+        delay.delay_ms(timing.post_delay_ms).await;
+        Ok(())
+    }
 }
 
 /// Base for the InterfaceVariant implementation for an stm32l0/sx1276 combination
 pub struct Stm32l0InterfaceVariant<CTRL, WAIT> {
     board_type: BoardType,
-    nss: CTRL,
-    reset: CTRL,
+    pins: ControlPins<CTRL>,
     irq: WAIT,
     rf_switch_rx: Option<CTRL>,
     rf_switch_tx: Option<CTRL>,
@@ -147,13 +491,25 @@ where
     ) -> Result<Self, RadioError> {
         Ok(Self {
             board_type: BoardType::Stm32l0Sx1276, // updated when associated with a specific LoRa board
-            nss,
-            reset,
+            pins: ControlPins::new(
+                nss,
+                reset,
+                ResetTiming {
+                    pre_delay_ms: 10,
+                    pulse_ms: 10,
+                    post_delay_ms: 10,
+                },
+            ),
             irq,
             rf_switch_rx,
             rf_switch_tx,
         })
     }
+
+    /// The reset and NSS lines, to adjust the reset sequence and the polarity of the lines.
+    pub fn control_pins(&mut self) -> &mut ControlPins<CTRL> {
+        &mut self.pins
+    }
 }
 
 impl<CTRL, WAIT> InterfaceVariant for Stm32l0InterfaceVariant<CTRL, WAIT>
@@ -165,18 +521,13 @@ where
         self.board_type = board_type;
     }
     async fn set_nss_low(&mut self) -> Result<(), RadioError> {
-        self.nss.set_low().map_err(|_| NSS)
+        self.pins.set_nss(false)
     }
     async fn set_nss_high(&mut self) -> Result<(), RadioError> {
-        self.nss.set_high().map_err(|_| NSS)
+        self.pins.set_nss(true)
     }
     async fn reset(&mut self, delay: &mut impl DelayUs) -> Result<(), RadioError> {
-        delay.delay_ms(10).await;
-        self.reset.set_low().map_err(|_| Reset)?;
-        delay.delay_ms(10).await;
-        self.reset.set_high().map_err(|_| Reset)?;
-        delay.delay_ms(10).await;
-        Ok(())
+        self.pins.reset(delay).await
     }
     async fn wait_on_busy(&mut self) -> Result<(), RadioError> {
         Ok(())
@@ -217,11 +568,117 @@ where
     }
 }
 
+/// Startup time of the TCXO of the Murata CMWX1ZZABZ module, in milliseconds.
+const CMWX1ZZABZ_TCXO_STARTUP_MS: u32 = 5;
+
+/// Base for the InterfaceVariant implementation for the Murata CMWX1ZZABZ (Type ABZ) module
+///
+/// The module pairs an sx1276 with a TCXO that must be powered before the radio is reset, and an RF switch
+/// driven by three control lines. The radio interrupts are routed to DIO0. On the B-L072Z-LRWAN1 board the
+/// module is wired as follows:
+/// - NSS: PA15, RESET: PC0, DIO0: PB4
+/// - TCXO_VCC: PA12
+/// - CRF1 (RX): PA1, CRF2 (TX RFO): PC2, CRF3 (TX PA_BOOST): PC1
+pub struct Cmwx1zzabzInterfaceVariant<CTRL, WAIT> {
+    board_type: BoardType,
+    pins: ControlPins<CTRL>,
+    dio0: WAIT,
+    tcxo_enable: CTRL,
+    rf_switch_rx: CTRL,
+    rf_switch_tx_rfo: CTRL,
+    rf_switch_tx_boost: CTRL,
+}
+
+impl<CTRL, WAIT> Cmwx1zzabzInterfaceVariant<CTRL, WAIT>
+where
+    CTRL: OutputPin,
+    WAIT: Wait,
+{
+    /// Create an InterfaceVariant instance for a Murata CMWX1ZZABZ module
+    pub fn new(
+        nss: CTRL,
+        reset: CTRL,
+        dio0: WAIT,
+        tcxo_enable: CTRL,
+        rf_switch_rx: CTRL,
+        rf_switch_tx_rfo: CTRL,
+        rf_switch_tx_boost: CTRL,
+    ) -> Result<Self, RadioError> {
+        Ok(Self {
+            board_type: BoardType::Stm32l0Sx1276, // updated when associated with a specific LoRa board
+            pins: ControlPins::new(
+                nss,
+                reset,
+                ResetTiming {
+                    pre_delay_ms: 5,
+                    pulse_ms: 10,
+                    post_delay_ms: 10,
+                },
+            ),
+            dio0,
+            tcxo_enable,
+            rf_switch_rx,
+            rf_switch_tx_rfo,
+            rf_switch_tx_boost,
+        })
+    }
+
+    /// The reset and NSS lines, to adjust the reset sequence and the polarity of the lines.
+    pub fn control_pins(&mut self) -> &mut ControlPins<CTRL> {
+        &mut self.pins
+    }
+}
+
+impl<CTRL, WAIT> InterfaceVariant for Cmwx1zzabzInterfaceVariant<CTRL, WAIT>
+where
+    CTRL: OutputPin,
+    WAIT: Wait,
+{
+    fn set_board_type(&mut self, board_type: BoardType) {
+        self.board_type = board_type;
+    }
+    async fn set_nss_low(&mut self) -> Result<(), RadioError> {
+        self.pins.set_nss(false)
+    }
+    async fn set_nss_high(&mut self) -> Result<(), RadioError> {
+        self.pins.set_nss(true)
+    }
+    async fn reset(&mut self, delay: &mut impl DelayUs) -> Result<(), RadioError> {
+        // The sx1276 is clocked by the TCXO, which must be running before the radio comes out of reset, even
+        // when the reset is handled externally
+        self.tcxo_enable.set_high().map_err(|_| Reset)?;
+        delay.delay_ms(CMWX1ZZABZ_TCXO_STARTUP_MS).await;
+        self.pins.reset(delay).await
+    }
+    async fn wait_on_busy(&mut self) -> Result<(), RadioError> {
+        Ok(())
+    }
+    async fn await_irq(&mut self) -> Result<(), RadioError> {
+        self.dio0.wait_for_high().await.map_err(|_| Irq)
+    }
+
+    async fn enable_rf_switch_rx(&mut self) -> Result<(), RadioError> {
+        self.rf_switch_tx_rfo.set_low().map_err(|_| RfSwitchTx)?;
+        self.rf_switch_tx_boost.set_low().map_err(|_| RfSwitchTx)?;
+        self.rf_switch_rx.set_high().map_err(|_| RfSwitchRx)
+    }
+    async fn enable_rf_switch_tx(&mut self) -> Result<(), RadioError> {
+        // lora-phy transmits through the PA_BOOST output of the sx1276
+        self.rf_switch_rx.set_low().map_err(|_| RfSwitchRx)?;
+        self.rf_switch_tx_rfo.set_low().map_err(|_| RfSwitchTx)?;
+        self.rf_switch_tx_boost.set_high().map_err(|_| RfSwitchTx)
+    }
+    async fn disable_rf_switch(&mut self) -> Result<(), RadioError> {
+        self.rf_switch_rx.set_low().map_err(|_| RfSwitchRx)?;
+        self.rf_switch_tx_rfo.set_low().map_err(|_| RfSwitchTx)?;
+        self.rf_switch_tx_boost.set_low().map_err(|_| RfSwitchTx)
+    }
+}
+
 /// Base for the InterfaceVariant implementation for a generic Sx126x LoRa board
 pub struct GenericSx126xInterfaceVariant<CTRL, WAIT> {
     board_type: BoardType,
-    nss: CTRL,
-    reset: CTRL,
+    pins: ControlPins<CTRL>,
     dio1: WAIT,
     busy: WAIT,
     rf_switch_rx: Option<CTRL>,
@@ -244,14 +701,26 @@ where
     ) -> Result<Self, RadioError> {
         Ok(Self {
             board_type: BoardType::Rak4631Sx1262, // updated when associated with a specific LoRa board
-            nss,
-            reset,
+            pins: ControlPins::new(
+                nss,
+                reset,
+                ResetTiming {
+                    pre_delay_ms: 10,
+                    pulse_ms: 20,
+                    post_delay_ms: 10,
+                },
+            ),
             dio1,
             busy,
             rf_switch_rx,
             rf_switch_tx,
         })
     }
+
+    /// The reset and NSS lines, to adjust the reset sequence and the polarity of the lines.
+    pub fn control_pins(&mut self) -> &mut ControlPins<CTRL> {
+        &mut self.pins
+    }
 }
 
 impl<CTRL, WAIT> InterfaceVariant for GenericSx126xInterfaceVariant<CTRL, WAIT>
@@ -263,21 +732,21 @@ where
         self.board_type = board_type;
     }
     async fn set_nss_low(&mut self) -> Result<(), RadioError> {
-        self.nss.set_low().map_err(|_| NSS)
+        self.pins.set_nss(false)
     }
     async fn set_nss_high(&mut self) -> Result<(), RadioError> {
-        self.nss.set_high().map_err(|_| NSS)
+        self.pins.set_nss(true)
     }
     async fn reset(&mut self, delay: &mut impl DelayUs) -> Result<(), RadioError> {
-        delay.delay_ms(10).await;
-        self.reset.set_low().map_err(|_| Reset)?;
-        delay.delay_ms(20).await;
-        self.reset.set_high().map_err(|_| Reset)?;
-        delay.delay_ms(10).await;
-        Ok(())
+        self.pins.reset(delay).await
     }
     async fn wait_on_busy(&mut self) -> Result<(), RadioError> {
-        self.busy.wait_for_low().await.map_err(|_| Busy)
+        #[cfg(feature = "latency-audit")]
+        let start = crate::latency::now();
+        self.busy.wait_for_low().await.map_err(|_| Busy)?;
+        #[cfg(feature = "latency-audit")]
+        crate::latency::busy_released(start);
+        Ok(())
     }
     async fn await_irq(&mut self) -> Result<(), RadioError> {
         self.dio1.wait_for_high().await.map_err(|_| DIO1)?;
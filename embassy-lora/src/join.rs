@@ -0,0 +1,199 @@
+//! Join request retransmission backoff.
+//!
+//! A device that cannot join, e.g. because it is out of coverage, must not flood the network with join
+//! requests. The LoRaWAN specification caps the aggregated time on air of join requests since the device
+//! started: 36 s during the first hour, 36 s over the following 10 hours, then 8.7 s every 24 hours.
+//! [`JoinBackoff`] tracks that budget and tells when the next join request may be sent. It also lowers the data
+//! rate as attempts fail, trading airtime for range, according to a [`JoinPolicy`].
+//!
+//! Attach it to a [`LorawanRadio`](crate::lorawan::LorawanRadio) to hold join requests back until they are
+//! allowed and send them at the decayed data rate. lorawan-device 0.11 has no way to set the data rate of join
+//! requests, so the radio substitutes the spreading factor of [`JoinBackoff::data_rate`] for the one picked by
+//! the MAC, and listens for the join accept in RX1 at the same spreading factor. Data rates with another
+//! bandwidth than the channel chosen by the MAC, e.g. the 500 kHz DR4 of US915, are left to the MAC.
+
+use embassy_time::{Duration, Instant, Timer};
+use lora_phy::mod_params::{Bandwidth, SpreadingFactor};
+
+use crate::region::RegionConfig;
+
+const HOUR: Duration = Duration::from_secs(3600);
+
+/// Data rate decay and spacing of join attempts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct JoinPolicy {
+    /// Data rate of the first attempts.
+    pub max_data_rate: u8,
+    /// Lowest data rate the attempts decay to.
+    pub min_data_rate: u8,
+    /// Attempts at each data rate before moving to the next lower one.
+    pub attempts_per_data_rate: u8,
+    /// Minimum time between the starts of two attempts, on top of the airtime budget.
+    pub min_interval: Duration,
+}
+
+impl JoinPolicy {
+    /// Decay from `max_data_rate` to `min_data_rate`, two attempts per data rate, with no minimum interval.
+    pub const fn new(min_data_rate: u8, max_data_rate: u8) -> Self {
+        Self {
+            max_data_rate,
+            min_data_rate,
+            attempts_per_data_rate: 2,
+            min_interval: Duration::from_ticks(0),
+        }
+    }
+}
+
+/// Backoff state of the join procedure.
+#[derive(Debug, Clone, Copy)]
+pub struct JoinBackoff {
+    policy: JoinPolicy,
+    region: RegionConfig,
+    start: Instant,
+    period: u32,
+    used_us: u64,
+    attempts: u32,
+    last_attempt: Option<Instant>,
+}
+
+impl JoinBackoff {
+    /// Start the backoff for a device of `region` started at `start`, usually its power up.
+    pub fn new(policy: JoinPolicy, region: RegionConfig, start: Instant) -> Self {
+        Self {
+            policy,
+            region,
+            start,
+            period: 0,
+            used_us: 0,
+            attempts: 0,
+            last_attempt: None,
+        }
+    }
+
+    /// The policy followed.
+    pub fn policy(&self) -> &JoinPolicy {
+        &self.policy
+    }
+
+    /// Number of join requests sent.
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Data rate of the next join request.
+    pub fn data_rate(&self) -> u8 {
+        let steps = self.attempts / self.policy.attempts_per_data_rate.max(1) as u32;
+        let steps = steps.min(u8::MAX as u32) as u8;
+        self.policy
+            .max_data_rate
+            .saturating_sub(steps)
+            .max(self.policy.min_data_rate)
+    }
+
+    /// Modulation of [`data_rate`](Self::data_rate) in the region, or `None` if it is not a LoRa data rate.
+    pub fn modulation(&self) -> Option<(SpreadingFactor, Bandwidth)> {
+        self.region.data_rate(self.data_rate())
+    }
+
+    /// Index, start, end and airtime budget in microseconds of the period containing `at`.
+    fn period_at(&self, at: Instant) -> (u32, Instant, Instant, u64) {
+        let elapsed = at.checked_duration_since(self.start).unwrap_or(Duration::from_ticks(0));
+        if elapsed < HOUR {
+            (0, self.start, self.start + HOUR, 36_000_000)
+        } else if elapsed < HOUR * 11 {
+            (1, self.start + HOUR, self.start + HOUR * 11, 36_000_000)
+        } else {
+            let day = ((elapsed - HOUR * 11).as_secs() / (24 * 3600)) as u32;
+            let period_start = self.start + HOUR * 11 + HOUR * 24 * day;
+            (2 + day, period_start, period_start + HOUR * 24, 8_700_000)
+        }
+    }
+
+    /// Earliest time from `now` at which a join request of `time_on_air_us` may be sent.
+    pub fn next_attempt(&self, now: Instant, time_on_air_us: u32) -> Instant {
+        let earliest = match self.last_attempt {
+            Some(last) => now.max(last + self.policy.min_interval),
+            None => now,
+        };
+        let (period, _, end, budget_us) = self.period_at(earliest);
+        let used_us = if period == self.period { self.used_us } else { 0 };
+        if used_us + time_on_air_us as u64 <= budget_us {
+            earliest
+        } else {
+            end
+        }
+    }
+
+    /// Record a join request of `time_on_air_us` sent at `at`.
+    pub fn on_attempt(&mut self, at: Instant, time_on_air_us: u32) {
+        let (period, _, _, _) = self.period_at(at);
+        if period != self.period {
+            self.period = period;
+            self.used_us = 0;
+        }
+        self.used_us += time_on_air_us as u64;
+        self.attempts = self.attempts.saturating_add(1);
+        self.last_attempt = Some(at);
+        debug!("join attempt {}, next at DR{}", self.attempts, self.data_rate());
+    }
+
+    /// Wait until a join request of `time_on_air_us` may be sent.
+    pub async fn wait(&self, time_on_air_us: u32) {
+        Timer::at(self.next_attempt(Instant::now(), time_on_air_us)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::region::Region;
+
+    const EU868: RegionConfig = RegionConfig::new(Region::Eu868);
+
+    #[test]
+    fn airtime_budget() {
+        let start = Instant::from_secs(0);
+        let mut backoff = JoinBackoff::new(JoinPolicy::new(0, 5), EU868, start);
+        let mut now = start;
+        // 1 s join requests: 36 in the first hour, then 36 over the next 10 hours
+        for _ in 0..36 {
+            now = backoff.next_attempt(now, 1_000_000);
+            backoff.on_attempt(now, 1_000_000);
+        }
+        assert!(now < start + HOUR);
+        assert_eq!(backoff.next_attempt(now, 1_000_000), start + HOUR);
+        for _ in 0..36 {
+            now = backoff.next_attempt(now, 1_000_000);
+            backoff.on_attempt(now, 1_000_000);
+        }
+        assert_eq!(backoff.next_attempt(now, 1_000_000), start + HOUR * 11);
+
+        // 8 per day afterwards
+        now = start + HOUR * 11;
+        for _ in 0..8 {
+            now = backoff.next_attempt(now, 1_000_000);
+            backoff.on_attempt(now, 1_000_000);
+        }
+        assert_eq!(backoff.next_attempt(now, 1_000_000), start + HOUR * 35);
+    }
+
+    #[test]
+    fn data_rate_decay() {
+        let policy = JoinPolicy {
+            min_interval: Duration::from_secs(10),
+            ..JoinPolicy::new(1, 5)
+        };
+        let start = Instant::from_secs(0);
+        let mut backoff = JoinBackoff::new(policy, EU868, start);
+        let mut data_rates = [0; 12];
+        for data_rate in &mut data_rates {
+            *data_rate = backoff.data_rate();
+            let at = backoff.next_attempt(start, 100_000);
+            backoff.on_attempt(at, 100_000);
+        }
+        assert_eq!(data_rates, [5, 5, 4, 4, 3, 3, 2, 2, 1, 1, 1, 1]);
+        assert_eq!(backoff.modulation(), Some((SpreadingFactor::_11, Bandwidth::_125KHz)));
+        assert_eq!(backoff.next_attempt(start, 100_000), start + Duration::from_secs(120));
+    }
+}
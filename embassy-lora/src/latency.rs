@@ -0,0 +1,78 @@
+//! Worst-case latency audit of time-critical driver sections.
+//!
+//! Receive windows at high spreading factors leave little slack, and a heavily loaded executor can delay the
+//! driver enough to miss them. With the `latency-audit` feature, the interface variants record the worst
+//! latencies observed at runtime:
+//!
+//! - from the radio interrupt to the driver task resuming to read out the radio, measured on stm32wl where the
+//!   interrupt handler is part of this crate,
+//! - from a command being issued to the radio releasing BUSY.
+//!
+//! The worst cases are shared by every radio of the application, and can be read with [`report`] and cleared
+//! with [`reset`].
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use embassy_time::{Duration, Instant};
+
+#[cfg(feature = "stm32wl")]
+static IRQ_RAISED: AtomicU32 = AtomicU32::new(0);
+static MAX_IRQ_LATENCY: AtomicU32 = AtomicU32::new(0);
+static MAX_BUSY_WAIT: AtomicU32 = AtomicU32::new(0);
+
+/// Worst-case latencies observed since the last [`reset`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Report {
+    /// Time from the radio interrupt to the driver task resuming.
+    pub irq_latency: Duration,
+    /// Time waited for the radio to release BUSY after a command.
+    pub busy_wait: Duration,
+}
+
+/// Read the worst-case latencies.
+pub fn report() -> Report {
+    Report {
+        irq_latency: Duration::from_ticks(MAX_IRQ_LATENCY.load(Ordering::Relaxed) as u64),
+        busy_wait: Duration::from_ticks(MAX_BUSY_WAIT.load(Ordering::Relaxed) as u64),
+    }
+}
+
+/// Clear the worst-case latencies, e.g. after the application started up.
+pub fn reset() {
+    MAX_IRQ_LATENCY.store(0, Ordering::Relaxed);
+    MAX_BUSY_WAIT.store(0, Ordering::Relaxed);
+}
+
+/// Current time in ticks, truncated to 32 bits since only short intervals are measured.
+pub(crate) fn now() -> u32 {
+    Instant::now().as_ticks() as u32
+}
+
+/// Record the time the radio interrupt was raised, from the interrupt handler.
+#[cfg(feature = "stm32wl")]
+pub(crate) fn irq_raised() {
+    IRQ_RAISED.store(now(), Ordering::Relaxed);
+}
+
+/// Record the driver task resuming after the radio interrupt.
+#[cfg(feature = "stm32wl")]
+pub(crate) fn irq_handled() {
+    record(&MAX_IRQ_LATENCY, IRQ_RAISED.load(Ordering::Relaxed), "IRQ latency");
+}
+
+/// Record the radio releasing BUSY, for a wait started at `start`.
+pub(crate) fn busy_released(start: u32) {
+    record(&MAX_BUSY_WAIT, start, "BUSY wait");
+}
+
+fn record(max: &AtomicU32, start: u32, _section: &str) {
+    let ticks = now().wrapping_sub(start);
+    if max.fetch_max(ticks, Ordering::Relaxed) < ticks {
+        debug!(
+            "new worst-case {}: {} us",
+            _section,
+            Duration::from_ticks(ticks as u64).as_micros()
+        );
+    }
+}
@@ -1,12 +1,94 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 #![feature(async_fn_in_trait)]
+#![feature(error_in_core)]
 //! embassy-lora holds LoRa-specific functionality.
 
 pub(crate) mod fmt;
 
+/// adaptive data rate
+pub mod adr;
+
+/// time-on-air calculations
+pub mod airtime;
+
+/// LoRaWAN Class B beacon tracking and ping slots
+#[cfg(feature = "class-b")]
+pub mod class_b;
+
+/// LoRaWAN application layer clock synchronization
+#[cfg(feature = "time")]
+pub mod clock_sync;
+
+/// application-level framing of point-to-point datagrams
+pub mod datagram;
+
+/// deduplication of downlinks received through several gateways
+pub mod dedup;
+
+/// duty cycle enforcement
+#[cfg(feature = "time")]
+pub mod duty_cycle;
+
+/// receiver of the LoRaWAN fragmented data block transport
+pub mod fragmentation;
+
 /// interface variants required by the external lora physical layer crate (lora-phy)
 pub mod iv;
 
+/// join request retransmission backoff
+#[cfg(feature = "time")]
+pub mod join;
+
+/// worst-case latency audit of time-critical driver sections
+#[cfg(feature = "latency-audit")]
+pub mod latency;
+
+/// conversions between lorawan-device and lora-phy types
+#[cfg(feature = "time")]
+pub mod lorawan;
+
+/// point-to-point links without a LoRaWAN MAC
+pub mod p2p;
+
+/// traffic-driven power management of point-to-point radios
+#[cfg(feature = "time")]
+pub mod power;
+
+/// persistence of the LoRaWAN session in flash
+#[cfg(feature = "persistence")]
+pub mod persistence;
+
+/// reference point-to-point messaging protocol
+#[cfg(feature = "protocol")]
+pub mod protocol;
+
+/// store-and-forward queue for outbound messages
+pub mod queue;
+
+/// LoRaWAN regional parameters selected at runtime
+pub mod region;
+
+/// regulatory limits of sub-GHz ISM bands
+pub mod regulatory;
+
+/// airtime-fair sharing of a radio between several clients
+pub mod scheduler;
+
+/// driver for the ST S2-LP sub-GHz transceiver
+pub mod s2lp;
+
+/// time synchronization over point-to-point links
+#[cfg(feature = "time")]
+pub mod timesync;
+
+/// bridge between point-to-point links and embassy-net UDP sockets
+#[cfg(feature = "net")]
+pub mod udp_bridge;
+
+/// driver for the Microchip RN2483/RN2903 LoRaWAN modems
+#[cfg(feature = "rn2xx3")]
+pub mod rn2xx3;
+
 #[cfg(feature = "time")]
 use embassy_time::{Duration, Instant, Timer};
 
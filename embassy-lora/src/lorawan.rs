@@ -0,0 +1,805 @@
+//! Glue between lorawan-device and lora-phy.
+//!
+//! lorawan-device and lora-phy each define their own modulation types. The conversions in this module match
+//! every variant explicitly, so that a variant added on either side breaks the build instead of being silently
+//! mapped to a wrong value.
+//!
+//! [`LorawanRadio`] drives a lora-phy radio on behalf of the lorawan-device MAC, and keeps Class C devices listening
+//! on RX2 between uplinks.
+
+use core::convert::Infallible;
+
+use embassy_time::{Duration, Instant, Timer};
+use embedded_hal_async::delay::DelayUs;
+use futures::future::{select, Either};
+use futures::pin_mut;
+use lora_phy::mod_params::{
+    Bandwidth, CodingRate, ModulationParams, PacketParams, PacketStatus, RadioError, SpreadingFactor,
+};
+use lora_phy::mod_traits::RadioKind;
+use lora_phy::LoRa;
+use lorawan_device::async_device::radio::{self, PhyRxTx, RfConfig, RxQuality, TxConfig};
+use lorawan_device::Timings;
+
+use crate::adr::{self, Adr, LinkStats};
+use crate::airtime;
+use crate::duty_cycle::{DutyCycle, DutyCyclePolicy};
+use crate::join::JoinBackoff;
+use crate::p2p::{self, CarrierSense, ListenBeforeTalk, NoCarrierSense};
+
+const PREAMBLE_LENGTH: u16 = 8;
+const BEACON_PREAMBLE_LENGTH: u16 = 10;
+const RECEIVE_DELAY1_MS: u32 = 1000;
+const JOIN_ACCEPT_DELAY1_MS: u32 = 5000;
+const DEFAULT_RX_WINDOW_OFFSET_MS: i32 = -50;
+const DEFAULT_RX_WINDOW_DURATION_MS: u32 = 1050;
+/// Time left to switch a Class C radio back to RX2 before the MAC closes RX1.
+const CLASS_C_RX1_MARGIN_MS: u32 = 20;
+
+/// Errors reported by [`LorawanRadio`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The radio reported an error.
+    Radio(RadioError),
+    /// The duty cycle of the sub-band is exhausted, transmissions are allowed again from the given time.
+    DutyCycle(Instant),
+    /// Listen-before-talk found the channel busy, the uplink was not sent.
+    ChannelBusy,
+    /// Listen-before-talk asks for carrier sense, but the radio has no [`CarrierSense`] implementation.
+    CarrierSenseUnavailable,
+}
+
+impl From<RadioError> for Error {
+    fn from(err: RadioError) -> Self {
+        Error::Radio(err)
+    }
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::Radio(err) => write!(f, "radio error: {:?}", err),
+            Error::DutyCycle(available_at) => write!(
+                f,
+                "duty cycle exhausted, next transmission allowed at {} ms",
+                available_at.as_millis()
+            ),
+            Error::ChannelBusy => write!(f, "channel busy"),
+            Error::CarrierSenseUnavailable => write!(f, "carrier sense unavailable"),
+        }
+    }
+}
+
+impl core::error::Error for Error {}
+
+/// Timing of a receive window that closed without a downlink, see [`LorawanRadio::last_rx_miss`].
+///
+/// A radio entering receive mode late is the most common reason for missing downlinks, especially at high
+/// spreading factors where the preamble is only a few tens of milliseconds longer than the window offset. A
+/// consistently positive `late_ms` points at a busy executor, a slow SPI bus or a long MCU wake up, which the
+/// `latency-audit` feature helps tell apart.
+///
+/// Whether a preamble was detected in the window is not reported: lora-phy 2 neither enables nor exposes the
+/// preamble detection interrupt of the radios.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RxWindowMiss {
+    /// Window that expired, 1 for RX1 and 2 for RX2.
+    pub window: u8,
+    /// Time from the end of the uplink to the RX command being issued to the radio, in milliseconds.
+    pub rx_start_ms: u32,
+    /// Delay between the expected window opening and the RX command, in milliseconds. Negative when the radio
+    /// was early.
+    pub late_ms: i32,
+}
+
+/// Convert a lorawan-device spreading factor to its lora-phy equivalent.
+pub fn spreading_factor(spreading_factor: radio::SpreadingFactor) -> SpreadingFactor {
+    match spreading_factor {
+        radio::SpreadingFactor::_5 => SpreadingFactor::_5,
+        radio::SpreadingFactor::_6 => SpreadingFactor::_6,
+        radio::SpreadingFactor::_7 => SpreadingFactor::_7,
+        radio::SpreadingFactor::_8 => SpreadingFactor::_8,
+        radio::SpreadingFactor::_9 => SpreadingFactor::_9,
+        radio::SpreadingFactor::_10 => SpreadingFactor::_10,
+        radio::SpreadingFactor::_11 => SpreadingFactor::_11,
+        radio::SpreadingFactor::_12 => SpreadingFactor::_12,
+    }
+}
+
+/// Convert a lorawan-device bandwidth to its lora-phy equivalent.
+pub fn bandwidth(bandwidth: radio::Bandwidth) -> Bandwidth {
+    match bandwidth {
+        radio::Bandwidth::_7KHz => Bandwidth::_7KHz,
+        radio::Bandwidth::_10KHz => Bandwidth::_10KHz,
+        radio::Bandwidth::_15KHz => Bandwidth::_15KHz,
+        radio::Bandwidth::_20KHz => Bandwidth::_20KHz,
+        radio::Bandwidth::_31KHz => Bandwidth::_31KHz,
+        radio::Bandwidth::_41KHz => Bandwidth::_41KHz,
+        radio::Bandwidth::_62KHz => Bandwidth::_62KHz,
+        radio::Bandwidth::_125KHz => Bandwidth::_125KHz,
+        radio::Bandwidth::_250KHz => Bandwidth::_250KHz,
+        radio::Bandwidth::_500KHz => Bandwidth::_500KHz,
+    }
+}
+
+/// Convert a lorawan-device coding rate to its lora-phy equivalent.
+pub fn coding_rate(coding_rate: radio::CodingRate) -> CodingRate {
+    match coding_rate {
+        radio::CodingRate::_4_5 => CodingRate::_4_5,
+        radio::CodingRate::_4_6 => CodingRate::_4_6,
+        radio::CodingRate::_4_7 => CodingRate::_4_7,
+        radio::CodingRate::_4_8 => CodingRate::_4_8,
+    }
+}
+
+/// Convert a lora-phy spreading factor to its lorawan-device equivalent.
+fn device_spreading_factor(spreading_factor: SpreadingFactor) -> radio::SpreadingFactor {
+    match spreading_factor {
+        SpreadingFactor::_5 => radio::SpreadingFactor::_5,
+        SpreadingFactor::_6 => radio::SpreadingFactor::_6,
+        SpreadingFactor::_7 => radio::SpreadingFactor::_7,
+        SpreadingFactor::_8 => radio::SpreadingFactor::_8,
+        SpreadingFactor::_9 => radio::SpreadingFactor::_9,
+        SpreadingFactor::_10 => radio::SpreadingFactor::_10,
+        SpreadingFactor::_11 => radio::SpreadingFactor::_11,
+        SpreadingFactor::_12 => radio::SpreadingFactor::_12,
+    }
+}
+
+/// Move `spreading_factor` by `shift` steps, within the spreading factors supported by the radio.
+fn shifted_spreading_factor(spreading_factor: radio::SpreadingFactor, shift: i8) -> radio::SpreadingFactor {
+    const ORDER: [SpreadingFactor; 8] = [
+        SpreadingFactor::_5,
+        SpreadingFactor::_6,
+        SpreadingFactor::_7,
+        SpreadingFactor::_8,
+        SpreadingFactor::_9,
+        SpreadingFactor::_10,
+        SpreadingFactor::_11,
+        SpreadingFactor::_12,
+    ];
+    let index = unwrap!(ORDER
+        .iter()
+        .position(|sf| *sf == self::spreading_factor(spreading_factor)));
+    device_spreading_factor(ORDER[(index as i8 + shift).clamp(0, ORDER.len() as i8 - 1) as usize])
+}
+
+/// Create the lora-phy modulation parameters matching a lorawan-device RF configuration.
+pub fn modulation_params<RK, DLY>(
+    lora: &mut LoRa<RK, DLY>,
+    config: &radio::RfConfig,
+) -> Result<ModulationParams, RadioError>
+where
+    RK: RadioKind,
+    DLY: DelayUs,
+{
+    lora.create_modulation_params(
+        spreading_factor(config.bb.sf),
+        bandwidth(config.bb.bw),
+        coding_rate(config.bb.cr),
+        config.frequency,
+    )
+}
+
+/// Receive windows opened after an uplink.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RxWindows {
+    /// Open RX1, then RX2 if nothing was received in RX1.
+    Both,
+    /// Only open RX1.
+    Rx1,
+    /// Only open RX2.
+    Rx2,
+    /// Open neither window, for uplinks after which no downlink is expected.
+    None,
+}
+
+impl RxWindows {
+    fn opens(self, window: u8) -> bool {
+        matches!(
+            (self, window),
+            (RxWindows::Both, _) | (RxWindows::Rx1, 0) | (RxWindows::Rx2, 1)
+        )
+    }
+}
+
+/// A lora-phy radio driven by the lorawan-device MAC.
+pub struct LorawanRadio<RK, DLY, CS = NoCarrierSense>
+where
+    RK: RadioKind,
+    DLY: DelayUs,
+    CS: CarrierSense,
+{
+    lora: LoRa<RK, DLY>,
+    carrier_sense: CS,
+    lbt: Option<ListenBeforeTalk>,
+    rx_window_policy: Option<fn(&[u8]) -> RxWindows>,
+    rx_windows: RxWindows,
+    rx_window: u8,
+    rx1_delay_ms: u32,
+    rx_delay_ms: u32,
+    tx_end: Instant,
+    last_rx_miss: Option<RxWindowMiss>,
+    interrupted: bool,
+    rx_window_offset_ms: i32,
+    rx_window_duration_ms: u32,
+    link_stats: LinkStats,
+    adr: Option<Adr>,
+    duty_cycle: Option<(DutyCycle, DutyCyclePolicy)>,
+    join_backoff: Option<JoinBackoff>,
+    rx1_sf_shift: i8,
+    class_c: Option<RfConfig>,
+    rx2_armed: Option<PacketParams>,
+}
+
+impl<RK, DLY> LorawanRadio<RK, DLY>
+where
+    RK: RadioKind,
+    DLY: DelayUs,
+{
+    /// Create a LoRaWAN radio from a lora-phy radio set up for a public or private network.
+    pub fn new(lora: LoRa<RK, DLY>) -> Self {
+        Self {
+            lora,
+            carrier_sense: NoCarrierSense,
+            lbt: None,
+            rx_window_policy: None,
+            rx_windows: RxWindows::Both,
+            rx_window: 0,
+            rx1_delay_ms: RECEIVE_DELAY1_MS,
+            rx_delay_ms: RECEIVE_DELAY1_MS,
+            tx_end: Instant::now(),
+            last_rx_miss: None,
+            interrupted: false,
+            rx_window_offset_ms: DEFAULT_RX_WINDOW_OFFSET_MS,
+            rx_window_duration_ms: DEFAULT_RX_WINDOW_DURATION_MS,
+            link_stats: LinkStats::new(),
+            adr: None,
+            duty_cycle: None,
+            join_backoff: None,
+            rx1_sf_shift: 0,
+            class_c: None,
+            rx2_armed: None,
+        }
+    }
+}
+
+impl<RK, DLY, CS> LorawanRadio<RK, DLY, CS>
+where
+    RK: RadioKind,
+    DLY: DelayUs,
+    CS: CarrierSense,
+{
+    /// Read the RSSI with `carrier_sense`, for the carrier sense of listen-before-talk.
+    pub fn with_carrier_sense<C: CarrierSense>(self, carrier_sense: C) -> LorawanRadio<RK, DLY, C> {
+        LorawanRadio {
+            lora: self.lora,
+            carrier_sense,
+            lbt: self.lbt,
+            rx_window_policy: self.rx_window_policy,
+            rx_windows: self.rx_windows,
+            rx_window: self.rx_window,
+            rx1_delay_ms: self.rx1_delay_ms,
+            rx_delay_ms: self.rx_delay_ms,
+            tx_end: self.tx_end,
+            last_rx_miss: self.last_rx_miss,
+            interrupted: self.interrupted,
+            rx_window_offset_ms: self.rx_window_offset_ms,
+            rx_window_duration_ms: self.rx_window_duration_ms,
+            link_stats: self.link_stats,
+            adr: self.adr,
+            duty_cycle: self.duty_cycle,
+            join_backoff: self.join_backoff,
+            rx1_sf_shift: self.rx1_sf_shift,
+            class_c: self.class_c,
+            rx2_armed: self.rx2_armed,
+        }
+    }
+
+    /// Assess the channel before every uplink, or transmit unconditionally with `None`.
+    ///
+    /// Uplinks on a busy channel are rejected with [`Error::ChannelBusy`], which the MAC hands back to the
+    /// application to retry later. Carrier sense is rejected with [`Error::CarrierSenseUnavailable`] if the radio
+    /// has no [`CarrierSense`] implementation, see [`with_carrier_sense`](Self::with_carrier_sense).
+    pub fn set_listen_before_talk(&mut self, lbt: Option<ListenBeforeTalk>) -> Result<(), Error> {
+        p2p::check_carrier_sense::<CS>(lbt.as_ref()).map_err(|_| Error::CarrierSenseUnavailable)?;
+        self.lbt = lbt;
+        Ok(())
+    }
+
+    /// Install a function choosing the receive windows to open after each uplink.
+    ///
+    /// The function is called with the PHY payload of the uplink. Skipping the windows of uplinks that cannot
+    /// trigger a downlink keeps the radio asleep instead of listening, which matters for devices that rarely send
+    /// confirmed uplinks. The policy is not consulted for join requests, which always open both windows.
+    pub fn set_rx_window_policy(&mut self, policy: Option<fn(&[u8]) -> RxWindows>) {
+        self.rx_window_policy = policy;
+    }
+
+    /// Set the delay from the end of an uplink to RX1, as configured by the network, which is used to diagnose
+    /// missed receive windows.
+    pub fn set_rx1_delay_ms(&mut self, delay_ms: u32) {
+        self.rx1_delay_ms = delay_ms;
+    }
+
+    /// Set when the receive windows open relative to their nominal start, and how long they stay open.
+    ///
+    /// Opening early, with a negative offset, and listening longer compensate for the clock error of the device
+    /// and for the time taken to wake up the MCU and configure the radio over SPI. The defaults, opening 50 ms
+    /// early for 1050 ms, suit most boards; devices with an accurate clock and a fast SPI bus can shorten them
+    /// to save power, while devices with a slow wake up or an RC oscillator need wider windows.
+    pub fn set_rx_window_timings(&mut self, offset_ms: i32, duration_ms: u32) {
+        self.rx_window_offset_ms = offset_ms;
+        self.rx_window_duration_ms = duration_ms;
+    }
+
+    /// Enforce the duty cycle limits of a region on the uplinks, or stop enforcing them with `None`.
+    ///
+    /// Uplinks on a sub-band whose duty cycle is exhausted are delayed or rejected with [`Error::DutyCycle`],
+    /// according to `policy`.
+    pub fn set_duty_cycle(&mut self, duty_cycle: Option<DutyCycle>, policy: DutyCyclePolicy) {
+        self.duty_cycle = duty_cycle.map(|duty_cycle| (duty_cycle, policy));
+    }
+
+    /// The duty cycle accounting of the uplinks, to query when the next uplink is allowed.
+    pub fn duty_cycle(&self) -> Option<&DutyCycle> {
+        self.duty_cycle.as_ref().map(|(duty_cycle, _)| duty_cycle)
+    }
+
+    /// Hold join requests back according to a join backoff and send them at its decayed data rate, or send them
+    /// right away as configured by the MAC with `None`.
+    ///
+    /// Remove the backoff once the device has joined.
+    pub fn set_join_backoff(&mut self, join_backoff: Option<JoinBackoff>) {
+        self.join_backoff = join_backoff;
+    }
+
+    /// Send uplinks at the data rate and output power of `adr`, or at the ones picked by the MAC with `None`.
+    ///
+    /// The `LinkADRReq` commands of the downlinks are applied to `adr`, and RX1 follows its data rate.
+    pub fn set_adr(&mut self, adr: Option<Adr>) {
+        self.adr = adr;
+    }
+
+    /// The ADR settings the uplinks are sent with.
+    pub fn adr(&self) -> Option<&Adr> {
+        self.adr.as_ref()
+    }
+
+    /// Change the ADR settings, for instance to apply [`Adr::set`] in [`AdrMode::Off`](crate::adr::AdrMode::Off).
+    pub fn adr_mut(&mut self) -> Option<&mut Adr> {
+        self.adr.as_mut()
+    }
+
+    /// Operate as a Class C device listening on `rx2` between uplinks, or as a Class A device with `None`.
+    ///
+    /// The radio switches to continuous reception on RX2 as soon as an uplink ends and again before RX1 closes,
+    /// and is stopped before the next uplink. Update the RX2 settings whenever the network changes them.
+    pub fn set_class_c(&mut self, rx2: Option<RfConfig>) {
+        self.class_c = rx2;
+        if rx2.is_none() {
+            self.rx2_armed = None;
+        }
+    }
+
+    /// The join backoff, to query when the next join attempt is allowed.
+    pub fn join_backoff(&self) -> Option<&JoinBackoff> {
+        self.join_backoff.as_ref()
+    }
+
+    /// Timing of the last receive window opened, if it closed without a downlink.
+    ///
+    /// The MAC closes receive windows by dropping the receive operation, so this is recorded when the radio
+    /// starts listening and cleared when a downlink is received.
+    pub fn last_rx_miss(&self) -> Option<&RxWindowMiss> {
+        self.last_rx_miss.as_ref()
+    }
+
+    /// Statistics of the link, which drive [`Adr`].
+    pub fn link_stats(&self) -> &LinkStats {
+        &self.link_stats
+    }
+
+    /// Access the underlying lora-phy radio.
+    pub fn lora(&mut self) -> &mut LoRa<RK, DLY> {
+        &mut self.lora
+    }
+
+    /// Put the radio to sleep if a transmission or a receive window was cancelled.
+    ///
+    /// The MAC closes receive windows by dropping the receive operation, which leaves the radio listening until
+    /// the next uplink. Calling this once the MAC returns stops it right away, including the continuous RX2
+    /// reception of Class C devices.
+    pub async fn recover(&mut self) -> Result<(), RadioError> {
+        if self.interrupted {
+            self.lora.sleep(false).await?;
+            self.interrupted = false;
+        }
+        self.rx2_armed = None;
+        Ok(())
+    }
+
+    /// Wait for a downlink received on RX2 outside of the receive windows, for Class C operation.
+    ///
+    /// Requires [`set_class_c`](Self::set_class_c). The radio keeps listening between uplinks whether this future
+    /// is polled or not, so race it against the next uplink, e.g. with `select`: dropping it is safe. `buf` should
+    /// hold 255 bytes, the largest PHY payload.
+    ///
+    /// lorawan-device 0.11 only takes downlinks within its receive windows, so the PHY payload is returned as
+    /// is, for the application to authenticate and decrypt.
+    pub async fn receive_continuous(&mut self, buf: &mut [u8]) -> Result<(usize, RxQuality), Error> {
+        let rx2 = self.class_c.ok_or(RadioError::InvalidConfiguration)?;
+        let rx_pkt_params = match self.rx2_armed {
+            Some(rx_pkt_params) => rx_pkt_params,
+            None => {
+                self.recover().await?;
+                self.arm_rx2(rx2).await?
+            }
+        };
+        let result = self.lora.rx(&rx_pkt_params, buf).await;
+        if result.is_err() {
+            // Listen again from scratch on the next call
+            self.rx2_armed = None;
+        }
+        let (len, status) = result?;
+        Ok((len as usize, self.on_downlink(&rx2, status, &buf[..len as usize])))
+    }
+
+    /// Receive a Class B beacon, listening for up to `window_in_secs` or until one is received if `None`.
+    ///
+    /// Beacons are sent with an implicit header, so `buf` must be exactly the size of the beacons of the region.
+    pub async fn receive_beacon(
+        &mut self,
+        config: RfConfig,
+        window_in_secs: Option<u8>,
+        buf: &mut [u8],
+    ) -> Result<(usize, RxQuality), Error> {
+        self.recover().await?;
+        let (rx_pkt_params, _) = self.start_rx(&config, window_in_secs, buf.len(), true).await?;
+        let result = self.lora.rx(&rx_pkt_params, buf).await;
+        self.interrupted = false;
+        let (len, status) = result?;
+        Ok((len as usize, RxQuality::new(status.rssi, status.snr as i8)))
+    }
+
+    /// Listen for a Class B downlink in a ping slot, which should be called when the slot opens.
+    ///
+    /// lora-phy 2 cannot stop receiving when no preamble is detected within a few symbols, so the slot is first
+    /// checked with channel activity detection, which only lasts a couple of symbols. The receiver is only started
+    /// if a preamble is on air, and returns `None` if the slot is empty or no downlink follows.
+    pub async fn receive_ping_slot(
+        &mut self,
+        config: RfConfig,
+        buf: &mut [u8],
+    ) -> Result<Option<(usize, RxQuality)>, Error> {
+        self.recover().await?;
+        let mdltn_params = modulation_params(&mut self.lora, &config)?;
+        // lora-phy only programs the packet parameters, and so the IQ inversion of downlinks, when preparing a
+        // reception, and the radio loses them in cold sleep
+        let max_len = buf.len().min(u8::MAX as usize) as u8;
+        let rx_pkt_params =
+            self.lora
+                .create_rx_packet_params(PREAMBLE_LENGTH, false, max_len, true, true, &mdltn_params)?;
+        self.interrupted = true;
+        self.lora
+            .prepare_for_rx(&mdltn_params, &rx_pkt_params, None, None, true)
+            .await?;
+        self.lora.prepare_for_cad(&mdltn_params, true).await?;
+        let activity = self.lora.cad().await;
+        self.interrupted = false;
+        if !activity? {
+            return Ok(None);
+        }
+
+        let (rx_pkt_params, _) = self.start_rx(&config, Some(1), buf.len(), false).await?;
+        let result = self.lora.rx(&rx_pkt_params, buf).await;
+        self.interrupted = false;
+        match result {
+            Ok((len, status)) => Ok(Some((
+                len as usize,
+                self.on_downlink(&config, status, &buf[..len as usize]),
+            ))),
+            Err(RadioError::ReceiveTimeout) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Configure the radio to receive downlinks or beacons, and start listening for up to `window_in_secs`.
+    ///
+    /// Returns the packet parameters and the time at which the RX command was issued.
+    async fn start_rx(
+        &mut self,
+        config: &RfConfig,
+        window_in_secs: Option<u8>,
+        buf_len: usize,
+        beacon: bool,
+    ) -> Result<(PacketParams, Instant), RadioError> {
+        let mdltn_params = modulation_params(&mut self.lora, config)?;
+        let max_len = buf_len.min(u8::MAX as usize) as u8;
+        let rx_pkt_params = if beacon {
+            // Beacons have a fixed length, no payload CRC and are not IQ inverted
+            self.lora
+                .create_rx_packet_params(BEACON_PREAMBLE_LENGTH, true, max_len, false, false, &mdltn_params)?
+        } else {
+            self.lora
+                .create_rx_packet_params(PREAMBLE_LENGTH, false, max_len, true, true, &mdltn_params)?
+        };
+        self.interrupted = true;
+        // The RX command is the last one issued by prepare_for_rx
+        self.lora
+            .prepare_for_rx(&mdltn_params, &rx_pkt_params, window_in_secs, None, true)
+            .await?;
+        Ok((rx_pkt_params, Instant::now()))
+    }
+
+    /// Start listening continuously on the Class C RX2 channel, until the next uplink.
+    async fn arm_rx2(&mut self, rx2: RfConfig) -> Result<PacketParams, RadioError> {
+        let (rx_pkt_params, _) = self.start_rx(&rx2, None, u8::MAX as usize, false).await?;
+        self.rx2_armed = Some(rx_pkt_params);
+        Ok(rx_pkt_params)
+    }
+
+    /// Leave the radio idle until the MAC closes the current receive window: listening on RX2 for Class C devices,
+    /// asleep otherwise.
+    async fn idle(&mut self) -> Result<Infallible, RadioError> {
+        match self.class_c {
+            Some(rx2) => {
+                self.arm_rx2(rx2).await?;
+            }
+            None => {
+                self.lora.sleep(false).await?;
+                self.interrupted = false;
+            }
+        }
+        core::future::pending().await
+    }
+
+    fn on_downlink(&mut self, config: &RfConfig, status: PacketStatus, payload: &[u8]) -> RxQuality {
+        let quality = RxQuality::new(status.rssi, status.snr as i8);
+        self.link_stats
+            .on_downlink(quality.snr(), spreading_factor(config.bb.sf));
+        if let Some(adr) = &mut self.adr {
+            for req in adr::link_adr_reqs(payload) {
+                adr.apply(&req);
+            }
+        }
+        quality
+    }
+
+    /// Release the underlying lora-phy radio.
+    pub fn release(self) -> LoRa<RK, DLY> {
+        self.lora
+    }
+}
+
+impl<RK, DLY, CS> PhyRxTx for LorawanRadio<RK, DLY, CS>
+where
+    RK: RadioKind,
+    DLY: DelayUs,
+    CS: CarrierSense,
+{
+    type PhyError = Error;
+
+    async fn tx(&mut self, config: TxConfig, buf: &[u8]) -> Result<u32, Self::PhyError> {
+        // Join requests, with a zero message type, are answered after the join accept delay in either window
+        let join_request = matches!(buf.first(), Some(mhdr) if mhdr >> 5 == 0);
+        self.rx_windows = match self.rx_window_policy {
+            Some(policy) if !join_request => policy(buf),
+            _ => RxWindows::Both,
+        };
+        self.rx_window = 0;
+        self.last_rx_miss = None;
+        self.rx_delay_ms = if join_request {
+            JOIN_ACCEPT_DELAY1_MS
+        } else {
+            self.rx1_delay_ms
+        };
+        self.recover().await?;
+
+        // Join requests go out at the decayed data rate of the backoff and other uplinks at the data rate of ADR, as
+        // long as it fits the channel picked by the MAC
+        let mut config = config;
+        let modulation = match (join_request, &self.join_backoff, &mut self.adr) {
+            (true, Some(join_backoff), _) => join_backoff.modulation(),
+            (false, _, Some(adr)) => {
+                // The MAC builds the frame, so the ADRACKReq bit is left to it
+                adr.on_uplink(&self.link_stats);
+                config.pw = adr.tx_power_dbm() as i8;
+                adr.modulation()
+            }
+            _ => None,
+        };
+        self.rx1_sf_shift = 0;
+        if let Some((sf, bw)) = modulation {
+            if bw == bandwidth(config.rf.bb.bw) {
+                let shift = sf as i8 - spreading_factor(config.rf.bb.sf) as i8;
+                config.rf.bb.sf = device_spreading_factor(sf);
+                self.rx1_sf_shift = shift;
+            }
+        }
+
+        let bandwidth_in_hz = airtime::bandwidth_in_hz(bandwidth(config.rf.bb.bw));
+        let time_on_air_us = airtime::time_on_air_us(
+            spreading_factor(config.rf.bb.sf),
+            bandwidth(config.rf.bb.bw),
+            coding_rate(config.rf.bb.cr),
+            PREAMBLE_LENGTH,
+            false,
+            true,
+            buf.len(),
+        );
+        if let (true, Some(join_backoff)) = (join_request, &self.join_backoff) {
+            join_backoff.wait(time_on_air_us).await;
+        }
+        if let Some((duty_cycle, policy)) = &self.duty_cycle {
+            if !duty_cycle.is_allowed(config.rf.frequency, bandwidth_in_hz, Instant::now()) {
+                match policy {
+                    DutyCyclePolicy::Delay => duty_cycle.wait(config.rf.frequency, bandwidth_in_hz).await,
+                    DutyCyclePolicy::Reject => {
+                        let available_at = unwrap!(duty_cycle.next_allowed(config.rf.frequency, bandwidth_in_hz));
+                        return Err(Error::DutyCycle(available_at));
+                    }
+                }
+            }
+        }
+
+        let mdltn_params = modulation_params(&mut self.lora, &config.rf)?;
+        if let Some(lbt) = self.lbt {
+            let rx_pkt_params =
+                self.lora
+                    .create_rx_packet_params(PREAMBLE_LENGTH, false, u8::MAX, true, true, &mdltn_params)?;
+            self.interrupted = true;
+            let busy = p2p::channel_busy(
+                &mut self.lora,
+                &mut self.carrier_sense,
+                &lbt,
+                &mdltn_params,
+                &rx_pkt_params,
+                true,
+            )
+            .await?;
+            self.interrupted = false;
+            if busy {
+                debug!("channel busy, uplink not sent");
+                return Err(Error::ChannelBusy);
+            }
+        }
+        let mut tx_pkt_params =
+            self.lora
+                .create_tx_packet_params(PREAMBLE_LENGTH, false, true, false, &mdltn_params)?;
+        self.interrupted = true;
+        self.lora.prepare_for_tx(&mdltn_params, config.pw as i32, false).await?;
+        let result = self.lora.tx(&mdltn_params, &mut tx_pkt_params, buf, 0xffffff).await;
+        self.interrupted = false;
+        result?;
+        self.tx_end = Instant::now();
+        self.link_stats.on_uplink();
+        if let (true, Some(join_backoff)) = (join_request, &mut self.join_backoff) {
+            join_backoff.on_attempt(self.tx_end, time_on_air_us);
+        }
+        if let Some((duty_cycle, _)) = &mut self.duty_cycle {
+            duty_cycle.record(config.rf.frequency, bandwidth_in_hz, time_on_air_us, self.tx_end);
+        }
+        // Class C devices listen on RX2 from the end of the uplink until RX1 opens
+        if let Some(rx2) = self.class_c {
+            self.arm_rx2(rx2).await?;
+        }
+        Ok(0)
+    }
+
+    async fn rx(&mut self, mut config: RfConfig, buf: &mut [u8]) -> Result<(usize, RxQuality), Self::PhyError> {
+        let window = self.rx_window;
+        // RX1 follows the data rate the uplink was sent at, with the data rate offset of the MAC
+        if window == 0 && self.rx1_sf_shift != 0 {
+            config.bb.sf = shifted_spreading_factor(config.bb.sf, self.rx1_sf_shift);
+        }
+        self.rx_window = self.rx_window.saturating_add(1);
+        // RX2 of Class C devices is already listening, since the end of the uplink or of RX1
+        if let (1, Some(rx2), Some(rx_pkt_params)) = (window, self.class_c, self.rx2_armed) {
+            if same_channel(&rx2, &config) {
+                let (len, status) = self.lora.rx(&rx_pkt_params, buf).await?;
+                self.last_rx_miss = None;
+                return Ok((len as usize, self.on_downlink(&config, status, &buf[..len as usize])));
+            }
+        }
+        self.recover().await?;
+        if !self.rx_windows.opens(window) {
+            // Let the MAC time out the window while the radio sleeps or listens on RX2
+            match self.idle().await? {}
+        }
+
+        // The MAC closes the window by dropping this future, the radio timeout only fires when it is late
+        let window_in_secs = self.get_rx_window_duration_ms().div_ceil(1000).clamp(1, u8::MAX as u32) as u8;
+        let (rx_pkt_params, rx_started) = self.start_rx(&config, Some(window_in_secs), buf.len(), false).await?;
+        let rx_start_ms = (rx_started - self.tx_end).as_millis() as u32;
+        let expected_ms = (self.rx_delay_ms + window as u32 * 1000) as i32 + self.get_rx_window_offset_ms();
+        // Counted as missed until a downlink is received, since the window usually closes by being dropped
+        self.last_rx_miss = Some(RxWindowMiss {
+            window: window + 1,
+            rx_start_ms,
+            late_ms: rx_start_ms as i32 - expected_ms,
+        });
+        let result = {
+            let rx = self.lora.rx(&rx_pkt_params, buf);
+            if window == 0 && self.class_c.is_some() {
+                // Switch back to RX2 before the MAC drops RX1, so that no downlink is missed in between
+                let rx1_end = rx_started
+                    + Duration::from_millis(self.rx_window_duration_ms.saturating_sub(CLASS_C_RX1_MARGIN_MS) as u64);
+                let timeout = Timer::at(rx1_end);
+                pin_mut!(rx);
+                match select(rx, timeout).await {
+                    Either::Left((result, _)) => result,
+                    Either::Right(_) => Err(RadioError::ReceiveTimeout),
+                }
+            } else {
+                rx.await
+            }
+        };
+        self.interrupted = false;
+        match result {
+            Ok((len, status)) => {
+                self.last_rx_miss = None;
+                Ok((len as usize, self.on_downlink(&config, status, &buf[..len as usize])))
+            }
+            Err(RadioError::ReceiveTimeout) => {
+                debug!("receive window closed: {:?}", self.last_rx_miss);
+                // Let the MAC close the window and move on to the next one
+                match self.idle().await? {}
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// Whether two RF configurations listen on the same channel with the same modulation.
+fn same_channel(a: &RfConfig, b: &RfConfig) -> bool {
+    a.frequency == b.frequency && a.bb.sf == b.bb.sf && a.bb.bw == b.bb.bw && a.bb.cr == b.bb.cr
+}
+
+impl<RK, DLY, CS> Timings for LorawanRadio<RK, DLY, CS>
+where
+    RK: RadioKind,
+    DLY: DelayUs,
+    CS: CarrierSense,
+{
+    fn get_rx_window_offset_ms(&self) -> i32 {
+        self.rx_window_offset_ms
+    }
+
+    fn get_rx_window_duration_ms(&self) -> u32 {
+        self.rx_window_duration_ms
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::airtime::{bandwidth_in_hz, symbol_time_us};
+
+    #[test]
+    fn conversions_preserve_values() {
+        assert_eq!(bandwidth_in_hz(bandwidth(radio::Bandwidth::_7KHz)), 7_810);
+        assert_eq!(bandwidth_in_hz(bandwidth(radio::Bandwidth::_62KHz)), 62_500);
+        assert_eq!(bandwidth_in_hz(bandwidth(radio::Bandwidth::_500KHz)), 500_000);
+        assert_eq!(
+            symbol_time_us(spreading_factor(radio::SpreadingFactor::_12), Bandwidth::_125KHz),
+            32_768
+        );
+        assert_eq!(
+            symbol_time_us(spreading_factor(radio::SpreadingFactor::_5), Bandwidth::_125KHz),
+            256
+        );
+        assert_eq!(coding_rate(radio::CodingRate::_4_8), CodingRate::_4_8);
+    }
+
+    #[test]
+    fn rx_windows() {
+        assert!(RxWindows::Both.opens(0) && RxWindows::Both.opens(1));
+        assert!(RxWindows::Rx1.opens(0) && !RxWindows::Rx1.opens(1));
+        assert!(!RxWindows::Rx2.opens(0) && RxWindows::Rx2.opens(1));
+        assert!(!RxWindows::None.opens(0) && !RxWindows::None.opens(1));
+    }
+}
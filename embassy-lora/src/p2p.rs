@@ -0,0 +1,830 @@
+//! Point-to-point LoRa links.
+//!
+//! [`P2pRadio`] wraps a lora-phy [`LoRa`] instance for applications that exchange raw LoRa packets without a
+//! LoRaWAN MAC. A regulatory [`Region`] can be attached to the radio, in which case every operation is checked
+//! against the limits of that region before the radio is touched.
+
+use embedded_hal_async::delay::DelayUs;
+use lora_phy::mod_params::{
+    Bandwidth, BoardType, CodingRate, DutyCycleParams, ModulationParams, PacketParams, PacketStatus, RadioError,
+    SpreadingFactor,
+};
+use lora_phy::mod_traits::RadioKind;
+use lora_phy::LoRa;
+
+use crate::airtime::{bandwidth_in_hz, time_on_air_us};
+use crate::regulatory::{Region, Violation};
+
+const DEFAULT_PREAMBLE_LENGTH: u16 = 8;
+const TX_TIMEOUT_MS: u32 = 0xffffff;
+const CAD_RX_WINDOW_SECS: u8 = 1;
+
+/// Errors reported by point-to-point operations.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The radio reported an error.
+    Radio(RadioError),
+    /// The operation is not allowed in the region attached to the radio.
+    Regulatory(Violation),
+    /// Listen-before-talk detected activity on the channel, the packet was not sent.
+    ChannelBusy,
+    /// No packet was received before the receive timeout, see [`LinkConfig::rx_timeout_secs`].
+    RxTimeout,
+    /// A packet was received with an invalid CRC.
+    Crc,
+    /// Listen-before-talk asks for carrier sense, but the radio has no [`CarrierSense`] implementation.
+    CarrierSenseUnavailable,
+}
+
+impl From<RadioError> for Error {
+    fn from(err: RadioError) -> Self {
+        match err {
+            RadioError::ReceiveTimeout => Error::RxTimeout,
+            RadioError::CRCErrorOnReceive => Error::Crc,
+            err => Error::Radio(err),
+        }
+    }
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::Radio(err) => write!(f, "radio error: {:?}", err),
+            Error::Regulatory(violation) => write!(f, "regulatory violation: {:?}", violation),
+            Error::ChannelBusy => write!(f, "channel busy"),
+            Error::RxTimeout => write!(f, "receive timeout"),
+            Error::Crc => write!(f, "CRC error"),
+            Error::CarrierSenseUnavailable => write!(f, "carrier sense unavailable"),
+        }
+    }
+}
+
+impl core::error::Error for Error {}
+
+impl From<Violation> for Error {
+    fn from(err: Violation) -> Self {
+        Error::Regulatory(err)
+    }
+}
+
+/// Radio settings of a point-to-point link.
+#[derive(Debug, Clone, Copy)]
+pub struct LinkConfig {
+    /// Carrier frequency in Hz.
+    pub frequency_in_hz: u32,
+    /// LoRa spreading factor.
+    pub spreading_factor: SpreadingFactor,
+    /// LoRa bandwidth.
+    pub bandwidth: Bandwidth,
+    /// LoRa coding rate.
+    pub coding_rate: CodingRate,
+    /// Output power in dBm used when transmitting.
+    pub output_power: i32,
+    /// Number of preamble symbols.
+    ///
+    /// The receiver must be configured with a preamble at least as long as the transmitter's. Wake-on-radio
+    /// schemes use long preambles so that receivers sampling the channel periodically do not miss packets.
+    pub preamble_length: u16,
+    /// Payload length of packets sent in implicit header mode, or `None` to send an explicit header.
+    ///
+    /// Without a header, both ends must agree on the payload length beforehand. This saves airtime on links
+    /// exchanging fixed-size packets, such as ranging beacons.
+    pub implicit_header: Option<u8>,
+    /// Append a CRC to the payload and check it on reception.
+    ///
+    /// Packets failing the check are reported as an error by the radio. Protocols carrying their own integrity
+    /// check, see [`datagram`](crate::datagram), may disable it to save two bytes of airtime.
+    pub crc_on: bool,
+    /// Invert the I and Q signals.
+    ///
+    /// LoRaWAN gateways transmit with inverted IQ so that devices do not hear each other's uplinks. Setting
+    /// this allows emulating downlinks, or receiving them from a repeater. Both ends must use the same setting.
+    pub iq_inverted: bool,
+    /// Use the boosted LNA gain when receiving, if the radio supports it.
+    ///
+    /// This improves sensitivity by a few dB, at the cost of about 2 mA of extra current while listening.
+    pub rx_boosted: bool,
+    /// Time after which [`P2pRadio::receive`] gives up with [`Error::RxTimeout`], or `None` to listen until a
+    /// packet is received.
+    ///
+    /// The radio measures the timeout itself, with a resolution of one second. Listening without a timeout
+    /// should be reserved to mains-powered nodes, or combined with a timeout on the caller's side.
+    pub rx_timeout_secs: Option<u8>,
+}
+
+impl LinkConfig {
+    /// Create a link configuration sending packets with an 8 symbol preamble, an explicit header, a CRC,
+    /// normal IQ and the normal receiver gain, and receiving without a timeout.
+    pub fn new(
+        frequency_in_hz: u32,
+        spreading_factor: SpreadingFactor,
+        bandwidth: Bandwidth,
+        coding_rate: CodingRate,
+        output_power: i32,
+    ) -> Self {
+        Self {
+            frequency_in_hz,
+            spreading_factor,
+            bandwidth,
+            coding_rate,
+            output_power,
+            preamble_length: DEFAULT_PREAMBLE_LENGTH,
+            implicit_header: None,
+            crc_on: true,
+            iq_inverted: false,
+            rx_boosted: false,
+            rx_timeout_secs: None,
+        }
+    }
+
+    /// Time on air of a packet carrying `payload_len` bytes, in microseconds.
+    pub fn time_on_air_us(&self, payload_len: usize) -> u32 {
+        time_on_air_us(
+            self.spreading_factor,
+            self.bandwidth,
+            self.coding_rate,
+            self.preamble_length,
+            self.implicit_header.is_some(),
+            self.crc_on,
+            payload_len,
+        )
+    }
+}
+
+/// Listen-before-talk policy applied before every transmission.
+///
+/// The channel is first assessed by carrier sense, sampling the RSSI for `duration_us` and comparing the highest
+/// reading to `rssi_threshold_dbm`, which detects any kind of transmitter. Japan (ARIB STD-T108) and South Korea
+/// require it before every transmission, see [`ListenBeforeTalk::JAPAN`] and [`ListenBeforeTalk::KOREA`]. Channel
+/// activity detection can follow, which detects LoRa preambles well below the noise floor but nothing else.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ListenBeforeTalk {
+    /// RSSI above which the channel is busy, in dBm, or `None` to skip carrier sense.
+    ///
+    /// Carrier sense needs a radio with a [`CarrierSense`] implementation, the radios without one reject it.
+    pub rssi_threshold_dbm: Option<i16>,
+    /// Time the RSSI is sampled for, in microseconds.
+    pub duration_us: u32,
+    /// Number of consecutive channel activity detections that must find the channel idle.
+    ///
+    /// Each detection lasts a couple of symbols.
+    pub cad_count: u8,
+}
+
+impl ListenBeforeTalk {
+    /// Carrier sense required in Japan: -80 dBm for 5 ms.
+    pub const JAPAN: Self = Self {
+        rssi_threshold_dbm: Some(-80),
+        duration_us: 5_000,
+        cad_count: 0,
+    };
+    /// Carrier sense required in South Korea: -65 dBm for 5 ms.
+    pub const KOREA: Self = Self {
+        rssi_threshold_dbm: Some(-65),
+        duration_us: 5_000,
+        cad_count: 0,
+    };
+}
+
+/// Instantaneous RSSI readings, for the carrier sense of [`ListenBeforeTalk`].
+///
+/// lora-phy 2 does not expose the instantaneous RSSI of the radios, so boards provide it, e.g. by issuing the
+/// `GetRssiInst` command of the SX126x. The radio is listening on the channel assessed while this is called.
+pub trait CarrierSense {
+    /// Whether RSSI readings are available, which listen-before-talk with carrier sense requires.
+    const AVAILABLE: bool = true;
+
+    /// Sample the RSSI for `duration_us`, and return the highest reading in dBm.
+    async fn max_rssi(&mut self, duration_us: u32) -> Result<i16, RadioError>;
+}
+
+/// Radios without RSSI readings, which only support channel activity detection.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoCarrierSense;
+
+impl CarrierSense for NoCarrierSense {
+    const AVAILABLE: bool = false;
+
+    async fn max_rssi(&mut self, _duration_us: u32) -> Result<i16, RadioError> {
+        Err(RadioError::InvalidConfiguration)
+    }
+}
+
+/// Check that a radio reading the RSSI with `CS` can run `lbt`.
+pub(crate) fn check_carrier_sense<CS: CarrierSense>(lbt: Option<&ListenBeforeTalk>) -> Result<(), Error> {
+    match lbt {
+        Some(lbt) if lbt.rssi_threshold_dbm.is_some() && !CS::AVAILABLE => Err(Error::CarrierSenseUnavailable),
+        _ => Ok(()),
+    }
+}
+
+/// Assess the channel according to `lbt` on behalf of a radio wrapper, returning true if it is busy.
+///
+/// The radio is left asleep or in standby.
+pub(crate) async fn channel_busy<RK, DLY, CS>(
+    lora: &mut LoRa<RK, DLY>,
+    carrier_sense: &mut CS,
+    lbt: &ListenBeforeTalk,
+    mdltn_params: &ModulationParams,
+    rx_pkt_params: &PacketParams,
+    rx_boosted: bool,
+) -> Result<bool, RadioError>
+where
+    RK: RadioKind,
+    DLY: DelayUs,
+    CS: CarrierSense,
+{
+    if let Some(threshold) = lbt.rssi_threshold_dbm {
+        lora.prepare_for_rx(mdltn_params, rx_pkt_params, None, None, rx_boosted)
+            .await?;
+        let rssi = carrier_sense.max_rssi(lbt.duration_us).await;
+        lora.sleep(false).await?;
+        let rssi = rssi?;
+        if rssi > threshold {
+            debug!("carrier sensed at {} dBm", rssi);
+            return Ok(true);
+        }
+    }
+    for _ in 0..lbt.cad_count {
+        lora.prepare_for_cad(mdltn_params, rx_boosted).await?;
+        if lora.cad().await? {
+            debug!("channel activity detected");
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Description of a completed transmission, passed to the TX hook.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TxRecord {
+    /// Carrier frequency in Hz.
+    pub frequency_in_hz: u32,
+    /// Output power in dBm.
+    pub output_power: i32,
+    /// Time on air in microseconds.
+    pub time_on_air_us: u32,
+    /// Payload length in bytes.
+    pub payload_len: usize,
+    /// Time at which the transmission ended.
+    #[cfg(feature = "time")]
+    pub timestamp: embassy_time::Instant,
+}
+
+/// Features and limits of a radio, as driven by this crate.
+///
+/// Generic application code can use them to adapt at runtime to the radio it runs on, e.g. to pick a link
+/// budget or fall back from duty-cycled receive to plain receive.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Capabilities {
+    /// Lowest output power in dBm.
+    pub min_output_power: i32,
+    /// Highest output power in dBm.
+    pub max_output_power: i32,
+    /// Lowest carrier frequency in Hz.
+    pub min_frequency_in_hz: u32,
+    /// Highest carrier frequency in Hz.
+    pub max_frequency_in_hz: u32,
+    /// Lowest spreading factor.
+    pub min_spreading_factor: SpreadingFactor,
+    /// Channel activity detection, see [`P2pRadio::cad`].
+    pub cad: bool,
+    /// Hardware duty-cycled receive, see [`P2pRadio::receive_duty_cycle`].
+    pub duty_cycled_rx: bool,
+    /// (G)FSK modulation. lora-phy only drives the LoRa modem of the radios.
+    pub gfsk: bool,
+    /// Time-of-flight ranging, only available on 2.4 GHz radios which lora-phy does not support.
+    pub ranging: bool,
+}
+
+impl Capabilities {
+    /// Capabilities of the radio fitted on a board.
+    pub fn of(board_type: BoardType) -> Self {
+        let sx126x = Self {
+            min_output_power: -9,
+            max_output_power: 22,
+            min_frequency_in_hz: 150_000_000,
+            max_frequency_in_hz: 960_000_000,
+            min_spreading_factor: SpreadingFactor::_5,
+            cad: true,
+            duty_cycled_rx: true,
+            gfsk: false,
+            ranging: false,
+        };
+        match board_type {
+            BoardType::GenericSx1261 => Self {
+                min_output_power: -17,
+                max_output_power: 15,
+                ..sx126x
+            },
+            // lora-phy transmits through the PA_BOOST output of the sx1276
+            BoardType::Stm32l0Sx1276 => Self {
+                min_output_power: 2,
+                max_output_power: 20,
+                min_frequency_in_hz: 137_000_000,
+                max_frequency_in_hz: 1_020_000_000,
+                min_spreading_factor: SpreadingFactor::_6,
+                duty_cycled_rx: false,
+                ..sx126x
+            },
+            _ => sx126x,
+        }
+    }
+}
+
+/// A LoRa radio used for point-to-point links.
+pub struct P2pRadio<RK, DLY, CS = NoCarrierSense>
+where
+    RK: RadioKind,
+    DLY: DelayUs,
+    CS: CarrierSense,
+{
+    lora: LoRa<RK, DLY>,
+    carrier_sense: CS,
+    region: Option<Region>,
+    lbt: Option<ListenBeforeTalk>,
+    tx_hook: Option<fn(&TxRecord)>,
+    interrupted: bool,
+}
+
+impl<RK, DLY> P2pRadio<RK, DLY>
+where
+    RK: RadioKind,
+    DLY: DelayUs,
+{
+    /// Create a point-to-point radio without regulatory checks.
+    pub fn new(lora: LoRa<RK, DLY>) -> Self {
+        Self {
+            lora,
+            carrier_sense: NoCarrierSense,
+            region: None,
+            lbt: None,
+            tx_hook: None,
+            interrupted: false,
+        }
+    }
+
+    /// Create a point-to-point radio that rejects operations not allowed in `region`.
+    pub fn with_region(lora: LoRa<RK, DLY>, region: Region) -> Self {
+        Self {
+            lora,
+            carrier_sense: NoCarrierSense,
+            region: Some(region),
+            lbt: None,
+            tx_hook: None,
+            interrupted: false,
+        }
+    }
+}
+
+impl<RK, DLY, CS> P2pRadio<RK, DLY, CS>
+where
+    RK: RadioKind,
+    DLY: DelayUs,
+    CS: CarrierSense,
+{
+    /// Read the RSSI with `carrier_sense`, for the carrier sense of listen-before-talk.
+    pub fn with_carrier_sense<C: CarrierSense>(self, carrier_sense: C) -> P2pRadio<RK, DLY, C> {
+        P2pRadio {
+            lora: self.lora,
+            carrier_sense,
+            region: self.region,
+            lbt: self.lbt,
+            tx_hook: self.tx_hook,
+            interrupted: self.interrupted,
+        }
+    }
+
+    /// Attach a regulatory region, or remove it with `None`.
+    pub fn set_region(&mut self, region: Option<Region>) {
+        self.region = region;
+    }
+
+    /// The regulatory region attached to the radio.
+    pub fn region(&self) -> Option<Region> {
+        self.region
+    }
+
+    /// Features and limits of the radio.
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities::of(self.lora.get_board_type())
+    }
+
+    /// Assess the channel before every transmission, or transmit unconditionally with `None`.
+    ///
+    /// Carrier sense is rejected with [`Error::CarrierSenseUnavailable`] if the radio has no [`CarrierSense`]
+    /// implementation, see [`with_carrier_sense`](Self::with_carrier_sense).
+    pub fn set_listen_before_talk(&mut self, lbt: Option<ListenBeforeTalk>) -> Result<(), Error> {
+        check_carrier_sense::<CS>(lbt.as_ref())?;
+        self.lbt = lbt;
+        Ok(())
+    }
+
+    /// Install a function called after every successful transmission, or remove it with `None`.
+    ///
+    /// This is intended for devices that have to keep a record of their transmissions, e.g. for regulatory
+    /// audits. The hook runs in the context of the task transmitting, so it should only hand the record over,
+    /// for instance to a channel drained by a task writing to flash.
+    pub fn set_tx_hook(&mut self, hook: Option<fn(&TxRecord)>) {
+        self.tx_hook = hook;
+    }
+
+    /// Access the underlying lora-phy instance.
+    ///
+    /// Operations performed directly on the radio bypass the regulatory checks.
+    pub fn lora(&mut self) -> &mut LoRa<RK, DLY> {
+        &mut self.lora
+    }
+
+    /// Release the underlying lora-phy instance.
+    pub fn release(self) -> LoRa<RK, DLY> {
+        self.lora
+    }
+
+    /// Transmit a packet.
+    ///
+    /// If listen-before-talk is enabled and the channel is found busy, [`Error::ChannelBusy`] is returned and it
+    /// is up to the caller to back off and retry.
+    pub async fn send(&mut self, config: &LinkConfig, payload: &[u8]) -> Result<(), Error> {
+        self.recover().await?;
+        if let Some(len) = config.implicit_header {
+            if payload.len() != len as usize {
+                return Err(RadioError::PayloadSizeMismatch(len as usize, payload.len()).into());
+            }
+        }
+
+        let time_on_air_us = config.time_on_air_us(payload.len());
+        if let Some(region) = &self.region {
+            region.check_tx(
+                config.frequency_in_hz,
+                bandwidth_in_hz(config.bandwidth),
+                config.output_power,
+                time_on_air_us,
+            )?;
+        }
+
+        let mdltn_params = self.modulation_params(config)?;
+        if let Some(lbt) = self.lbt {
+            let rx_pkt_params = self.rx_packet_params(config, &mdltn_params, u8::MAX)?;
+            self.interrupted = true;
+            let busy = channel_busy(
+                &mut self.lora,
+                &mut self.carrier_sense,
+                &lbt,
+                &mdltn_params,
+                &rx_pkt_params,
+                config.rx_boosted,
+            )
+            .await?;
+            self.interrupted = false;
+            if busy {
+                debug!("channel busy, not transmitting");
+                return Err(Error::ChannelBusy);
+            }
+        }
+
+        let mut tx_pkt_params = self.lora.create_tx_packet_params(
+            config.preamble_length,
+            config.implicit_header.is_some(),
+            config.crc_on,
+            config.iq_inverted,
+            &mdltn_params,
+        )?;
+        self.interrupted = true;
+        self.lora
+            .prepare_for_tx(&mdltn_params, config.output_power, false)
+            .await?;
+        let result = self
+            .lora
+            .tx(&mdltn_params, &mut tx_pkt_params, payload, TX_TIMEOUT_MS)
+            .await;
+        self.interrupted = false;
+        result?;
+
+        if let Some(hook) = self.tx_hook {
+            hook(&TxRecord {
+                frequency_in_hz: config.frequency_in_hz,
+                output_power: config.output_power,
+                time_on_air_us,
+                payload_len: payload.len(),
+                #[cfg(feature = "time")]
+                timestamp: embassy_time::Instant::now(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Listen until a packet is received or the receive timeout of the link expires, and return the length and
+    /// reception quality of the packet.
+    pub async fn receive(&mut self, config: &LinkConfig, buf: &mut [u8]) -> Result<(usize, PacketStatus), Error> {
+        self.recover().await?;
+        if let Some(region) = &self.region {
+            region.check_rx(config.frequency_in_hz, bandwidth_in_hz(config.bandwidth))?;
+        }
+
+        let mdltn_params = self.modulation_params(config)?;
+        Ok(self
+            .receive_with(config, &mdltn_params, config.rx_timeout_secs, None, buf)
+            .await?)
+    }
+
+    /// Listen using the hardware duty-cycled receive mode, until a packet is received.
+    ///
+    /// The radio alternates between listening for `rx_time_us` and sleeping for `sleep_time_us` without any
+    /// intervention from the MCU, and stays in receive mode once a preamble is detected. The preamble of the
+    /// transmitter must be long enough to span a sleep period plus a couple of symbols, see
+    /// [`LinkConfig::preamble_length`]. Times are rounded down to the 15.625 µs resolution of the radio.
+    pub async fn receive_duty_cycle(
+        &mut self,
+        config: &LinkConfig,
+        rx_time_us: u32,
+        sleep_time_us: u32,
+        buf: &mut [u8],
+    ) -> Result<(usize, PacketStatus), Error> {
+        self.recover().await?;
+        if let Some(region) = &self.region {
+            region.check_rx(config.frequency_in_hz, bandwidth_in_hz(config.bandwidth))?;
+        }
+
+        let duty_cycle_params = DutyCycleParams {
+            rx_time: (rx_time_us as u64 * 64 / 1000) as u32,
+            sleep_time: (sleep_time_us as u64 * 64 / 1000) as u32,
+        };
+        let mdltn_params = self.modulation_params(config)?;
+        Ok(self
+            .receive_with(config, &mdltn_params, None, Some(&duty_cycle_params), buf)
+            .await?)
+    }
+
+    /// Put the radio in continuous receive mode and return a stream of the packets received.
+    ///
+    /// The radio stays in receive mode between packets, so none are missed while the previous one is processed.
+    /// Buffers passed to [`RxStream::next_packet`] should be able to hold the largest packet, 255 bytes.
+    pub async fn receive_continuous(&mut self, config: &LinkConfig) -> Result<RxStream<'_, RK, DLY>, Error> {
+        self.recover().await?;
+        if let Some(region) = &self.region {
+            region.check_rx(config.frequency_in_hz, bandwidth_in_hz(config.bandwidth))?;
+        }
+
+        let mdltn_params = self.modulation_params(config)?;
+        let rx_pkt_params = self.rx_packet_params(config, &mdltn_params, u8::MAX)?;
+        // The radio keeps receiving until the next operation once the stream is dropped
+        self.interrupted = true;
+        self.lora
+            .prepare_for_rx(&mdltn_params, &rx_pkt_params, None, None, config.rx_boosted)
+            .await?;
+        Ok(RxStream {
+            lora: &mut self.lora,
+            rx_pkt_params,
+        })
+    }
+
+    /// Run channel activity detection, returning true if a LoRa preamble was detected on the channel.
+    pub async fn cad(&mut self, config: &LinkConfig) -> Result<bool, Error> {
+        self.recover().await?;
+        if let Some(region) = &self.region {
+            region.check_rx(config.frequency_in_hz, bandwidth_in_hz(config.bandwidth))?;
+        }
+
+        let mdltn_params = self.modulation_params(config)?;
+        self.interrupted = true;
+        self.lora.prepare_for_cad(&mdltn_params, config.rx_boosted).await?;
+        let result = self.lora.cad().await;
+        self.interrupted = false;
+        Ok(result?)
+    }
+
+    /// Run channel activity detection and receive the packet if activity is detected.
+    ///
+    /// This is the equivalent of the CAD-to-RX exit mode of the radios: the receiver is only started when a
+    /// preamble is on air, and it gives up if no packet follows within a second. Returns `None` if the channel
+    /// was idle or no packet was received.
+    pub async fn cad_receive(
+        &mut self,
+        config: &LinkConfig,
+        buf: &mut [u8],
+    ) -> Result<Option<(usize, PacketStatus)>, Error> {
+        if !self.cad(config).await? {
+            return Ok(None);
+        }
+
+        let mdltn_params = self.modulation_params(config)?;
+        match self
+            .receive_with(config, &mdltn_params, Some(CAD_RX_WINDOW_SECS), None, buf)
+            .await
+        {
+            Ok(received) => Ok(Some(received)),
+            Err(RadioError::ReceiveTimeout) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Receive packets for as long as the channel stays busy.
+    ///
+    /// Waits for a first packet, then after every packet runs channel activity detection and keeps receiving
+    /// while activity is detected. `on_packet` is called with each packet and its reception quality. Returns
+    /// the number of packets received once the channel is idle.
+    pub async fn receive_while_busy(
+        &mut self,
+        config: &LinkConfig,
+        buf: &mut [u8],
+        mut on_packet: impl FnMut(&[u8], PacketStatus),
+    ) -> Result<usize, Error> {
+        let (len, status) = self.receive(config, buf).await?;
+        on_packet(&buf[..len], status);
+        let mut count = 1;
+        while let Some((len, status)) = self.cad_receive(config, buf).await? {
+            on_packet(&buf[..len], status);
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Transmit an unmodulated carrier on the link's frequency until [`stop`](Self::stop) is called.
+    ///
+    /// This is a test mode for antenna tuning and regulatory measurements. When a region is attached, the
+    /// frequency and output power are checked but the dwell time limit is not.
+    pub async fn continuous_wave(&mut self, config: &LinkConfig) -> Result<(), Error> {
+        self.recover().await?;
+        if let Some(region) = &self.region {
+            region.check_tx(
+                config.frequency_in_hz,
+                bandwidth_in_hz(config.bandwidth),
+                config.output_power,
+                0,
+            )?;
+        }
+
+        let mdltn_params = self.modulation_params(config)?;
+        // The carrier is on until the radio is stopped
+        self.interrupted = true;
+        self.lora
+            .continuous_wave(&mdltn_params, config.output_power, false)
+            .await?;
+        Ok(())
+    }
+
+    /// Abort the current operation, e.g. a continuous wave transmission, and put the radio to sleep.
+    ///
+    /// The radio wakes up by itself on the next operation.
+    pub async fn stop(&mut self) -> Result<(), Error> {
+        self.sleep(false).await
+    }
+
+    /// Put the radio in its lowest power state until the next operation.
+    ///
+    /// The RF switch is released and the oscillator stopped. With `warm_start`, the radio keeps its
+    /// configuration and wakes up faster, at the cost of a slightly higher sleep current; otherwise it is fully
+    /// reconfigured on wake up. There is no need to wake the radio explicitly, the next operation does it.
+    pub async fn sleep(&mut self, warm_start: bool) -> Result<(), Error> {
+        self.lora.sleep(warm_start).await?;
+        self.interrupted = false;
+        Ok(())
+    }
+
+    /// Stop an operation that was cancelled, putting the radio to sleep.
+    ///
+    /// Dropping the future of an operation, e.g. when it loses a `select!` race or times out, leaves the radio
+    /// transmitting or receiving with the RF switch engaged. The next operation stops it before doing anything
+    /// else, but calling this right after cancelling an operation saves the power drawn in the meantime. It does
+    /// nothing if no operation was cancelled.
+    pub async fn recover(&mut self) -> Result<(), Error> {
+        if self.interrupted {
+            debug!("radio operation cancelled, putting the radio to sleep");
+            self.sleep(false).await?;
+        }
+        Ok(())
+    }
+
+    fn modulation_params(&mut self, config: &LinkConfig) -> Result<ModulationParams, RadioError> {
+        self.lora.create_modulation_params(
+            config.spreading_factor,
+            config.bandwidth,
+            config.coding_rate,
+            config.frequency_in_hz,
+        )
+    }
+
+    fn rx_packet_params(
+        &mut self,
+        config: &LinkConfig,
+        mdltn_params: &ModulationParams,
+        max_payload_length: u8,
+    ) -> Result<PacketParams, RadioError> {
+        // In implicit header mode the length programmed is the one of every packet, not a maximum
+        let (implicit_header, payload_length) = match config.implicit_header {
+            Some(len) => (true, len),
+            None => (false, max_payload_length),
+        };
+        self.lora.create_rx_packet_params(
+            config.preamble_length,
+            implicit_header,
+            payload_length,
+            config.crc_on,
+            config.iq_inverted,
+            mdltn_params,
+        )
+    }
+
+    async fn receive_with(
+        &mut self,
+        config: &LinkConfig,
+        mdltn_params: &ModulationParams,
+        window_in_secs: Option<u8>,
+        duty_cycle_params: Option<&DutyCycleParams>,
+        buf: &mut [u8],
+    ) -> Result<(usize, PacketStatus), RadioError> {
+        let max_payload_length = buf.len().min(u8::MAX as usize) as u8;
+        let rx_pkt_params = self.rx_packet_params(config, mdltn_params, max_payload_length)?;
+        self.interrupted = true;
+        self.lora
+            .prepare_for_rx(
+                mdltn_params,
+                &rx_pkt_params,
+                window_in_secs,
+                duty_cycle_params,
+                config.rx_boosted,
+            )
+            .await?;
+        let result = self.lora.rx(&rx_pkt_params, buf).await;
+        self.interrupted = false;
+        let (len, status) = result?;
+        Ok((len as usize, status))
+    }
+}
+
+/// Packets received by a radio in continuous receive mode, see [`P2pRadio::receive_continuous`].
+///
+/// Dropping the stream leaves the radio in receive mode until the next operation, or until
+/// [`P2pRadio::recover`] is called.
+pub struct RxStream<'a, RK, DLY>
+where
+    RK: RadioKind,
+    DLY: DelayUs,
+{
+    lora: &'a mut LoRa<RK, DLY>,
+    rx_pkt_params: PacketParams,
+}
+
+impl<'a, RK, DLY> RxStream<'a, RK, DLY>
+where
+    RK: RadioKind,
+    DLY: DelayUs,
+{
+    /// Wait for the next packet, and return its length and reception quality.
+    pub async fn next_packet(&mut self, buf: &mut [u8]) -> Result<(usize, PacketStatus), Error> {
+        let (len, status) = self.lora.rx(&self.rx_pkt_params, buf).await?;
+        Ok((len as usize, status))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capabilities() {
+        let sx1262 = Capabilities::of(BoardType::Stm32wlSx1262);
+        assert_eq!(sx1262.max_output_power, 22);
+        assert!(sx1262.duty_cycled_rx);
+
+        let sx1276 = Capabilities::of(BoardType::Stm32l0Sx1276);
+        assert_eq!(sx1276.min_spreading_factor, SpreadingFactor::_6);
+        assert!(sx1276.cad && !sx1276.duty_cycled_rx);
+        assert_eq!(Capabilities::of(BoardType::GenericSx1261).max_output_power, 15);
+    }
+
+    #[test]
+    fn receive_errors() {
+        assert_eq!(Error::from(RadioError::ReceiveTimeout), Error::RxTimeout);
+        assert_eq!(Error::from(RadioError::CRCErrorOnReceive), Error::Crc);
+        assert_eq!(Error::from(RadioError::Busy), Error::Radio(RadioError::Busy));
+    }
+
+    #[test]
+    fn carrier_sense_required() {
+        struct Rssi;
+        impl CarrierSense for Rssi {
+            async fn max_rssi(&mut self, _duration_us: u32) -> Result<i16, RadioError> {
+                Ok(-100)
+            }
+        }
+
+        let cad_only = ListenBeforeTalk {
+            rssi_threshold_dbm: None,
+            duration_us: 0,
+            cad_count: 2,
+        };
+        assert_eq!(
+            check_carrier_sense::<NoCarrierSense>(Some(&ListenBeforeTalk::JAPAN)),
+            Err(Error::CarrierSenseUnavailable)
+        );
+        assert_eq!(check_carrier_sense::<NoCarrierSense>(Some(&cad_only)), Ok(()));
+        assert_eq!(check_carrier_sense::<NoCarrierSense>(None), Ok(()));
+        assert_eq!(check_carrier_sense::<Rssi>(Some(&ListenBeforeTalk::KOREA)), Ok(()));
+    }
+}
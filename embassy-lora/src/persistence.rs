@@ -0,0 +1,651 @@
+//! Persistence of the LoRaWAN session in flash.
+//!
+//! Rejoining after every reset wastes airtime and battery, and quickly exhausts the DevNonces of LoRaWAN 1.0.4
+//! devices. [`SessionStore`] keeps the session keys, the DevAddr, the frame counters and the last DevNonce in
+//! [`PAGES`] erase pages of a NOR flash, so that a device resumes its session after a reset.
+//!
+//! The flash is used as two append-only logs, one for the session and one for the frame counters, each
+//! spanning two pages so that the latest entry survives a power loss while the other page is erased. The
+//! uplink counter, which changes with every uplink, is only written every `counter_step` uplinks: the store
+//! reserves counter values ahead, and a device restarting resumes after the reservation, skipping at most
+//! `counter_step` values but never reusing one.
+//!
+//! LoRaWAN 1.1 sessions are supported as well: their additional network keys can be derived with
+//! [`Session::derive_1_1`], the last JoinNonce is kept to reject replayed join accepts, and the store tracks
+//! the `RekeyInd` the device has to send until the network confirms the session with a `RekeyConf`.
+
+use aes::cipher::{BlockEncrypt, KeyInit};
+use aes::Aes128;
+use embedded_storage_async::nor_flash::NorFlash;
+
+use crate::adr::ADR_ACK_LIMIT;
+
+/// Number of flash erase pages used by a [`SessionStore`].
+pub const PAGES: u32 = 4;
+
+/// Command identifier of `RekeyInd` and `RekeyConf`.
+pub const REKEY_CID: u8 = 0x0b;
+/// Minor version of LoRaWAN 1.1, sent in `RekeyInd`.
+const LORAWAN_1_1_MINOR: u8 = 1;
+
+const MAX_ENTRY_LENGTH: usize = 128;
+const CHECKSUM_LENGTH: usize = 2;
+// Sequence number, session id, flags, DevNonce, JoinNonce, DevAddr and session keys
+const SESSION_LENGTH: usize = 4 + 4 + 1 + 2 + 4 + 4 + 4 * 16;
+const JOINED: u8 = 0x01;
+const LORAWAN_1_1: u8 = 0x02;
+const REKEY_PENDING: u8 = 0x04;
+const HAS_JOIN_NONCE: u8 = 0x08;
+// Sequence number, session id, reserved uplink counter and downlink counter
+const COUNTERS_LENGTH: usize = 4 + 4 + 4 + 4;
+
+/// Errors reported when setting up a [`SessionStore`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The pages are not aligned to an erase page or do not fit the flash, or the entries do not fit a page.
+    InvalidConfiguration,
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::InvalidConfiguration => write!(f, "invalid flash configuration"),
+        }
+    }
+}
+
+impl core::error::Error for Error {}
+
+/// A LoRaWAN session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Session {
+    /// Device address assigned by the network.
+    pub dev_addr: u32,
+    /// Network session key, the FNwkSIntKey of LoRaWAN 1.1.
+    pub nwk_skey: [u8; 16],
+    /// Application session key.
+    pub app_skey: [u8; 16],
+    /// Counter of the next uplink.
+    pub fcnt_up: u32,
+    /// Last downlink frame counter.
+    pub fcnt_down: u32,
+    /// Additional network keys of a LoRaWAN 1.1 session.
+    pub lorawan_1_1: Option<Lorawan11Keys>,
+}
+
+/// Network session keys specific to LoRaWAN 1.1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Lorawan11Keys {
+    /// Serving network session integrity key.
+    pub snwk_sint_key: [u8; 16],
+    /// Network session encryption key, encrypting the MAC commands.
+    pub nwk_senc_key: [u8; 16],
+}
+
+impl Session {
+    /// Derive the session keys of a LoRaWAN 1.1 join from the root keys of the device and the join exchange.
+    pub fn derive_1_1(
+        dev_addr: u32,
+        nwk_key: &[u8; 16],
+        app_key: &[u8; 16],
+        join_nonce: u32,
+        join_eui: u64,
+        dev_nonce: u16,
+    ) -> Self {
+        let derive = |key: &[u8; 16], kind: u8| {
+            let mut block = [0; 16];
+            block[0] = kind;
+            block[1..4].copy_from_slice(&join_nonce.to_le_bytes()[..3]);
+            block[4..12].copy_from_slice(&join_eui.to_le_bytes());
+            block[12..14].copy_from_slice(&dev_nonce.to_le_bytes());
+            let cipher = Aes128::new(key.into());
+            cipher.encrypt_block(aes::Block::from_mut_slice(&mut block));
+            block
+        };
+        Self {
+            dev_addr,
+            nwk_skey: derive(nwk_key, 0x01),
+            app_skey: derive(app_key, 0x02),
+            fcnt_up: 0,
+            fcnt_down: 0,
+            lorawan_1_1: Some(Lorawan11Keys {
+                snwk_sint_key: derive(nwk_key, 0x03),
+                nwk_senc_key: derive(nwk_key, 0x04),
+            }),
+        }
+    }
+}
+
+/// State restored from flash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Stored {
+    /// Last DevNonce used to join.
+    pub dev_nonce: u16,
+    /// Last JoinNonce accepted from a LoRaWAN 1.1 network.
+    pub join_nonce: Option<u32>,
+    /// The session, if the device has joined.
+    pub session: Option<Session>,
+}
+
+/// An append-only log of fixed-size entries over two erase pages.
+#[derive(Debug, Clone, Copy)]
+struct Log {
+    start: u32,
+    page_size: u32,
+    payload_len: usize,
+    entry_len: usize,
+    seq: u32,
+    next: u32,
+}
+
+impl Log {
+    fn new(start: u32, page_size: u32, payload_len: usize, write_size: usize) -> Result<Self, Error> {
+        let entry_len = (payload_len + CHECKSUM_LENGTH).div_ceil(write_size) * write_size;
+        if entry_len > MAX_ENTRY_LENGTH || entry_len as u32 > page_size {
+            return Err(Error::InvalidConfiguration);
+        }
+        Ok(Self {
+            start,
+            page_size,
+            payload_len,
+            entry_len,
+            seq: 0,
+            next: 0,
+        })
+    }
+
+    fn slots_per_page(&self) -> u32 {
+        self.page_size / self.entry_len as u32
+    }
+
+    fn slots(&self) -> u32 {
+        2 * self.slots_per_page()
+    }
+
+    fn offset(&self, slot: u32) -> u32 {
+        let slots_per_page = self.slots_per_page();
+        self.start + slot / slots_per_page * self.page_size + slot % slots_per_page * self.entry_len as u32
+    }
+
+    async fn read<F: NorFlash>(&self, flash: &mut F, slot: u32, entry: &mut [u8]) -> Result<(), F::Error> {
+        flash.read(self.offset(slot), &mut entry[..self.entry_len]).await
+    }
+
+    /// Find the latest valid entry, copying its payload. Returns false if the log is empty.
+    async fn scan<F: NorFlash>(&mut self, flash: &mut F, payload: &mut [u8]) -> Result<bool, F::Error> {
+        let mut entry = [0; MAX_ENTRY_LENGTH];
+        let mut latest = None;
+        for slot in 0..self.slots() {
+            self.read(flash, slot, &mut entry).await?;
+            let (data, check) = entry[..self.payload_len + CHECKSUM_LENGTH].split_at(self.payload_len);
+            if is_erased(&entry[..self.entry_len]) || checksum(data).to_le_bytes() != check {
+                continue;
+            }
+            let seq = u32::from_le_bytes(unwrap!(data[..4].try_into()));
+            if latest.map_or(true, |(latest_seq, _)| seq > latest_seq) {
+                latest = Some((seq, slot));
+                payload[..self.payload_len].copy_from_slice(data);
+            }
+        }
+
+        match latest {
+            Some((seq, slot)) => {
+                self.seq = seq;
+                self.next = (slot + 1) % self.slots();
+                Ok(true)
+            }
+            None => {
+                self.seq = 0;
+                self.next = 0;
+                Ok(false)
+            }
+        }
+    }
+
+    /// Append an entry, whose sequence number is filled in.
+    async fn append<F: NorFlash>(&mut self, flash: &mut F, payload: &mut [u8]) -> Result<(), F::Error> {
+        let mut entry = [0xff; MAX_ENTRY_LENGTH];
+        loop {
+            let slot = self.next;
+            self.next = (slot + 1) % self.slots();
+            if slot % self.slots_per_page() == 0 {
+                // The other page holds the latest entry, this one can be reclaimed
+                let page = self.offset(slot);
+                flash.erase(page, page + self.page_size).await?;
+                break;
+            }
+            // Skip entries torn by a power loss
+            self.read(flash, slot, &mut entry).await?;
+            if is_erased(&entry[..self.entry_len]) {
+                break;
+            }
+        }
+
+        self.seq = self.seq.wrapping_add(1);
+        payload[..4].copy_from_slice(&self.seq.to_le_bytes());
+        let payload = &payload[..self.payload_len];
+        entry.fill(0xff);
+        entry[..self.payload_len].copy_from_slice(payload);
+        entry[self.payload_len..][..CHECKSUM_LENGTH].copy_from_slice(&checksum(payload).to_le_bytes());
+        let slot = (self.next + self.slots() - 1) % self.slots();
+        flash.write(self.offset(slot), &entry[..self.entry_len]).await
+    }
+}
+
+fn is_erased(entry: &[u8]) -> bool {
+    entry.iter().all(|byte| *byte == 0xff)
+}
+
+/// Fletcher-16 checksum, detecting entries torn by a power loss.
+fn checksum(data: &[u8]) -> u16 {
+    let (a, b) = data.iter().fold((0u16, 0u16), |(a, b), byte| {
+        let a = (a + *byte as u16) % 255;
+        (a, (b + a) % 255)
+    });
+    b << 8 | a
+}
+
+/// Storage of a LoRaWAN session in [`PAGES`] erase pages of a NOR flash.
+pub struct SessionStore<F: NorFlash> {
+    flash: F,
+    counter_step: u32,
+    session_log: Log,
+    counter_log: Log,
+    session_id: u32,
+    dev_nonce: u16,
+    join_nonce: Option<u32>,
+    session: Option<Session>,
+    reserved_fcnt_up: u32,
+    rekey_pending: bool,
+    rekey_uplinks: u16,
+}
+
+impl<F: NorFlash> SessionStore<F> {
+    /// Store the session in the pages of `flash` starting at `offset`, which must be aligned to an erase page.
+    ///
+    /// The uplink counter is written every `counter_step` uplinks, trading flash wear for the counter values
+    /// skipped after a reset. Call [`load`](Self::load) before any other method.
+    pub fn new(flash: F, offset: u32, counter_step: u32) -> Result<Self, Error> {
+        let page_size = F::ERASE_SIZE as u32;
+        if offset % page_size != 0 || offset as usize + PAGES as usize * F::ERASE_SIZE > flash.capacity() {
+            return Err(Error::InvalidConfiguration);
+        }
+        Ok(Self {
+            flash,
+            counter_step: counter_step.max(1),
+            session_log: Log::new(offset, page_size, SESSION_LENGTH, F::WRITE_SIZE)?,
+            counter_log: Log::new(offset + 2 * page_size, page_size, COUNTERS_LENGTH, F::WRITE_SIZE)?,
+            session_id: 0,
+            dev_nonce: 0,
+            join_nonce: None,
+            session: None,
+            reserved_fcnt_up: 0,
+            rekey_pending: false,
+            rekey_uplinks: 0,
+        })
+    }
+
+    /// Restore the state stored in flash.
+    ///
+    /// The uplink counter of the session restored is the first one not reserved, so that no value is reused.
+    pub async fn load(&mut self) -> Result<Stored, F::Error> {
+        let mut payload = [0; SESSION_LENGTH];
+        self.session = None;
+        if self.session_log.scan(&mut self.flash, &mut payload).await? {
+            let flags = payload[8];
+            self.session_id = u32::from_le_bytes(unwrap!(payload[4..8].try_into()));
+            self.dev_nonce = u16::from_le_bytes([payload[9], payload[10]]);
+            self.join_nonce =
+                (flags & HAS_JOIN_NONCE != 0).then(|| u32::from_le_bytes(unwrap!(payload[11..15].try_into())));
+            self.rekey_pending = flags & REKEY_PENDING != 0;
+            if flags & JOINED != 0 {
+                self.session = Some(Session {
+                    dev_addr: u32::from_le_bytes(unwrap!(payload[15..19].try_into())),
+                    nwk_skey: unwrap!(payload[19..35].try_into()),
+                    app_skey: unwrap!(payload[35..51].try_into()),
+                    fcnt_up: 0,
+                    fcnt_down: 0,
+                    lorawan_1_1: (flags & LORAWAN_1_1 != 0).then(|| Lorawan11Keys {
+                        snwk_sint_key: unwrap!(payload[51..67].try_into()),
+                        nwk_senc_key: unwrap!(payload[67..83].try_into()),
+                    }),
+                });
+            }
+        }
+
+        let mut payload = [0; COUNTERS_LENGTH];
+        self.reserved_fcnt_up = 0;
+        if self.counter_log.scan(&mut self.flash, &mut payload).await?
+            && u32::from_le_bytes(unwrap!(payload[4..8].try_into())) == self.session_id
+        {
+            self.reserved_fcnt_up = u32::from_le_bytes(unwrap!(payload[8..12].try_into()));
+            if let Some(session) = &mut self.session {
+                session.fcnt_up = self.reserved_fcnt_up;
+                session.fcnt_down = u32::from_le_bytes(unwrap!(payload[12..16].try_into()));
+            }
+        }
+
+        debug!(
+            "session restored, joined: {}, DevNonce: {}",
+            self.session.is_some(),
+            self.dev_nonce
+        );
+        Ok(Stored {
+            dev_nonce: self.dev_nonce,
+            join_nonce: self.join_nonce,
+            session: self.session,
+        })
+    }
+
+    /// Take the next DevNonce, which is stored before being returned so that it is never used twice.
+    pub async fn next_dev_nonce(&mut self) -> Result<u16, F::Error> {
+        self.dev_nonce = self.dev_nonce.wrapping_add(1);
+        self.write_session().await?;
+        Ok(self.dev_nonce)
+    }
+
+    /// Store the session established by a join, including its frame counters.
+    pub async fn save_session(&mut self, session: &Session) -> Result<(), F::Error> {
+        self.session_id = self.session_id.wrapping_add(1);
+        self.session = Some(*session);
+        self.rekey_pending = false;
+        self.write_session().await?;
+        self.write_counters(session.fcnt_up, session.fcnt_down).await
+    }
+
+    /// Whether a LoRaWAN 1.1 join accept carrying `join_nonce` is fresh, and not replayed.
+    pub fn is_join_nonce_fresh(&self, join_nonce: u32) -> bool {
+        self.join_nonce.map_or(true, |last| join_nonce > last)
+    }
+
+    /// Store the session established by a LoRaWAN 1.1 join accept carrying `join_nonce`.
+    ///
+    /// The device then has to send a `RekeyInd`, see [`rekey_ind`](Self::rekey_ind).
+    pub async fn save_join_accept(&mut self, session: &Session, join_nonce: u32) -> Result<(), F::Error> {
+        self.session_id = self.session_id.wrapping_add(1);
+        self.session = Some(*session);
+        self.join_nonce = Some(join_nonce);
+        self.rekey_pending = session.lorawan_1_1.is_some();
+        self.rekey_uplinks = 0;
+        self.write_session().await?;
+        self.write_counters(session.fcnt_up, session.fcnt_down).await
+    }
+
+    /// The `RekeyInd` MAC command to add to every uplink until the network confirms the session.
+    pub fn rekey_ind(&self) -> Option<[u8; 2]> {
+        self.rekey_pending.then_some([REKEY_CID, LORAWAN_1_1_MINOR])
+    }
+
+    /// Handle the payload of a `RekeyConf` MAC command, following its command identifier.
+    pub async fn on_rekey_conf(&mut self, payload: &[u8]) -> Result<(), F::Error> {
+        match payload.first() {
+            Some(version) if self.rekey_pending && version & 0x0f == LORAWAN_1_1_MINOR => {
+                debug!("LoRaWAN 1.1 session confirmed");
+                self.rekey_pending = false;
+                self.write_session().await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the network failed to confirm the session within `ADR_ACK_LIMIT` uplinks, in which case the
+    /// device must join again.
+    pub fn rekey_expired(&self) -> bool {
+        self.rekey_pending && self.rekey_uplinks >= ADR_ACK_LIMIT
+    }
+
+    /// Forget the session, e.g. before rejoining. The DevNonce and JoinNonce are kept.
+    pub async fn clear_session(&mut self) -> Result<(), F::Error> {
+        self.session_id = self.session_id.wrapping_add(1);
+        self.session = None;
+        self.rekey_pending = false;
+        self.write_session().await
+    }
+
+    /// Update the frame counters of the session, before sending an uplink and after receiving a downlink.
+    ///
+    /// `fcnt_up` is the counter of the next uplink, which must only be sent once this returns. The flash is only
+    /// written when the uplink counter reaches the values reserved, so the downlink counter restored after a
+    /// reset may lag behind by up to `counter_step` uplinks.
+    pub async fn update_counters(&mut self, fcnt_up: u32, fcnt_down: u32) -> Result<(), F::Error> {
+        if let Some(session) = &mut self.session {
+            if self.rekey_pending && fcnt_up != session.fcnt_up {
+                self.rekey_uplinks = self.rekey_uplinks.saturating_add(1);
+            }
+            session.fcnt_up = fcnt_up;
+            session.fcnt_down = fcnt_down;
+            if fcnt_up >= self.reserved_fcnt_up {
+                self.write_counters(fcnt_up, fcnt_down).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Release the flash.
+    pub fn release(self) -> F {
+        self.flash
+    }
+
+    async fn write_session(&mut self) -> Result<(), F::Error> {
+        let mut payload = [0; SESSION_LENGTH];
+        payload[4..8].copy_from_slice(&self.session_id.to_le_bytes());
+        payload[9..11].copy_from_slice(&self.dev_nonce.to_le_bytes());
+        if let Some(join_nonce) = self.join_nonce {
+            payload[8] |= HAS_JOIN_NONCE;
+            payload[11..15].copy_from_slice(&join_nonce.to_le_bytes());
+        }
+        if let Some(session) = &self.session {
+            payload[8] |= JOINED;
+            payload[15..19].copy_from_slice(&session.dev_addr.to_le_bytes());
+            payload[19..35].copy_from_slice(&session.nwk_skey);
+            payload[35..51].copy_from_slice(&session.app_skey);
+            if let Some(keys) = &session.lorawan_1_1 {
+                payload[8] |= LORAWAN_1_1;
+                payload[51..67].copy_from_slice(&keys.snwk_sint_key);
+                payload[67..83].copy_from_slice(&keys.nwk_senc_key);
+            }
+        }
+        if self.rekey_pending {
+            payload[8] |= REKEY_PENDING;
+        }
+        self.session_log.append(&mut self.flash, &mut payload).await
+    }
+
+    async fn write_counters(&mut self, fcnt_up: u32, fcnt_down: u32) -> Result<(), F::Error> {
+        let reserved = fcnt_up.saturating_add(self.counter_step);
+        let mut payload = [0; COUNTERS_LENGTH];
+        payload[4..8].copy_from_slice(&self.session_id.to_le_bytes());
+        payload[8..12].copy_from_slice(&reserved.to_le_bytes());
+        payload[12..16].copy_from_slice(&fcnt_down.to_le_bytes());
+        self.counter_log.append(&mut self.flash, &mut payload).await?;
+        trace!("uplink counters reserved up to {}", reserved);
+        self.reserved_fcnt_up = reserved;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::convert::Infallible;
+
+    use embedded_storage_async::nor_flash::{ErrorType, ReadNorFlash};
+    use futures_executor::block_on;
+
+    use super::*;
+
+    struct Flash {
+        data: [u8; 1024],
+        writes: usize,
+    }
+
+    impl Flash {
+        fn new() -> Self {
+            Self {
+                data: [0xff; 1024],
+                writes: 0,
+            }
+        }
+    }
+
+    impl ErrorType for Flash {
+        type Error = Infallible;
+    }
+
+    impl ReadNorFlash for Flash {
+        const READ_SIZE: usize = 1;
+
+        async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Infallible> {
+            bytes.copy_from_slice(&self.data[offset as usize..][..bytes.len()]);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            self.data.len()
+        }
+    }
+
+    impl NorFlash for Flash {
+        const WRITE_SIZE: usize = 8;
+        const ERASE_SIZE: usize = 256;
+
+        async fn erase(&mut self, from: u32, to: u32) -> Result<(), Infallible> {
+            self.data[from as usize..to as usize].fill(0xff);
+            Ok(())
+        }
+
+        async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Infallible> {
+            assert_eq!(offset as usize % Self::WRITE_SIZE, 0);
+            for (cell, byte) in self.data[offset as usize..].iter_mut().zip(bytes) {
+                assert_eq!(*cell & byte, *byte, "bit set without erase");
+                *cell = *byte;
+            }
+            self.writes += 1;
+            Ok(())
+        }
+    }
+
+    fn session() -> Session {
+        Session {
+            dev_addr: 0x2601_1bd7,
+            nwk_skey: [0x11; 16],
+            app_skey: [0x22; 16],
+            fcnt_up: 0,
+            fcnt_down: 0,
+            lorawan_1_1: None,
+        }
+    }
+
+    #[test]
+    fn session_survives_reset() {
+        let mut store = SessionStore::new(Flash::new(), 0, 16).unwrap();
+        assert_eq!(
+            block_on(store.load()).unwrap(),
+            Stored {
+                dev_nonce: 0,
+                join_nonce: None,
+                session: None
+            }
+        );
+        assert_eq!(block_on(store.next_dev_nonce()).unwrap(), 1);
+        block_on(store.save_session(&session())).unwrap();
+        for fcnt_up in 1..=20 {
+            block_on(store.update_counters(fcnt_up, 3)).unwrap();
+        }
+
+        let mut store = SessionStore::new(store.release(), 0, 16).unwrap();
+        let stored = block_on(store.load()).unwrap();
+        assert_eq!(stored.dev_nonce, 1);
+        let restored = stored.session.unwrap();
+        assert_eq!(restored.dev_addr, session().dev_addr);
+        assert_eq!(restored.app_skey, session().app_skey);
+        assert_eq!((restored.fcnt_up, restored.fcnt_down), (32, 3));
+
+        block_on(store.clear_session()).unwrap();
+        let mut store = SessionStore::new(store.release(), 0, 16).unwrap();
+        assert_eq!(block_on(store.load()).unwrap().session, None);
+    }
+
+    #[test]
+    fn invalid_partition() {
+        // Misaligned, then past the end of the flash
+        assert_eq!(
+            SessionStore::new(Flash::new(), 128, 16).err(),
+            Some(Error::InvalidConfiguration)
+        );
+        assert_eq!(
+            SessionStore::new(Flash::new(), 256, 16).err(),
+            Some(Error::InvalidConfiguration)
+        );
+    }
+
+    #[test]
+    fn counters_wear_and_power_loss() {
+        let mut store = SessionStore::new(Flash::new(), 0, 4).unwrap();
+        block_on(store.load()).unwrap();
+        block_on(store.save_session(&session())).unwrap();
+        for fcnt_up in 1..=1000 {
+            block_on(store.update_counters(fcnt_up, 0)).unwrap();
+        }
+        let mut flash = store.release();
+        // One counter write every 4 uplinks, the logs wrapped around their pages several times
+        assert!(flash.writes <= 2 + 1000 / 4);
+
+        // Tear the latest counter entry, the previous reservation still covers every counter sent
+        let mut store = SessionStore::new(flash, 0, 4).unwrap();
+        block_on(store.load()).unwrap();
+        let log = store.counter_log;
+        flash = store.release();
+        let slot = (log.next + log.slots() - 1) % log.slots();
+        flash.data[log.offset(slot) as usize + 8] = 0;
+        let mut store = SessionStore::new(flash, 0, 4).unwrap();
+        let fcnt_up = block_on(store.load()).unwrap().session.unwrap().fcnt_up;
+        assert_eq!(fcnt_up, 1000);
+        block_on(store.update_counters(fcnt_up, 0)).unwrap();
+        let mut store = SessionStore::new(store.release(), 0, 4).unwrap();
+        assert_eq!(block_on(store.load()).unwrap().session.unwrap().fcnt_up, fcnt_up + 4);
+    }
+
+    #[test]
+    fn lorawan_1_1_rekey() {
+        let mut store = SessionStore::new(Flash::new(), 0, 16).unwrap();
+        block_on(store.load()).unwrap();
+        let dev_nonce = block_on(store.next_dev_nonce()).unwrap();
+        let session = Session::derive_1_1(
+            0x2601_1bd7,
+            &[0x11; 16],
+            &[0x22; 16],
+            5,
+            0x70b3_d57e_d000_0000,
+            dev_nonce,
+        );
+        let keys = session.lorawan_1_1.unwrap();
+        assert_ne!(session.nwk_skey, keys.snwk_sint_key);
+        assert_ne!(keys.snwk_sint_key, keys.nwk_senc_key);
+        assert!(store.is_join_nonce_fresh(5));
+        block_on(store.save_join_accept(&session, 5)).unwrap();
+        assert_eq!(store.rekey_ind(), Some([REKEY_CID, 1]));
+
+        // The session and the pending RekeyInd survive a reset, replayed join accepts are rejected
+        let mut store = SessionStore::new(store.release(), 0, 16).unwrap();
+        let stored = block_on(store.load()).unwrap();
+        assert_eq!(stored.join_nonce, Some(5));
+        assert_eq!(stored.session.unwrap().lorawan_1_1, Some(keys));
+        assert!(!store.is_join_nonce_fresh(5));
+        assert!(store.rekey_ind().is_some());
+        for fcnt_up in 1..=ADR_ACK_LIMIT as u32 {
+            block_on(store.update_counters(fcnt_up, 0)).unwrap();
+        }
+        assert!(store.rekey_expired());
+
+        block_on(store.on_rekey_conf(&[0x01])).unwrap();
+        assert_eq!(store.rekey_ind(), None);
+        assert!(!store.rekey_expired());
+        let mut store = SessionStore::new(store.release(), 0, 16).unwrap();
+        block_on(store.load()).unwrap();
+        assert_eq!(store.rekey_ind(), None);
+    }
+}
@@ -0,0 +1,172 @@
+//! Traffic-driven power management of a point-to-point radio.
+//!
+//! [`PowerManager`] wraps a [`P2pRadio`] and moves it through progressively cheaper [`PowerState`]s as the link
+//! goes quiet: from continuous receive, to hardware duty-cycled receive, to standby and then deep sleep with
+//! periodic wake ups. Each wake up runs channel activity detection, and receives the packet if a preamble is on
+//! air, so a sender using a preamble longer than the wake period reaches a sleeping node. Any packet sent or
+//! received brings the radio back to the active state. The application can veto the deep sleep state, e.g.
+//! while it expects an answer.
+
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use embedded_hal_async::delay::DelayUs;
+use lora_phy::mod_params::PacketStatus;
+use lora_phy::mod_traits::RadioKind;
+
+use crate::p2p::{CarrierSense, Error, LinkConfig, P2pRadio};
+
+/// Power state of the radio.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PowerState {
+    /// Continuous receive, the lowest latency and highest consumption.
+    Active,
+    /// Hardware duty-cycled receive.
+    DutyCycledRx,
+    /// The radio sleeps with its configuration retained, for a fast wake up, and checks the channel periodically.
+    Standby,
+    /// The radio sleeps with the lowest consumption, and checks the channel periodically.
+    DeepSleep,
+}
+
+/// Thresholds driving the transitions between power states.
+#[derive(Debug, Clone, Copy)]
+pub struct PowerPolicy {
+    /// Time without traffic after which the radio leaves the active state.
+    pub idle_timeout: Duration,
+    /// Listen and sleep times of the duty-cycled receive state in microseconds, or `None` to skip that state.
+    pub duty_cycle_us: Option<(u32, u32)>,
+    /// Time without traffic after which the radio goes to standby, or `None` to skip that state.
+    pub standby_timeout: Option<Duration>,
+    /// Time without traffic after which the radio goes to deep sleep.
+    pub sleep_timeout: Duration,
+    /// Period of the wake ups in standby and deep sleep, each of which checks the channel for activity. Senders
+    /// have to use a preamble longer than this period to reach a node in these states.
+    pub wake_period: Duration,
+}
+
+/// A point-to-point radio whose power state follows the traffic.
+pub struct PowerManager<'a, RK, DLY, CS>
+where
+    RK: RadioKind,
+    DLY: DelayUs,
+    CS: CarrierSense,
+{
+    radio: &'a mut P2pRadio<RK, DLY, CS>,
+    policy: PowerPolicy,
+    state: PowerState,
+    last_activity: Instant,
+    may_sleep: Option<fn() -> bool>,
+}
+
+impl<'a, RK, DLY, CS> PowerManager<'a, RK, DLY, CS>
+where
+    RK: RadioKind,
+    DLY: DelayUs,
+    CS: CarrierSense,
+{
+    /// Manage the power state of `radio` according to `policy`, starting in the active state.
+    pub fn new(radio: &'a mut P2pRadio<RK, DLY, CS>, policy: PowerPolicy) -> Self {
+        Self {
+            radio,
+            policy,
+            state: PowerState::Active,
+            last_activity: Instant::now(),
+            may_sleep: None,
+        }
+    }
+
+    /// Install a function consulted before entering deep sleep, which is vetoed when it returns false.
+    pub fn set_sleep_veto(&mut self, may_sleep: Option<fn() -> bool>) {
+        self.may_sleep = may_sleep;
+    }
+
+    /// The current power state.
+    pub fn state(&self) -> PowerState {
+        self.state
+    }
+
+    /// Transmit a packet, returning to the active state.
+    pub async fn send(&mut self, config: &LinkConfig, payload: &[u8]) -> Result<(), Error> {
+        self.radio.send(config, payload).await?;
+        self.on_activity();
+        Ok(())
+    }
+
+    /// Listen in the current power state, until a packet is received or the state has to change.
+    ///
+    /// Returns `None` when no packet was received, after a state transition or a wake up from standby or deep
+    /// sleep. Call this in a loop to keep the link up while letting the application run periodically.
+    pub async fn receive(
+        &mut self,
+        config: &LinkConfig,
+        buf: &mut [u8],
+    ) -> Result<Option<(usize, PacketStatus)>, Error> {
+        let received = match self.state {
+            PowerState::Active => {
+                let timeout = self.listen_timeout();
+                with_timeout(timeout, self.radio.receive(config, buf)).await.ok()
+            }
+            PowerState::DutyCycledRx => {
+                let (rx_time_us, sleep_time_us) = unwrap!(self.policy.duty_cycle_us);
+                let timeout = self.listen_timeout();
+                let receive = self.radio.receive_duty_cycle(config, rx_time_us, sleep_time_us, buf);
+                with_timeout(timeout, receive).await.ok()
+            }
+            PowerState::Standby | PowerState::DeepSleep => {
+                self.radio.sleep(self.state == PowerState::Standby).await?;
+                Timer::after(self.policy.wake_period).await;
+                self.radio.cad_receive(config, buf).await.transpose()
+            }
+        };
+
+        match received {
+            Some(result) => {
+                let received = result?;
+                self.on_activity();
+                Ok(Some(received))
+            }
+            None => {
+                // Stop the receive operation cancelled by the timeout
+                self.radio.recover().await?;
+                self.update_state();
+                Ok(None)
+            }
+        }
+    }
+
+    fn on_activity(&mut self) {
+        self.last_activity = Instant::now();
+        self.state = PowerState::Active;
+    }
+
+    /// Time to listen in the current state before checking whether to move to another one.
+    fn listen_timeout(&self) -> Duration {
+        let deadline = match (self.state, self.policy.duty_cycle_us) {
+            (PowerState::Active, Some(_)) => self.last_activity + self.policy.idle_timeout,
+            _ => self.last_activity + self.policy.standby_timeout.unwrap_or(self.policy.sleep_timeout),
+        };
+        match deadline.checked_duration_since(Instant::now()) {
+            Some(timeout) if timeout.as_ticks() > 0 => timeout,
+            // Deep sleep was vetoed without a standby state, check again later
+            _ => self.policy.wake_period,
+        }
+    }
+
+    fn update_state(&mut self) {
+        let idle = Instant::now() - self.last_activity;
+        let may_sleep = self.may_sleep.map_or(true, |may_sleep| may_sleep());
+        let state = if idle >= self.policy.sleep_timeout && may_sleep {
+            PowerState::DeepSleep
+        } else if self.policy.standby_timeout.is_some_and(|timeout| idle >= timeout) {
+            PowerState::Standby
+        } else if idle >= self.policy.idle_timeout && self.policy.duty_cycle_us.is_some() {
+            PowerState::DutyCycledRx
+        } else {
+            PowerState::Active
+        };
+        if state != self.state {
+            debug!("radio power state {:?} -> {:?}", self.state, state);
+            self.state = state;
+        }
+    }
+}
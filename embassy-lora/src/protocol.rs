@@ -0,0 +1,519 @@
+//! Reference point-to-point messaging protocol.
+//!
+//! This module wires the point-to-point building blocks of this crate into a small but complete protocol,
+//! suitable for chat or telemetry between a handful of nodes. It is meant as a working starting point that can
+//! be stripped down or extended to fit an application:
+//!
+//! - nodes have a 16-bit address, and messages are sent to one node or broadcast, using the
+//!   [`datagram`](crate::datagram) header,
+//! - every frame is encrypted and authenticated with AES-128-CCM under a key shared by the network,
+//! - messages longer than a LoRa packet are split into up to 32 fragments,
+//! - unicast messages can request an acknowledgement for each fragment, which is retransmitted until it is
+//!   acknowledged or the retries are exhausted.
+//!
+//! A frame is made of the datagram header, the high half of the message counter, the fragment index and count,
+//! the encrypted fragment and a 4-byte authentication tag. The nonce is derived from the sender address, the
+//! message counter and the fragment index, so the counter of a node must never repeat under the same key. It has
+//! to be persisted across resets, see [`Node::counter`] and [`Node::set_counter`].
+
+use aes::Aes128;
+use ccm::aead::generic_array::GenericArray;
+use ccm::aead::{AeadInPlace, KeyInit};
+use ccm::consts::{U13, U4};
+use ccm::Ccm;
+use embassy_time::{with_timeout, Duration};
+use embedded_hal_async::delay::DelayUs;
+use lora_phy::mod_traits::RadioKind;
+
+use crate::datagram::{self, Header, HEADER_LENGTH};
+use crate::p2p::{self, CarrierSense, LinkConfig, P2pRadio};
+
+type Cipher = Ccm<Aes128, U4, U13>;
+
+const FLAG_ACK_REQUEST: u8 = 0x01;
+const FLAG_ACK: u8 = 0x02;
+
+const PREFIX_LENGTH: usize = HEADER_LENGTH + 4;
+const TAG_LENGTH: usize = 4;
+const MAX_FRAME_LENGTH: usize = 255;
+
+/// Largest payload carried by a single fragment.
+pub const MAX_FRAGMENT_LENGTH: usize = MAX_FRAME_LENGTH - PREFIX_LENGTH - TAG_LENGTH;
+
+/// Largest number of fragments of a message.
+pub const MAX_FRAGMENTS: usize = 32;
+
+/// Largest message that can be sent.
+pub const MAX_MESSAGE_LENGTH: usize = MAX_FRAGMENT_LENGTH * MAX_FRAGMENTS;
+
+/// Number of sources whose message counter is tracked to drop replayed frames.
+pub const MAX_SOURCES: usize = 16;
+
+/// Errors reported by the protocol.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The point-to-point link reported an error.
+    Link(p2p::Error),
+    /// A frame could not be encoded or decoded.
+    Datagram(datagram::Error),
+    /// The frame could not be authenticated with the network key.
+    Authentication,
+    /// The message does not fit in the buffer, or exceeds [`MAX_MESSAGE_LENGTH`].
+    MessageTooLarge,
+    /// A fragment was not acknowledged.
+    NoAck,
+}
+
+impl From<p2p::Error> for Error {
+    fn from(err: p2p::Error) -> Self {
+        Error::Link(err)
+    }
+}
+
+impl From<datagram::Error> for Error {
+    fn from(err: datagram::Error) -> Self {
+        Error::Datagram(err)
+    }
+}
+
+/// Settings of a node.
+#[derive(Clone)]
+pub struct NodeConfig {
+    /// Address of the node.
+    pub address: u16,
+    /// AES-128 key shared by the network.
+    pub key: [u8; 16],
+    /// Time to wait for the acknowledgement of a fragment.
+    pub ack_timeout: Duration,
+    /// Number of retransmissions of a fragment that is not acknowledged.
+    pub retries: u8,
+}
+
+/// A decoded frame.
+#[derive(Debug, PartialEq)]
+struct Frame<'a> {
+    header: Header,
+    counter: u32,
+    index: u8,
+    count: u8,
+    payload: &'a [u8],
+}
+
+fn nonce(source: u16, counter: u32, index: u8) -> [u8; 13] {
+    let mut nonce = [0; 13];
+    nonce[0..2].copy_from_slice(&source.to_le_bytes());
+    nonce[2..6].copy_from_slice(&counter.to_le_bytes());
+    nonce[6] = index;
+    nonce
+}
+
+/// Encrypt a fragment into `out`, and return the length of the frame.
+fn seal(
+    cipher: &Cipher,
+    header: &Header,
+    counter: u32,
+    index: u8,
+    count: u8,
+    payload: &[u8],
+    out: &mut [u8],
+) -> Result<usize, Error> {
+    let len = PREFIX_LENGTH + payload.len() + TAG_LENGTH;
+    if payload.len() > MAX_FRAGMENT_LENGTH || out.len() < len {
+        return Err(Error::MessageTooLarge);
+    }
+    header.encode(&[], out)?;
+    out[HEADER_LENGTH..HEADER_LENGTH + 2].copy_from_slice(&((counter >> 16) as u16).to_le_bytes());
+    out[HEADER_LENGTH + 2] = index;
+    out[HEADER_LENGTH + 3] = count;
+
+    let (prefix, rest) = out.split_at_mut(PREFIX_LENGTH);
+    let (data, tag) = rest.split_at_mut(payload.len());
+    data.copy_from_slice(payload);
+    let nonce = nonce(header.source, counter, index);
+    let computed = cipher
+        .encrypt_in_place_detached(GenericArray::from_slice(&nonce), prefix, data)
+        .map_err(|_| Error::MessageTooLarge)?;
+    tag[..TAG_LENGTH].copy_from_slice(&computed);
+    Ok(len)
+}
+
+/// Authenticate and decrypt a frame in place.
+fn open<'a>(cipher: &Cipher, frame: &'a mut [u8]) -> Result<Frame<'a>, Error> {
+    if frame.len() < PREFIX_LENGTH + TAG_LENGTH {
+        return Err(Error::Datagram(datagram::Error::Truncated));
+    }
+    let (header, _) = Header::decode(frame)?;
+    let epoch = u16::from_le_bytes([frame[HEADER_LENGTH], frame[HEADER_LENGTH + 1]]);
+    let counter = (epoch as u32) << 16 | header.sequence as u32;
+    let (index, count) = (frame[HEADER_LENGTH + 2], frame[HEADER_LENGTH + 3]);
+
+    let tag_start = frame.len() - TAG_LENGTH;
+    let (rest, tag) = frame.split_at_mut(tag_start);
+    let (prefix, data) = rest.split_at_mut(PREFIX_LENGTH);
+    let nonce = nonce(header.source, counter, index);
+    cipher
+        .decrypt_in_place_detached(
+            GenericArray::from_slice(&nonce),
+            prefix,
+            data,
+            GenericArray::from_slice(tag),
+        )
+        .map_err(|_| Error::Authentication)?;
+    Ok(Frame {
+        header,
+        counter,
+        index,
+        count,
+        payload: data,
+    })
+}
+
+/// Reassembly state of the message being received.
+#[derive(Debug, Default)]
+struct Reassembly {
+    source: u16,
+    counter: u32,
+    count: u8,
+    received: u32,
+    len: usize,
+}
+
+impl Reassembly {
+    /// Store a fragment in `buf`, and return the length of the message once all its fragments are received.
+    fn add(&mut self, frame: &Frame, buf: &mut [u8]) -> Result<Option<usize>, Error> {
+        if frame.count == 0 || frame.count as usize > MAX_FRAGMENTS || frame.index >= frame.count {
+            return Err(Error::MessageTooLarge);
+        }
+        if (frame.header.source, frame.counter) != (self.source, self.counter) || self.count == 0 {
+            *self = Reassembly {
+                source: frame.header.source,
+                counter: frame.counter,
+                count: frame.count,
+                received: 0,
+                len: 0,
+            };
+        }
+
+        let offset = frame.index as usize * MAX_FRAGMENT_LENGTH;
+        let end = offset + frame.payload.len();
+        if end > buf.len() {
+            return Err(Error::MessageTooLarge);
+        }
+        buf[offset..end].copy_from_slice(frame.payload);
+        self.received |= 1 << frame.index;
+        if frame.index == frame.count - 1 {
+            self.len = end;
+        }
+
+        if self.received.count_ones() == self.count as u32 {
+            self.count = 0;
+            Ok(Some(self.len))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// Highest message counter delivered from each source, the least recently delivered source being forgotten
+/// first when the table is full.
+#[derive(Debug, Default)]
+struct ReplayTable {
+    entries: [Option<ReplayEntry>; MAX_SOURCES],
+    deliveries: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ReplayEntry {
+    source: u16,
+    counter: u32,
+    delivery: u32,
+}
+
+impl ReplayTable {
+    /// Highest counter delivered from `source`, if it is tracked.
+    fn highest(&self, source: u16) -> Option<u32> {
+        self.entries
+            .iter()
+            .flatten()
+            .find(|entry| entry.source == source)
+            .map(|entry| entry.counter)
+    }
+
+    /// Record the delivery of the message `counter` from `source`.
+    fn delivered(&mut self, source: u16, counter: u32) {
+        self.deliveries = self.deliveries.wrapping_add(1);
+        let deliveries = self.deliveries;
+        let slot = match self.entries.iter().position(|e| e.is_some_and(|e| e.source == source)) {
+            Some(index) => index,
+            None => match self.entries.iter().position(Option::is_none) {
+                Some(index) => index,
+                None => unwrap!(self
+                    .entries
+                    .iter()
+                    .flatten()
+                    .enumerate()
+                    .max_by_key(|(_, entry)| deliveries.wrapping_sub(entry.delivery))
+                    .map(|(index, _)| index)),
+            },
+        };
+        self.entries[slot] = Some(ReplayEntry {
+            source,
+            counter,
+            delivery: deliveries,
+        });
+    }
+}
+
+/// A node of the network.
+pub struct Node<'a, RK, DLY, CS>
+where
+    RK: RadioKind,
+    DLY: DelayUs,
+    CS: CarrierSense,
+{
+    radio: &'a mut P2pRadio<RK, DLY, CS>,
+    link: LinkConfig,
+    address: u16,
+    cipher: Cipher,
+    ack_timeout: Duration,
+    retries: u8,
+    counter: u32,
+    reassembly: Reassembly,
+    replay: ReplayTable,
+}
+
+impl<'a, RK, DLY, CS> Node<'a, RK, DLY, CS>
+where
+    RK: RadioKind,
+    DLY: DelayUs,
+    CS: CarrierSense,
+{
+    /// Create a node communicating over `radio` with the settings of `link`.
+    pub fn new(radio: &'a mut P2pRadio<RK, DLY, CS>, link: LinkConfig, config: &NodeConfig) -> Self {
+        Self {
+            radio,
+            link,
+            address: config.address,
+            cipher: Cipher::new(GenericArray::from_slice(&config.key)),
+            ack_timeout: config.ack_timeout,
+            retries: config.retries,
+            counter: 0,
+            reassembly: Reassembly::default(),
+            replay: ReplayTable::default(),
+        }
+    }
+
+    /// Counter of the next message sent, to be persisted before the node is reset.
+    pub fn counter(&self) -> u32 {
+        self.counter
+    }
+
+    /// Restore the message counter, e.g. from flash after a reset.
+    pub fn set_counter(&mut self, counter: u32) {
+        self.counter = counter;
+    }
+
+    /// Send a message to `destination`, or to every node with [`Header::BROADCAST`].
+    ///
+    /// With `ack`, every fragment of a unicast message is retransmitted until the destination acknowledges it.
+    pub async fn send(&mut self, destination: u16, message: &[u8], ack: bool) -> Result<(), Error> {
+        if message.len() > MAX_MESSAGE_LENGTH {
+            return Err(Error::MessageTooLarge);
+        }
+        let ack = ack && destination != Header::BROADCAST;
+        let counter = self.next_counter();
+        let header = Header {
+            flags: if ack { FLAG_ACK_REQUEST } else { 0 },
+            source: self.address,
+            destination,
+            sequence: counter as u16,
+        };
+
+        let count = message.len().div_ceil(MAX_FRAGMENT_LENGTH).max(1) as u8;
+        let mut frame = [0; MAX_FRAME_LENGTH];
+        for (index, fragment) in (0..count).zip(message.chunks(MAX_FRAGMENT_LENGTH).chain(core::iter::once(&[][..]))) {
+            let len = seal(&self.cipher, &header, counter, index, count, fragment, &mut frame)?;
+            self.send_fragment(&frame[..len], ack, counter, index).await?;
+        }
+        Ok(())
+    }
+
+    /// Wait for a message addressed to this node or broadcast, and return its source and length.
+    ///
+    /// Frames that cannot be authenticated are dropped, and so are frames whose counter is not above the highest
+    /// one delivered from their source, which covers both retransmissions and replays. Retransmitted fragments of
+    /// the last message delivered from a source are still acknowledged, as the sender may have missed the
+    /// acknowledgement. The counters of the last [`MAX_SOURCES`] sources delivered from are kept in RAM, so
+    /// frames of a forgotten source, or recorded before a reset, are accepted once again.
+    pub async fn receive(&mut self, buf: &mut [u8]) -> Result<(u16, usize), Error> {
+        let mut frame_buf = [0; MAX_FRAME_LENGTH];
+        loop {
+            let (len, _) = self.radio.receive(&self.link, &mut frame_buf).await?;
+            let frame = match open(&self.cipher, &mut frame_buf[..len]) {
+                Ok(frame) => frame,
+                Err(_) => {
+                    trace!("dropping invalid frame");
+                    continue;
+                }
+            };
+            let (header, counter, index) = (frame.header, frame.counter, frame.index);
+            if header.flags & FLAG_ACK != 0
+                || (header.destination != self.address && header.destination != Header::BROADCAST)
+            {
+                continue;
+            }
+
+            let highest = self.replay.highest(header.source);
+            let fresh = highest.map_or(true, |highest| counter > highest);
+            if !fresh && highest != Some(counter) {
+                trace!("dropping replayed frame from {}", header.source);
+                continue;
+            }
+            let complete = if fresh { self.reassembly.add(&frame, buf)? } else { None };
+            if header.flags & FLAG_ACK_REQUEST != 0 {
+                self.send_ack(header.source, counter, index).await?;
+            }
+            if let Some(len) = complete {
+                self.replay.delivered(header.source, counter);
+                return Ok((header.source, len));
+            }
+        }
+    }
+
+    fn next_counter(&mut self) -> u32 {
+        let counter = self.counter;
+        self.counter = self.counter.wrapping_add(1);
+        counter
+    }
+
+    async fn send_fragment(&mut self, frame: &[u8], ack: bool, counter: u32, index: u8) -> Result<(), Error> {
+        if !ack {
+            self.radio.send(&self.link, frame).await?;
+            return Ok(());
+        }
+        for _ in 0..=self.retries {
+            self.radio.send(&self.link, frame).await?;
+            if let Ok(result) = with_timeout(self.ack_timeout, self.wait_ack(counter, index)).await {
+                return result;
+            }
+            debug!("no acknowledgement for fragment {}", index);
+        }
+        Err(Error::NoAck)
+    }
+
+    async fn wait_ack(&mut self, counter: u32, index: u8) -> Result<(), Error> {
+        let mut frame_buf = [0; MAX_FRAME_LENGTH];
+        loop {
+            let (len, _) = self.radio.receive(&self.link, &mut frame_buf).await?;
+            if let Ok(frame) = open(&self.cipher, &mut frame_buf[..len]) {
+                if frame.header.flags & FLAG_ACK != 0
+                    && frame.header.destination == self.address
+                    && frame.payload == ack_payload(counter, index)
+                {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    async fn send_ack(&mut self, destination: u16, counter: u32, index: u8) -> Result<(), Error> {
+        let ack_counter = self.next_counter();
+        let header = Header {
+            flags: FLAG_ACK,
+            source: self.address,
+            destination,
+            sequence: ack_counter as u16,
+        };
+        let mut frame = [0; MAX_FRAME_LENGTH];
+        let payload = ack_payload(counter, index);
+        let len = seal(&self.cipher, &header, ack_counter, 0, 1, &payload, &mut frame)?;
+        self.radio.send(&self.link, &frame[..len]).await?;
+        Ok(())
+    }
+}
+
+fn ack_payload(counter: u32, index: u8) -> [u8; 5] {
+    let mut payload = [0; 5];
+    payload[..4].copy_from_slice(&counter.to_le_bytes());
+    payload[4] = index;
+    payload
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: Header = Header {
+        flags: FLAG_ACK_REQUEST,
+        source: 1,
+        destination: 2,
+        sequence: 0x0003,
+    };
+
+    #[test]
+    fn seal_and_open() {
+        let cipher = Cipher::new(GenericArray::from_slice(&[0x2b; 16]));
+        let mut frame = [0; MAX_FRAME_LENGTH];
+        let len = seal(&cipher, &HEADER, 0x0004_0003, 1, 2, b"hello", &mut frame).unwrap();
+        assert_eq!(len, PREFIX_LENGTH + 5 + TAG_LENGTH);
+        assert_ne!(&frame[PREFIX_LENGTH..PREFIX_LENGTH + 5], b"hello");
+
+        let mut tampered = frame;
+        tampered[3] ^= 1;
+        assert_eq!(open(&cipher, &mut tampered[..len]), Err(Error::Authentication));
+
+        let frame = open(&cipher, &mut frame[..len]).unwrap();
+        assert_eq!(frame.header, HEADER);
+        assert_eq!((frame.counter, frame.index, frame.count), (0x0004_0003, 1, 2));
+        assert_eq!(frame.payload, b"hello");
+    }
+
+    #[test]
+    fn reassembly_out_of_order() {
+        let mut buf = [0; MAX_FRAGMENT_LENGTH + 3];
+        let first = [0xaa; MAX_FRAGMENT_LENGTH];
+        let fragment = |index, payload| Frame {
+            header: HEADER,
+            counter: 3,
+            index,
+            count: 2,
+            payload,
+        };
+
+        let mut reassembly = Reassembly::default();
+        assert_eq!(reassembly.add(&fragment(1, b"end"), &mut buf), Ok(None));
+        // A retransmitted fragment is only stored once
+        assert_eq!(reassembly.add(&fragment(1, b"end"), &mut buf), Ok(None));
+        assert_eq!(
+            reassembly.add(&fragment(0, &first), &mut buf),
+            Ok(Some(MAX_FRAGMENT_LENGTH + 3))
+        );
+        assert_eq!(&buf[MAX_FRAGMENT_LENGTH..], b"end");
+
+        let mut small = [0; 8];
+        assert_eq!(
+            reassembly.add(&fragment(0, &first), &mut small),
+            Err(Error::MessageTooLarge)
+        );
+    }
+
+    #[test]
+    fn replay_table() {
+        let mut replay = ReplayTable::default();
+        assert_eq!(replay.highest(1), None);
+        replay.delivered(1, 10);
+        replay.delivered(1, 12);
+        assert_eq!(replay.highest(1), Some(12));
+
+        // The least recently delivered source is forgotten first
+        for source in 2..=MAX_SOURCES as u16 {
+            replay.delivered(source, 0);
+        }
+        replay.delivered(1, 13);
+        replay.delivered(100, 0);
+        assert_eq!(replay.highest(1), Some(13));
+        assert_eq!(replay.highest(2), None);
+        assert_eq!(replay.highest(100), Some(0));
+    }
+}
@@ -0,0 +1,366 @@
+//! Store-and-forward queue for outbound messages.
+//!
+//! Battery powered sensors often generate data while the radio cannot transmit: it is busy with another
+//! exchange, asleep, or out of duty cycle budget. [`OutboundQueue`] holds those messages in RAM until a
+//! [`Sink`] accepts them. The queue can be placed in a `static` and shared between the tasks producing
+//! messages and the task owning the radio.
+
+use core::cell::RefCell;
+use core::future::poll_fn;
+use core::task::Poll;
+
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::waitqueue::WakerRegistration;
+
+/// Destination of queued messages, typically a radio.
+pub trait Sink {
+    /// Error returned when a message could not be sent.
+    type Error;
+
+    /// Send one message.
+    ///
+    /// Returning an error leaves the message in the queue, so it is retried on the next flush. A sink that is
+    /// not allowed to transmit yet, e.g. because its duty cycle budget is exhausted, should return an error
+    /// without touching the radio. Sinks keeping a duty cycle budget in reserve for alarms can use `priority`
+    /// to decide whether a message may draw from it, e.g. with `DutyCycle::is_allowed_with_priority` after
+    /// `DutyCycle::set_high_priority_reserve`.
+    async fn send(&mut self, priority: Priority, payload: &[u8]) -> Result<(), Self::Error>;
+}
+
+/// Priority class of a queued message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Priority {
+    /// Periodic reports and other messages that can wait.
+    Routine,
+    /// Alarms, sent before any routine message regardless of queue order.
+    High,
+}
+
+/// What to do when a message is pushed to a full queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum OverflowPolicy {
+    /// Discard the oldest queued message to make room, keeping the most recent data.
+    DropOldest,
+    /// Discard the message being pushed.
+    DropNewest,
+}
+
+/// Errors returned when pushing a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PushError {
+    /// The message is longer than the queue's maximum message size.
+    TooLarge,
+    /// The queue is full and the overflow policy is [`OverflowPolicy::DropNewest`].
+    Full,
+}
+
+struct Entry<const MTU: usize> {
+    used: bool,
+    priority: Priority,
+    seq: u32,
+    len: usize,
+    data: [u8; MTU],
+}
+
+impl<const MTU: usize> Entry<MTU> {
+    const EMPTY: Self = Self {
+        used: false,
+        priority: Priority::Routine,
+        seq: 0,
+        len: 0,
+        data: [0; MTU],
+    };
+}
+
+struct State<const N: usize, const MTU: usize> {
+    entries: [Entry<MTU>; N],
+    next_seq: u32,
+    dropped: u32,
+    waker: WakerRegistration,
+}
+
+impl<const N: usize, const MTU: usize> State<N, MTU> {
+    fn count(&self, priority: Option<Priority>) -> usize {
+        self.entries
+            .iter()
+            .filter(|e| e.used && priority.map_or(true, |p| e.priority == p))
+            .count()
+    }
+
+    /// Index of the oldest message of the given priority.
+    fn oldest(&self, priority: Priority) -> Option<usize> {
+        // Sequence numbers are compared relative to the next one to stay correct when they wrap.
+        let next_seq = self.next_seq;
+        self.entries
+            .iter()
+            .enumerate()
+            .filter(|(_, e)| e.used && e.priority == priority)
+            .max_by_key(|(_, e)| next_seq.wrapping_sub(e.seq))
+            .map(|(i, _)| i)
+    }
+
+    /// Index of the next message to send.
+    fn front(&self) -> Option<usize> {
+        self.oldest(Priority::High).or_else(|| self.oldest(Priority::Routine))
+    }
+}
+
+/// A fixed-capacity queue of up to `N` messages of at most `MTU` bytes each.
+///
+/// Messages are sent oldest first, except that [`Priority::High`] messages always go before routine ones. A
+/// number of slots can be reserved for high priority messages so that routine traffic piling up during a long
+/// outage can never prevent an alarm from being queued. A high priority message pushed to a full queue
+/// replaces a routine message, whatever the overflow policy.
+pub struct OutboundQueue<M: RawMutex, const N: usize, const MTU: usize> {
+    policy: OverflowPolicy,
+    reserved: usize,
+    state: Mutex<M, RefCell<State<N, MTU>>>,
+}
+
+impl<M: RawMutex, const N: usize, const MTU: usize> OutboundQueue<M, N, MTU> {
+    /// Create an empty queue.
+    pub const fn new(policy: OverflowPolicy) -> Self {
+        Self::with_reserved_slots(policy, 0)
+    }
+
+    /// Create an empty queue keeping `reserved` slots for high priority messages.
+    pub const fn with_reserved_slots(policy: OverflowPolicy, reserved: usize) -> Self {
+        core::assert!(reserved <= N);
+        Self {
+            policy,
+            reserved,
+            state: Mutex::new(RefCell::new(State {
+                entries: [Entry::<MTU>::EMPTY; N],
+                next_seq: 0,
+                dropped: 0,
+                waker: WakerRegistration::new(),
+            })),
+        }
+    }
+
+    /// Add a routine message to the queue.
+    pub fn push(&self, payload: &[u8]) -> Result<(), PushError> {
+        self.push_with_priority(Priority::Routine, payload)
+    }
+
+    /// Add a message of the given priority to the queue.
+    pub fn push_with_priority(&self, priority: Priority, payload: &[u8]) -> Result<(), PushError> {
+        if payload.len() > MTU {
+            return Err(PushError::TooLarge);
+        }
+        self.state.lock(|s| {
+            let mut s = s.borrow_mut();
+            let full = match priority {
+                Priority::High => s.count(None) == N,
+                Priority::Routine => s.count(None) == N || s.count(Some(Priority::Routine)) >= N - self.reserved,
+            };
+            if full {
+                let victim = match (priority, self.policy) {
+                    (Priority::High, OverflowPolicy::DropOldest) => {
+                        s.oldest(Priority::Routine).or_else(|| s.oldest(Priority::High))
+                    }
+                    (Priority::High, OverflowPolicy::DropNewest) => s.oldest(Priority::Routine),
+                    (Priority::Routine, OverflowPolicy::DropOldest) => s.oldest(Priority::Routine),
+                    (Priority::Routine, OverflowPolicy::DropNewest) => None,
+                };
+                s.dropped = s.dropped.wrapping_add(1);
+                match victim {
+                    Some(i) => s.entries[i].used = false,
+                    None => return Err(PushError::Full),
+                }
+            }
+
+            let index = unwrap!(s.entries.iter().position(|e| !e.used));
+            let seq = s.next_seq;
+            s.next_seq = seq.wrapping_add(1);
+            let entry = &mut s.entries[index];
+            entry.used = true;
+            entry.priority = priority;
+            entry.seq = seq;
+            entry.len = payload.len();
+            entry.data[..payload.len()].copy_from_slice(payload);
+            s.waker.wake();
+            Ok(())
+        })
+    }
+
+    /// Number of queued messages.
+    pub fn len(&self) -> usize {
+        self.state.lock(|s| s.borrow().count(None))
+    }
+
+    /// Returns true if no message is queued.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of messages discarded because the queue was full.
+    pub fn dropped(&self) -> u32 {
+        self.state.lock(|s| s.borrow().dropped)
+    }
+
+    /// Discard all queued messages.
+    pub fn clear(&self) {
+        self.state.lock(|s| {
+            for entry in s.borrow_mut().entries.iter_mut() {
+                entry.used = false;
+            }
+        })
+    }
+
+    /// Wait until at least one message is queued.
+    pub async fn wait_pending(&self) {
+        poll_fn(|cx| {
+            self.state.lock(|s| {
+                let mut s = s.borrow_mut();
+                if s.count(None) > 0 {
+                    Poll::Ready(())
+                } else {
+                    s.waker.register(cx.waker());
+                    Poll::Pending
+                }
+            })
+        })
+        .await
+    }
+
+    /// Copy the next message to send out of the queue without removing it.
+    pub(crate) fn peek(&self, buf: &mut [u8; MTU]) -> Option<(u32, Priority, usize)> {
+        self.state.lock(|s| {
+            let s = s.borrow();
+            s.front().map(|i| {
+                let entry = &s.entries[i];
+                buf[..entry.len].copy_from_slice(&entry.data[..entry.len]);
+                (entry.seq, entry.priority, entry.len)
+            })
+        })
+    }
+
+    /// Remove the message identified by `seq`, unless it was already dropped.
+    pub(crate) fn remove(&self, seq: u32) {
+        self.state.lock(|s| {
+            for entry in s.borrow_mut().entries.iter_mut() {
+                if entry.used && entry.seq == seq {
+                    entry.used = false;
+                }
+            }
+        })
+    }
+
+    /// Send queued messages until the queue is empty or the sink returns an error.
+    ///
+    /// Returns the number of messages sent. Messages pushed while flushing are sent as part of the same flush,
+    /// and a high priority message pushed meanwhile goes before the remaining routine ones.
+    pub async fn flush<S: Sink>(&self, sink: &mut S) -> Result<usize, S::Error> {
+        let mut buf = [0; MTU];
+        let mut sent = 0;
+        while let Some((seq, priority, len)) = self.peek(&mut buf) {
+            sink.send(priority, &buf[..len]).await?;
+            self.remove(seq);
+            sent += 1;
+        }
+        Ok(sent)
+    }
+}
+
+/// Sink recording up to 8 messages of `LEN` bytes, refusing them once its budget is spent.
+#[cfg(test)]
+pub(crate) struct TestSink<const LEN: usize> {
+    pub(crate) sent: [[u8; LEN]; 8],
+    pub(crate) count: usize,
+    pub(crate) budget: usize,
+}
+
+#[cfg(test)]
+impl<const LEN: usize> TestSink<LEN> {
+    pub(crate) fn new(budget: usize) -> Self {
+        Self {
+            sent: [[0; LEN]; 8],
+            count: 0,
+            budget,
+        }
+    }
+}
+
+#[cfg(test)]
+impl<const LEN: usize> Sink for TestSink<LEN> {
+    type Error = ();
+
+    async fn send(&mut self, _priority: Priority, payload: &[u8]) -> Result<(), ()> {
+        if self.budget == 0 {
+            return Err(());
+        }
+        self.budget -= 1;
+        self.sent[self.count].copy_from_slice(payload);
+        self.count += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+    use futures_executor::block_on;
+
+    use super::*;
+
+    #[test]
+    fn flush_in_order_until_sink_refuses() {
+        let queue: OutboundQueue<NoopRawMutex, 4, 2> = OutboundQueue::new(OverflowPolicy::DropOldest);
+        for i in 0..3u8 {
+            queue.push(&[i, i]).unwrap();
+        }
+        assert_eq!(queue.push(&[0, 1, 2]), Err(PushError::TooLarge));
+
+        let mut sink = TestSink::<2>::new(2);
+        assert_eq!(block_on(queue.flush(&mut sink)), Err(()));
+        assert_eq!(&sink.sent[..2], &[[0, 0], [1, 1]]);
+        assert_eq!(queue.len(), 1);
+
+        sink.budget = 8;
+        assert_eq!(block_on(queue.flush(&mut sink)), Ok(1));
+        assert_eq!(sink.sent[2], [2, 2]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn overflow_policies() {
+        let queue: OutboundQueue<NoopRawMutex, 2, 1> = OutboundQueue::new(OverflowPolicy::DropOldest);
+        for i in 0..3u8 {
+            queue.push(&[i]).unwrap();
+        }
+        assert_eq!(queue.dropped(), 1);
+        let mut buf = [0; 1];
+        assert_eq!(queue.peek(&mut buf).map(|(_, _, len)| len), Some(1));
+        assert_eq!(buf, [1]);
+
+        let queue: OutboundQueue<NoopRawMutex, 2, 1> = OutboundQueue::new(OverflowPolicy::DropNewest);
+        queue.push(&[0]).unwrap();
+        queue.push(&[1]).unwrap();
+        assert_eq!(queue.push(&[2]), Err(PushError::Full));
+        queue.peek(&mut buf);
+        assert_eq!(buf, [0]);
+        assert_eq!(queue.dropped(), 1);
+    }
+
+    #[test]
+    fn high_priority_goes_first_and_uses_reserved_slots() {
+        let queue: OutboundQueue<NoopRawMutex, 3, 2> =
+            OutboundQueue::with_reserved_slots(OverflowPolicy::DropNewest, 1);
+        queue.push(&[0, 0]).unwrap();
+        queue.push(&[1, 1]).unwrap();
+        assert_eq!(queue.push(&[2, 2]), Err(PushError::Full));
+        queue.push_with_priority(Priority::High, &[9, 9]).unwrap();
+        // A second alarm replaces the oldest routine message
+        queue.push_with_priority(Priority::High, &[8, 8]).unwrap();
+        assert_eq!(queue.dropped(), 2);
+
+        let mut sink = TestSink::<2>::new(8);
+        assert_eq!(block_on(queue.flush(&mut sink)), Ok(3));
+        assert_eq!(&sink.sent[..3], &[[9, 9], [8, 8], [1, 1]]);
+    }
+}
@@ -0,0 +1,221 @@
+//! LoRaWAN region selected at runtime.
+//!
+//! A [`RegionConfig`] is built from a [`Region`] value, which can be parsed from a configuration string such as
+//! `"US915"`, so that one firmware image can ship to every region. It builds the lorawan-device region
+//! configuration of the MAC with [`RegionConfig::configuration`], which owns the channel plan, and maps the data
+//! rates and TX power indices for the join backoff and ADR.
+//!
+//! US915 and AU915 gateways usually only listen on one sub-band of 8 channels, selected with
+//! [`RegionConfig::with_subband`].
+
+use core::str::FromStr;
+
+use lora_phy::mod_params::{Bandwidth, SpreadingFactor};
+
+use crate::adr::AdrLimits;
+pub use crate::regulatory::Region;
+
+/// Errors reported when selecting a region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The region name is not known.
+    UnknownRegion,
+    /// The region has no sub-bands, or the sub-band is not between 1 and 8.
+    InvalidSubband,
+    /// The region is not supported by lorawan-device.
+    UnsupportedRegion,
+}
+
+impl FromStr for Region {
+    type Err = Error;
+
+    /// Parse a region from its name in the LoRaWAN regional parameters, e.g. `"EU868"`, ignoring case.
+    fn from_str(name: &str) -> Result<Self, Error> {
+        [
+            ("EU868", Region::Eu868),
+            ("US915", Region::Us915),
+            ("AU915", Region::Au915),
+            ("AS923", Region::As923),
+            ("IN865", Region::In865),
+            ("KR920", Region::Kr920),
+        ]
+        .into_iter()
+        .find(|(region_name, _)| region_name.eq_ignore_ascii_case(name))
+        .map(|(_, region)| region)
+        .ok_or(Error::UnknownRegion)
+    }
+}
+
+/// Region of a LoRaWAN device, selected at runtime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RegionConfig {
+    region: Region,
+    subband: Option<u8>,
+}
+
+impl RegionConfig {
+    /// Select `region`, with every channel enabled.
+    pub const fn new(region: Region) -> Self {
+        Self { region, subband: None }
+    }
+
+    /// Restrict US915 or AU915 to one sub-band, from 1 to 8.
+    pub fn with_subband(self, subband: u8) -> Result<Self, Error> {
+        match self.region {
+            Region::Us915 | Region::Au915 if (1..=8).contains(&subband) => Ok(Self {
+                subband: Some(subband),
+                ..self
+            }),
+            _ => Err(Error::InvalidSubband),
+        }
+    }
+
+    /// The region.
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    /// The sub-band the channels are restricted to, if any.
+    pub fn subband(&self) -> Option<u8> {
+        self.subband
+    }
+
+    /// The lorawan-device region, or `None` if lorawan-device does not support the region.
+    #[cfg(feature = "time")]
+    pub fn mac_region(&self) -> Option<lorawan_device::region::Region> {
+        use lorawan_device::region::Region as MacRegion;
+
+        match self.region {
+            Region::Eu868 => Some(MacRegion::EU868),
+            Region::Us915 => Some(MacRegion::US915),
+            Region::Au915 => Some(MacRegion::AU915),
+            Region::As923 => Some(MacRegion::AS923_1),
+            Region::In865 => Some(MacRegion::IN865),
+            Region::Kr920 => None,
+        }
+    }
+
+    /// The configuration of the lorawan-device MAC for the region and its sub-band.
+    #[cfg(feature = "time")]
+    pub fn configuration(&self) -> Result<lorawan_device::region::Configuration, Error> {
+        let mut configuration =
+            lorawan_device::region::Configuration::new(self.mac_region().ok_or(Error::UnsupportedRegion)?);
+        if let Some(subband) = self.subband {
+            configuration.set_subband(subband);
+        }
+        Ok(configuration)
+    }
+
+    /// Number of uplink channels defined by the region.
+    fn channels(&self) -> u8 {
+        match self.region {
+            Region::Us915 | Region::Au915 => 72,
+            Region::Eu868 | Region::As923 | Region::In865 | Region::Kr920 => 16,
+        }
+    }
+
+    /// Modulation of a LoRa data rate, or `None` if the data rate is not a LoRa one in the region.
+    pub fn data_rate(&self, data_rate: u8) -> Option<(SpreadingFactor, Bandwidth)> {
+        use Bandwidth::{_125KHz, _250KHz, _500KHz};
+
+        let sf = |sf: u8| match sf {
+            7 => SpreadingFactor::_7,
+            8 => SpreadingFactor::_8,
+            9 => SpreadingFactor::_9,
+            10 => SpreadingFactor::_10,
+            11 => SpreadingFactor::_11,
+            _ => SpreadingFactor::_12,
+        };
+        match (self.region, data_rate) {
+            (Region::Us915, 0..=3) => Some((sf(10 - data_rate), _125KHz)),
+            (Region::Us915, 4) => Some((sf(8), _500KHz)),
+            (Region::Au915, 0..=5) => Some((sf(12 - data_rate), _125KHz)),
+            (Region::Au915, 6) => Some((sf(8), _500KHz)),
+            (Region::Us915 | Region::Au915, 8..=13) => Some((sf(20 - data_rate), _500KHz)),
+            (Region::Eu868 | Region::As923 | Region::In865 | Region::Kr920, 0..=5) => {
+                Some((sf(12 - data_rate), _125KHz))
+            }
+            (Region::Eu868 | Region::As923, 6) => Some((sf(7), _250KHz)),
+            _ => None,
+        }
+    }
+
+    /// Output power of a TX power index in dBm, or `None` if the index is not defined in the region.
+    ///
+    /// Index 0 is the maximum EIRP of the region, and each step lowers it by 2 dB.
+    pub fn tx_power_dbm(&self, tx_power: u8) -> Option<i32> {
+        let (max_eirp_dbm, max_index) = match self.region {
+            Region::Eu868 => (16, 7),
+            Region::Us915 | Region::Au915 => (30, 14),
+            Region::As923 => (16, 7),
+            Region::In865 => (30, 10),
+            Region::Kr920 => (14, 7),
+        };
+        (tx_power <= max_index).then_some(max_eirp_dbm - 2 * tx_power as i32)
+    }
+
+    /// Limits of the uplink settings, to configure [`Adr`](crate::adr::Adr).
+    pub fn adr_limits(&self) -> AdrLimits {
+        let (max_data_rate, max_tx_power) = match self.region {
+            Region::Eu868 | Region::As923 | Region::Kr920 => (5, 7),
+            Region::In865 => (5, 10),
+            Region::Us915 => (4, 14),
+            Region::Au915 => (6, 14),
+        };
+        AdrLimits {
+            min_data_rate: 0,
+            max_data_rate,
+            max_tx_power,
+            channels: self.channels(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runtime_selection() {
+        let region: Region = "us915".parse().unwrap();
+        assert_eq!("XX123".parse::<Region>(), Err(Error::UnknownRegion));
+        assert_eq!(
+            RegionConfig::new(Region::Eu868).with_subband(2),
+            Err(Error::InvalidSubband)
+        );
+
+        let config = RegionConfig::new(region).with_subband(2).unwrap();
+        assert_eq!(config.subband(), Some(2));
+        assert_eq!(config.data_rate(0), Some((SpreadingFactor::_10, Bandwidth::_125KHz)));
+        assert_eq!(config.adr_limits().channels, 72);
+
+        let config = RegionConfig::new(Region::Eu868);
+        assert_eq!(config.data_rate(0), Some((SpreadingFactor::_12, Bandwidth::_125KHz)));
+        assert_eq!(config.tx_power_dbm(7), Some(2));
+        assert_eq!(config.tx_power_dbm(8), None);
+    }
+
+    #[cfg(feature = "time")]
+    #[test]
+    fn mac_configuration() {
+        use lorawan_device::region::Region as MacRegion;
+
+        let config = RegionConfig::new(Region::Us915).with_subband(2).unwrap();
+        assert!(matches!(config.mac_region(), Some(MacRegion::US915)));
+        assert!(config.configuration().is_ok());
+        assert!(matches!(
+            RegionConfig::new(Region::Eu868).mac_region(),
+            Some(MacRegion::EU868)
+        ));
+        assert!(matches!(
+            RegionConfig::new(Region::As923).mac_region(),
+            Some(MacRegion::AS923_1)
+        ));
+        assert!(matches!(
+            RegionConfig::new(Region::Kr920).configuration(),
+            Err(Error::UnsupportedRegion)
+        ));
+    }
+}
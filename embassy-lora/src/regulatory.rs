@@ -0,0 +1,200 @@
+//! Regulatory limits for sub-GHz ISM bands.
+//!
+//! The tables cover the limits the LoRaWAN regional parameters are built on. They are meant to catch obvious
+//! mistakes during development, they are not a substitute for checking the regulations that apply to a product.
+//! Power limits are expressed as the output power requested from the radio, so antenna gain has to be
+//! accounted for by the application.
+
+/// A contiguous frequency range with uniform limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SubBand {
+    /// Lowest frequency of the band in Hz.
+    pub start_hz: u32,
+    /// Highest frequency of the band in Hz.
+    pub end_hz: u32,
+    /// Maximum output power in dBm.
+    pub max_power_dbm: i32,
+    /// Maximum duty cycle in permille, 1000 if transmissions are not duty cycle limited.
+    pub duty_cycle_permille: u16,
+}
+
+impl SubBand {
+    const fn new(start_hz: u32, end_hz: u32, max_power_dbm: i32, duty_cycle_permille: u16) -> Self {
+        Self {
+            start_hz,
+            end_hz,
+            max_power_dbm,
+            duty_cycle_permille,
+        }
+    }
+
+    /// Returns true if a channel of the given width centered on `frequency_in_hz` lies within the band.
+    pub fn contains(&self, frequency_in_hz: u32, bandwidth_in_hz: u32) -> bool {
+        let half = bandwidth_in_hz / 2;
+        frequency_in_hz.saturating_sub(half) >= self.start_hz && frequency_in_hz.saturating_add(half) <= self.end_hz
+    }
+}
+
+const EU868_BANDS: &[SubBand] = &[
+    SubBand::new(863_000_000, 865_000_000, 14, 1),
+    SubBand::new(865_000_000, 868_000_000, 14, 10),
+    SubBand::new(868_000_000, 868_600_000, 14, 10),
+    SubBand::new(868_700_000, 869_200_000, 14, 1),
+    SubBand::new(869_400_000, 869_650_000, 27, 100),
+    SubBand::new(869_700_000, 870_000_000, 14, 10),
+];
+const US915_BANDS: &[SubBand] = &[SubBand::new(902_000_000, 928_000_000, 30, 1000)];
+const AU915_BANDS: &[SubBand] = &[SubBand::new(915_000_000, 928_000_000, 30, 1000)];
+const AS923_BANDS: &[SubBand] = &[SubBand::new(915_000_000, 928_000_000, 16, 1000)];
+const IN865_BANDS: &[SubBand] = &[SubBand::new(865_000_000, 867_000_000, 30, 1000)];
+const KR920_BANDS: &[SubBand] = &[SubBand::new(920_900_000, 923_300_000, 14, 1000)];
+
+/// Regulatory region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Region {
+    /// Europe, 863-870 MHz (ETSI EN 300 220).
+    Eu868,
+    /// North America, 902-928 MHz (FCC part 15.247).
+    Us915,
+    /// Australia, 915-928 MHz.
+    Au915,
+    /// Asia, 915-928 MHz.
+    As923,
+    /// India, 865-867 MHz.
+    In865,
+    /// South Korea, 920.9-923.3 MHz.
+    Kr920,
+}
+
+/// A transmission or reception that the region does not allow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Violation {
+    /// The channel does not lie within any band of the region.
+    Frequency {
+        /// Requested center frequency in Hz.
+        frequency_in_hz: u32,
+        /// Requested bandwidth in Hz.
+        bandwidth_in_hz: u32,
+    },
+    /// The requested output power exceeds the limit of the band.
+    OutputPower {
+        /// Requested output power in dBm.
+        requested_dbm: i32,
+        /// Maximum output power allowed in the band in dBm.
+        max_dbm: i32,
+    },
+    /// The transmission would exceed the maximum dwell time on a channel.
+    DwellTime {
+        /// Time on air of the transmission in microseconds.
+        time_on_air_us: u32,
+        /// Maximum dwell time in microseconds.
+        max_us: u32,
+    },
+}
+
+impl Region {
+    /// Frequency bands usable in the region.
+    pub fn bands(&self) -> &'static [SubBand] {
+        match self {
+            Region::Eu868 => EU868_BANDS,
+            Region::Us915 => US915_BANDS,
+            Region::Au915 => AU915_BANDS,
+            Region::As923 => AS923_BANDS,
+            Region::In865 => IN865_BANDS,
+            Region::Kr920 => KR920_BANDS,
+        }
+    }
+
+    /// Maximum time a single transmission may occupy a channel, in microseconds.
+    pub fn max_dwell_time_us(&self) -> Option<u32> {
+        match self {
+            Region::Us915 | Region::Au915 | Region::As923 => Some(400_000),
+            Region::Eu868 | Region::In865 | Region::Kr920 => None,
+        }
+    }
+
+    /// Look up the band a channel belongs to.
+    pub fn band(&self, frequency_in_hz: u32, bandwidth_in_hz: u32) -> Result<&'static SubBand, Violation> {
+        self.bands()
+            .iter()
+            .find(|band| band.contains(frequency_in_hz, bandwidth_in_hz))
+            .ok_or(Violation::Frequency {
+                frequency_in_hz,
+                bandwidth_in_hz,
+            })
+    }
+
+    /// Check that a transmission is allowed, returning the band it falls into.
+    pub fn check_tx(
+        &self,
+        frequency_in_hz: u32,
+        bandwidth_in_hz: u32,
+        output_power_dbm: i32,
+        time_on_air_us: u32,
+    ) -> Result<&'static SubBand, Violation> {
+        let band = self.band(frequency_in_hz, bandwidth_in_hz)?;
+        if output_power_dbm > band.max_power_dbm {
+            return Err(Violation::OutputPower {
+                requested_dbm: output_power_dbm,
+                max_dbm: band.max_power_dbm,
+            });
+        }
+        if let Some(max_us) = self.max_dwell_time_us() {
+            if time_on_air_us > max_us {
+                return Err(Violation::DwellTime { time_on_air_us, max_us });
+            }
+        }
+        Ok(band)
+    }
+
+    /// Check that the radio may listen on a channel.
+    pub fn check_rx(&self, frequency_in_hz: u32, bandwidth_in_hz: u32) -> Result<(), Violation> {
+        self.band(frequency_in_hz, bandwidth_in_hz).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn eu868_bands() {
+        let band = Region::Eu868.check_tx(868_100_000, 125_000, 14, 1_000_000).unwrap();
+        assert_eq!(band.duty_cycle_permille, 10);
+
+        let band = Region::Eu868.check_tx(869_525_000, 125_000, 27, 1_000_000).unwrap();
+        assert_eq!(band.duty_cycle_permille, 100);
+
+        // The channel would straddle the edge of the g1 band
+        assert_eq!(
+            Region::Eu868.check_tx(868_550_000, 125_000, 14, 0),
+            Err(Violation::Frequency {
+                frequency_in_hz: 868_550_000,
+                bandwidth_in_hz: 125_000
+            })
+        );
+        assert_eq!(
+            Region::Eu868.check_tx(868_100_000, 125_000, 20, 0),
+            Err(Violation::OutputPower {
+                requested_dbm: 20,
+                max_dbm: 14
+            })
+        );
+    }
+
+    #[test]
+    fn us915_dwell_time() {
+        assert!(Region::Us915.check_tx(903_900_000, 125_000, 20, 370_000).is_ok());
+        assert_eq!(
+            Region::Us915.check_tx(903_900_000, 125_000, 20, 1_000_000),
+            Err(Violation::DwellTime {
+                time_on_air_us: 1_000_000,
+                max_us: 400_000
+            })
+        );
+        assert!(Region::Us915.check_rx(868_100_000, 125_000).is_err());
+    }
+}
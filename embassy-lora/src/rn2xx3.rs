@@ -0,0 +1,443 @@
+//! Driver for the Microchip RN2483 (EU868) and RN2903 (US915) LoRaWAN modems.
+//!
+//! These modules run a complete LoRaWAN stack and are controlled through an ASCII command set over
+//! a UART (57600 baud, 8N1 by default). Every command is terminated by `\r\n` and answered with a
+//! single response line. `mac join` and `mac tx` answer twice: once when the command is accepted and
+//! once when the over-the-air exchange has completed.
+
+use core::fmt::Write as _;
+
+use embedded_io_async::{Read, Write};
+
+/// Errors reported by the modem driver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error<E> {
+    /// The UART reported an error.
+    Io(E),
+    /// A response line did not fit into the response buffer.
+    Overflow,
+    /// The modem answered with something the driver did not expect.
+    UnexpectedResponse,
+    /// A parameter was rejected (`invalid_param`).
+    InvalidParam,
+    /// The device has not joined a network (`not_joined`).
+    NotJoined,
+    /// All channels are blocked by duty-cycle restrictions (`no_free_ch`).
+    NoFreeChannel,
+    /// The device was told by the network to stay silent (`silent`).
+    Silent,
+    /// The uplink frame counter rolled over and a new join is required (`frame_counter_err_rejoin_needed`).
+    FrameCounterRolledOver,
+    /// The MAC is not in an idle state (`busy`).
+    Busy,
+    /// The LoRaWAN stack has been paused (`mac_paused`).
+    MacPaused,
+    /// The keys required for the join procedure are not set (`keys_not_init`).
+    KeysNotInitialized,
+    /// The network rejected the join request (`denied`).
+    JoinDenied,
+    /// The payload is too long for the current data rate (`invalid_data_len`).
+    InvalidDataLength,
+    /// The uplink failed, e.g. a confirmed uplink was not acknowledged (`mac_err`).
+    MacError,
+}
+
+impl<E> Error<E> {
+    fn from_status(line: &str) -> Option<Self> {
+        Some(match line {
+            "invalid_param" => Self::InvalidParam,
+            "not_joined" => Self::NotJoined,
+            "no_free_ch" => Self::NoFreeChannel,
+            "silent" => Self::Silent,
+            "frame_counter_err_rejoin_needed" => Self::FrameCounterRolledOver,
+            "busy" => Self::Busy,
+            "mac_paused" => Self::MacPaused,
+            "keys_not_init" => Self::KeysNotInitialized,
+            "denied" => Self::JoinDenied,
+            "invalid_data_len" => Self::InvalidDataLength,
+            "mac_err" => Self::MacError,
+            _ => return None,
+        })
+    }
+}
+
+/// Credentials used to join a network.
+pub enum JoinMode {
+    /// Over-the-air activation.
+    Otaa {
+        /// Device EUI, most significant byte first.
+        deveui: [u8; 8],
+        /// Application (join) EUI, most significant byte first.
+        appeui: [u8; 8],
+        /// Application key.
+        appkey: [u8; 16],
+    },
+    /// Activation by personalization.
+    Abp {
+        /// Device address, most significant byte first.
+        devaddr: [u8; 4],
+        /// Network session key.
+        nwkskey: [u8; 16],
+        /// Application session key.
+        appskey: [u8; 16],
+    },
+}
+
+/// A downlink received in one of the receive windows following an uplink.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Downlink {
+    /// FPort the downlink was received on.
+    pub port: u8,
+    /// Number of payload bytes written to the receive buffer.
+    pub len: usize,
+}
+
+/// Parsed response line.
+#[derive(Debug, PartialEq, Eq)]
+enum Response<'a> {
+    Ok,
+    Accepted,
+    TxOk,
+    Rx { port: u8, data: &'a str },
+    Other(&'a str),
+}
+
+fn parse_response<E>(line: &str) -> Result<Response<'_>, Error<E>> {
+    if let Some(err) = Error::from_status(line) {
+        return Err(err);
+    }
+    Ok(match line {
+        "ok" => Response::Ok,
+        "accepted" => Response::Accepted,
+        "mac_tx_ok" => Response::TxOk,
+        _ => match line.strip_prefix("mac_rx ") {
+            Some(rest) => {
+                let (port, data) = rest.split_once(' ').ok_or(Error::UnexpectedResponse)?;
+                let port = port.parse().map_err(|_| Error::UnexpectedResponse)?;
+                Response::Rx { port, data }
+            }
+            None => Response::Other(line),
+        },
+    })
+}
+
+fn hex_decode<E>(hex: &str, out: &mut [u8]) -> Result<usize, Error<E>> {
+    let hex = hex.as_bytes();
+    if hex.len() % 2 != 0 {
+        return Err(Error::UnexpectedResponse);
+    }
+    let len = hex.len() / 2;
+    if len > out.len() {
+        return Err(Error::Overflow);
+    }
+    let digit = |c: u8| -> Result<u8, Error<E>> {
+        (c as char)
+            .to_digit(16)
+            .map(|d| d as u8)
+            .ok_or(Error::UnexpectedResponse)
+    };
+    for (byte, pair) in out.iter_mut().zip(hex.chunks_exact(2)) {
+        *byte = digit(pair[0])? << 4 | digit(pair[1])?;
+    }
+    Ok(len)
+}
+
+/// Small stack buffer used to format numbers and hex strings.
+struct Scratch {
+    buf: [u8; 64],
+    len: usize,
+}
+
+impl Scratch {
+    fn new() -> Self {
+        Self { buf: [0; 64], len: 0 }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl core::fmt::Write for Scratch {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let end = self.len + s.len();
+        if end > self.buf.len() {
+            return Err(core::fmt::Error);
+        }
+        self.buf[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// RN2483/RN2903 modem attached to a UART.
+///
+/// `N` is the size of the buffer holding a single response line. It must be large enough for the
+/// hex-encoded downlinks the application expects (two characters per payload byte plus the
+/// `mac_rx <port> ` prefix).
+pub struct Rn2xx3<UART, const N: usize = 512> {
+    uart: UART,
+    line: [u8; N],
+}
+
+impl<UART, const N: usize> Rn2xx3<UART, N>
+where
+    UART: Read + Write,
+{
+    /// Create a new driver instance from a UART configured for the modem.
+    pub fn new(uart: UART) -> Self {
+        Self { uart, line: [0; N] }
+    }
+
+    /// Release the UART.
+    pub fn release(self) -> UART {
+        self.uart
+    }
+
+    async fn write(&mut self, bytes: &[u8]) -> Result<(), Error<UART::Error>> {
+        self.uart.write_all(bytes).await.map_err(Error::Io)
+    }
+
+    async fn write_hex(&mut self, bytes: &[u8]) -> Result<(), Error<UART::Error>> {
+        for chunk in bytes.chunks(32) {
+            let mut scratch = Scratch::new();
+            for b in chunk {
+                // 32 bytes encode to exactly 64 characters, this cannot overflow the scratch buffer.
+                let _ = write!(scratch, "{:02X}", b);
+            }
+            self.write(scratch.as_bytes()).await?;
+        }
+        Ok(())
+    }
+
+    async fn write_decimal(&mut self, value: u32) -> Result<(), Error<UART::Error>> {
+        let mut scratch = Scratch::new();
+        let _ = write!(scratch, "{}", value);
+        self.write(scratch.as_bytes()).await
+    }
+
+    async fn end_command(&mut self) -> Result<(), Error<UART::Error>> {
+        self.write(b"\r\n").await?;
+        self.uart.flush().await.map_err(Error::Io)
+    }
+
+    /// Read one response line into the line buffer and return its length, without the terminator.
+    async fn read_line(&mut self) -> Result<usize, Error<UART::Error>> {
+        let mut len = 0;
+        let mut overflow = false;
+        loop {
+            let mut byte = [0u8];
+            if self.uart.read(&mut byte).await.map_err(Error::Io)? == 0 {
+                return Err(Error::UnexpectedResponse);
+            }
+            match byte[0] {
+                b'\n' => break,
+                b'\r' => {}
+                b => {
+                    if len < N {
+                        self.line[len] = b;
+                        len += 1;
+                    } else {
+                        overflow = true;
+                    }
+                }
+            }
+        }
+        if overflow {
+            return Err(Error::Overflow);
+        }
+        Ok(len)
+    }
+
+    async fn response(&mut self) -> Result<Response<'_>, Error<UART::Error>> {
+        let len = self.read_line().await?;
+        let line = core::str::from_utf8(&self.line[..len]).map_err(|_| Error::UnexpectedResponse)?;
+        trace!("rn2xx3 < {}", line);
+        parse_response(line)
+    }
+
+    async fn expect_ok(&mut self) -> Result<(), Error<UART::Error>> {
+        match self.response().await? {
+            Response::Ok => Ok(()),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Send a raw command and return the response line.
+    ///
+    /// The command must not contain the `\r\n` terminator. This is an escape hatch for commands
+    /// not covered by the typed API; error responses are still mapped to [`Error`].
+    pub async fn command(&mut self, command: &str) -> Result<&str, Error<UART::Error>> {
+        trace!("rn2xx3 > {}", command);
+        self.write(command.as_bytes()).await?;
+        self.end_command().await?;
+        match self.response().await? {
+            Response::Ok => Ok("ok"),
+            Response::Other(line) => Ok(line),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Reboot the modem and return its firmware version string.
+    pub async fn reset(&mut self) -> Result<&str, Error<UART::Error>> {
+        self.command("sys reset").await
+    }
+
+    /// Restore the factory configuration and reboot the modem.
+    pub async fn factory_reset(&mut self) -> Result<&str, Error<UART::Error>> {
+        self.command("sys factoryRESET").await
+    }
+
+    /// Read the preprogrammed EUI of the modem.
+    pub async fn hardware_eui(&mut self) -> Result<[u8; 8], Error<UART::Error>> {
+        let line = self.command("sys get hweui").await?;
+        let mut eui = [0; 8];
+        if hex_decode(line, &mut eui)? != eui.len() {
+            return Err(Error::UnexpectedResponse);
+        }
+        Ok(eui)
+    }
+
+    async fn mac_set_hex(&mut self, param: &str, value: &[u8]) -> Result<(), Error<UART::Error>> {
+        self.write(b"mac set ").await?;
+        self.write(param.as_bytes()).await?;
+        self.write(b" ").await?;
+        self.write_hex(value).await?;
+        self.end_command().await?;
+        self.expect_ok().await
+    }
+
+    /// Enable or disable adaptive data rate.
+    pub async fn set_adr(&mut self, enabled: bool) -> Result<(), Error<UART::Error>> {
+        self.command(if enabled { "mac set adr on" } else { "mac set adr off" })
+            .await
+            .map(|_| ())
+    }
+
+    /// Set the data rate used for uplinks.
+    pub async fn set_data_rate(&mut self, data_rate: u8) -> Result<(), Error<UART::Error>> {
+        self.write(b"mac set dr ").await?;
+        self.write_decimal(data_rate as u32).await?;
+        self.end_command().await?;
+        self.expect_ok().await
+    }
+
+    /// Enable or disable an uplink channel.
+    ///
+    /// On the RN2903 this is how a US915 sub-band is selected.
+    pub async fn set_channel_enabled(&mut self, channel: u8, enabled: bool) -> Result<(), Error<UART::Error>> {
+        self.write(b"mac set ch status ").await?;
+        self.write_decimal(channel as u32).await?;
+        self.write(if enabled { b" on" } else { b" off" }).await?;
+        self.end_command().await?;
+        self.expect_ok().await
+    }
+
+    /// Persist the current MAC configuration to the modem's EEPROM.
+    pub async fn save(&mut self) -> Result<(), Error<UART::Error>> {
+        self.command("mac save").await.map(|_| ())
+    }
+
+    /// Configure the credentials and join the network.
+    pub async fn join(&mut self, mode: &JoinMode) -> Result<(), Error<UART::Error>> {
+        let command = match mode {
+            JoinMode::Otaa { deveui, appeui, appkey } => {
+                self.mac_set_hex("deveui", deveui).await?;
+                self.mac_set_hex("appeui", appeui).await?;
+                self.mac_set_hex("appkey", appkey).await?;
+                "mac join otaa"
+            }
+            JoinMode::Abp {
+                devaddr,
+                nwkskey,
+                appskey,
+            } => {
+                self.mac_set_hex("devaddr", devaddr).await?;
+                self.mac_set_hex("nwkskey", nwkskey).await?;
+                self.mac_set_hex("appskey", appskey).await?;
+                "mac join abp"
+            }
+        };
+        self.command(command).await?;
+        match self.response().await? {
+            Response::Accepted => Ok(()),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Send an uplink and wait for the receive windows to close.
+    ///
+    /// A downlink received in either window is written to `rx_buf` and returned.
+    pub async fn transmit(
+        &mut self,
+        confirmed: bool,
+        port: u8,
+        payload: &[u8],
+        rx_buf: &mut [u8],
+    ) -> Result<Option<Downlink>, Error<UART::Error>> {
+        self.write(if confirmed { b"mac tx cnf " } else { b"mac tx uncnf " })
+            .await?;
+        self.write_decimal(port as u32).await?;
+        self.write(b" ").await?;
+        self.write_hex(payload).await?;
+        self.end_command().await?;
+        self.expect_ok().await?;
+
+        match self.response().await? {
+            Response::TxOk => Ok(None),
+            Response::Rx { port, data } => {
+                let len = hex_decode(data, rx_buf)?;
+                Ok(Some(Downlink { port, len }))
+            }
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type E = Error<()>;
+
+    #[test]
+    fn parse_simple_responses() {
+        assert_eq!(parse_response::<()>("ok"), Ok(Response::Ok));
+        assert_eq!(parse_response::<()>("accepted"), Ok(Response::Accepted));
+        assert_eq!(parse_response::<()>("mac_tx_ok"), Ok(Response::TxOk));
+        assert_eq!(
+            parse_response::<()>("RN2483 1.0.1 Dec 15 2015 09:38:09"),
+            Ok(Response::Other("RN2483 1.0.1 Dec 15 2015 09:38:09"))
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(parse_response::<()>("denied"), Err(E::JoinDenied));
+        assert_eq!(parse_response::<()>("no_free_ch"), Err(E::NoFreeChannel));
+        assert_eq!(
+            parse_response::<()>("frame_counter_err_rejoin_needed"),
+            Err(E::FrameCounterRolledOver)
+        );
+    }
+
+    #[test]
+    fn parse_downlink() {
+        assert_eq!(
+            parse_response::<()>("mac_rx 42 DEADbeef"),
+            Ok(Response::Rx {
+                port: 42,
+                data: "DEADbeef"
+            })
+        );
+        assert_eq!(parse_response::<()>("mac_rx x 00"), Err(E::UnexpectedResponse));
+
+        let mut buf = [0; 4];
+        assert_eq!(hex_decode::<()>("DEADbeef", &mut buf), Ok(4));
+        assert_eq!(buf, [0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(hex_decode::<()>("DEADbeef00", &mut buf), Err(E::Overflow));
+        assert_eq!(hex_decode::<()>("ABC", &mut buf), Err(E::UnexpectedResponse));
+    }
+}
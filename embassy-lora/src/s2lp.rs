@@ -0,0 +1,465 @@
+//! Driver for the ST S2-LP sub-GHz transceiver.
+//!
+//! The driver uses the basic packet format with a one byte length field, so payloads are limited to the
+//! 128 byte FIFO. GPIO0 of the S2-LP is configured as the active-low interrupt output and has to be wired to
+//! the `irq` input.
+
+use embedded_hal::digital::v2::OutputPin;
+use embedded_hal_async::delay::DelayUs;
+use embedded_hal_async::digital::Wait;
+use embedded_hal_async::spi::{Operation, SpiDevice};
+
+/// Maximum payload length, bounded by the size of the FIFO.
+pub const MAX_PAYLOAD_LENGTH: usize = 128;
+
+const PART_NUMBER: u8 = 0x03;
+
+const HEADER_WRITE: u8 = 0x00;
+const HEADER_READ: u8 = 0x01;
+const HEADER_COMMAND: u8 = 0x80;
+
+mod reg {
+    pub const GPIO0_CONF: u8 = 0x00;
+    pub const SYNT3: u8 = 0x05;
+    pub const MOD4: u8 = 0x0E;
+    pub const MOD1: u8 = 0x11;
+    pub const MOD0: u8 = 0x12;
+    pub const PCKTCTRL6: u8 = 0x2B;
+    pub const PCKTCTRL3: u8 = 0x2E;
+    pub const PCKTCTRL2: u8 = 0x2F;
+    pub const PCKTCTRL1: u8 = 0x30;
+    pub const PCKTLEN1: u8 = 0x31;
+    pub const SYNC3: u8 = 0x33;
+    pub const IRQ_MASK3: u8 = 0x50;
+    pub const PA_POWER8: u8 = 0x5A;
+    pub const PA_POWER0: u8 = 0x62;
+    pub const XO_RCO_CONF1: u8 = 0x6C;
+    pub const RSSI_LEVEL: u8 = 0xA2;
+    pub const RX_PCKT_LEN1: u8 = 0xA4;
+    pub const DEVICE_INFO1: u8 = 0xF0;
+    pub const IRQ_STATUS3: u8 = 0xFA;
+    pub const FIFO: u8 = 0xFF;
+}
+
+mod cmd {
+    pub const TX: u8 = 0x60;
+    pub const RX: u8 = 0x61;
+    pub const READY: u8 = 0x62;
+    pub const STANDBY: u8 = 0x63;
+    pub const SLEEP: u8 = 0x64;
+    pub const SABORT: u8 = 0x67;
+    pub const SRES: u8 = 0x70;
+    pub const FLUSH_RX_FIFO: u8 = 0x71;
+    pub const FLUSH_TX_FIFO: u8 = 0x72;
+}
+
+mod irq {
+    pub const RX_DATA_READY: u32 = 1 << 0;
+    pub const RX_DATA_DISC: u32 = 1 << 1;
+    pub const TX_DATA_SENT: u32 = 1 << 2;
+    pub const CRC_ERROR: u32 = 1 << 4;
+    pub const TX_FIFO_ERROR: u32 = 1 << 5;
+    pub const RX_FIFO_ERROR: u32 = 1 << 6;
+}
+
+/// Errors reported by the S2-LP driver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error<E> {
+    /// The SPI bus reported an error.
+    Spi(E),
+    /// The shutdown pin could not be driven.
+    Shutdown,
+    /// Waiting on the interrupt pin failed.
+    Irq,
+    /// The device did not identify itself as an S2-LP.
+    UnknownPartNumber(u8),
+    /// A configuration value is out of the range supported by the chip.
+    InvalidConfiguration,
+    /// The payload does not fit in the FIFO.
+    PayloadTooLarge,
+    /// A packet was received with an invalid CRC.
+    Crc,
+    /// A packet was discarded by the packet handler, or the FIFO over- or underflowed.
+    Discarded,
+}
+
+/// Modulation scheme.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Modulation {
+    /// 2-FSK
+    Fsk,
+    /// 2-GFSK with BT = 1
+    GfskBt1,
+    /// 2-GFSK with BT = 0.5
+    GfskBt05,
+    /// On-off keying
+    Ook,
+}
+
+impl Modulation {
+    fn mod_type(self) -> u8 {
+        match self {
+            Modulation::Fsk => 0x0,
+            Modulation::GfskBt1 => 0x2,
+            Modulation::Ook => 0x5,
+            Modulation::GfskBt05 => 0xA,
+        }
+    }
+}
+
+/// Radio and packet configuration.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Config {
+    /// Carrier frequency in Hz, within 413-527 MHz or 826-1055 MHz.
+    pub frequency_in_hz: u32,
+    /// Data rate in bits per second.
+    pub data_rate: u32,
+    /// Frequency deviation in Hz, ignored for OOK.
+    pub deviation_in_hz: u32,
+    /// Modulation scheme.
+    pub modulation: Modulation,
+    /// Output power in dBm, between -30 and +14.
+    pub output_power: i8,
+    /// Preamble length in bytes.
+    pub preamble_length: u8,
+    /// 32 bit synchronization word.
+    pub sync_word: u32,
+    /// Append and check a 16 bit CRC (polynomial 0x1021).
+    pub crc_on: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            frequency_in_hz: 868_000_000,
+            data_rate: 38_400,
+            deviation_in_hz: 20_000,
+            modulation: Modulation::GfskBt1,
+            output_power: 10,
+            preamble_length: 4,
+            sync_word: 0x88888888,
+            crc_on: true,
+        }
+    }
+}
+
+/// Reception quality of a packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PacketStatus {
+    /// RSSI in dBm, sampled when the sync word was detected.
+    pub rssi: i16,
+}
+
+/// SYNT register value and band select bit for a carrier frequency.
+fn synth_word(frequency_in_hz: u32, xtal_hz: u32) -> Option<(u32, bool)> {
+    // The synthesizer output is divided by 4 in the high band and by 8 in the middle band.
+    let (band_factor, middle_band) = match frequency_in_hz {
+        826_000_000..=1_055_000_000 => (4u64, false),
+        413_000_000..=527_000_000 => (8u64, true),
+        _ => return None,
+    };
+    let synt = ((frequency_in_hz as u64 * band_factor / 2) << 20) / xtal_hz as u64;
+    Some((synt as u32 & 0x0FFF_FFFF, middle_band))
+}
+
+/// Mantissa and exponent of the data rate, relative to the digital clock.
+fn data_rate_word(data_rate: u32, fdig: u32) -> Option<(u16, u8)> {
+    let target = (data_rate as u64) << 33;
+    for e in 1..15u32 {
+        let m = target / ((fdig as u64) << e);
+        if (65_536..131_072).contains(&m) {
+            return Some(((m - 65_536) as u16, e as u8));
+        }
+    }
+    None
+}
+
+/// Mantissa and exponent of the frequency deviation, relative to the crystal.
+fn deviation_word(deviation_in_hz: u32, xtal_hz: u32) -> Option<(u8, u8)> {
+    let target = (deviation_in_hz as u64) << 22;
+    let m = target / xtal_hz as u64;
+    if m < 256 {
+        return Some((m as u8, 0));
+    }
+    for e in 1..16u32 {
+        let m = target / ((xtal_hz as u64) << (e - 1));
+        if (256..512).contains(&m) {
+            return Some(((m - 256) as u8, e as u8));
+        }
+    }
+    None
+}
+
+/// PA level for an output power, in 0.5 dB steps below the +14 dBm maximum.
+fn pa_level(output_power: i8) -> u8 {
+    (1 + 2 * (14 - output_power.clamp(-30, 14) as i16)) as u8
+}
+
+/// S2-LP transceiver.
+pub struct S2lp<SPI, SDN, IRQ, DLY> {
+    spi: SPI,
+    sdn: SDN,
+    irq: IRQ,
+    delay: DLY,
+    xtal_hz: u32,
+}
+
+impl<SPI, SDN, IRQ, DLY> S2lp<SPI, SDN, IRQ, DLY>
+where
+    SPI: SpiDevice,
+    SDN: OutputPin,
+    IRQ: Wait,
+    DLY: DelayUs,
+{
+    /// Create a driver instance. `xtal_hz` is the frequency of the crystal or TCXO clocking the chip.
+    pub fn new(spi: SPI, sdn: SDN, irq: IRQ, delay: DLY, xtal_hz: u32) -> Self {
+        Self {
+            spi,
+            sdn,
+            irq,
+            delay,
+            xtal_hz,
+        }
+    }
+
+    /// Frequency of the digital domain. Crystals above 30 MHz are divided by two.
+    fn fdig(&self) -> u32 {
+        if self.xtal_hz > 30_000_000 {
+            self.xtal_hz / 2
+        } else {
+            self.xtal_hz
+        }
+    }
+
+    async fn write_registers(&mut self, address: u8, data: &[u8]) -> Result<(), Error<SPI::Error>> {
+        self.spi
+            .transaction(&mut [Operation::Write(&[HEADER_WRITE, address]), Operation::Write(data)])
+            .await
+            .map_err(Error::Spi)
+    }
+
+    async fn read_registers(&mut self, address: u8, data: &mut [u8]) -> Result<(), Error<SPI::Error>> {
+        self.spi
+            .transaction(&mut [Operation::Write(&[HEADER_READ, address]), Operation::Read(data)])
+            .await
+            .map_err(Error::Spi)
+    }
+
+    async fn read_register(&mut self, address: u8) -> Result<u8, Error<SPI::Error>> {
+        let mut value = [0];
+        self.read_registers(address, &mut value).await?;
+        Ok(value[0])
+    }
+
+    async fn modify_register(&mut self, address: u8, mask: u8, value: u8) -> Result<(), Error<SPI::Error>> {
+        let current = self.read_register(address).await?;
+        self.write_registers(address, &[(current & !mask) | (value & mask)])
+            .await
+    }
+
+    async fn command(&mut self, command: u8) -> Result<(), Error<SPI::Error>> {
+        self.spi
+            .transaction(&mut [Operation::Write(&[HEADER_COMMAND, command])])
+            .await
+            .map_err(Error::Spi)
+    }
+
+    /// Read and clear the interrupt status.
+    async fn irq_status(&mut self) -> Result<u32, Error<SPI::Error>> {
+        let mut status = [0; 4];
+        self.read_registers(reg::IRQ_STATUS3, &mut status).await?;
+        Ok(u32::from_be_bytes(status))
+    }
+
+    async fn set_irq_mask(&mut self, mask: u32) -> Result<(), Error<SPI::Error>> {
+        self.write_registers(reg::IRQ_MASK3, &mask.to_be_bytes()).await
+    }
+
+    async fn wait_irq(&mut self) -> Result<u32, Error<SPI::Error>> {
+        self.irq.wait_for_low().await.map_err(|_| Error::Irq)?;
+        self.irq_status().await
+    }
+
+    /// Power up and reset the chip, check its identity and apply `config`.
+    pub async fn init(&mut self, config: &Config) -> Result<(), Error<SPI::Error>> {
+        self.sdn.set_high().map_err(|_| Error::Shutdown)?;
+        self.delay.delay_ms(1).await;
+        self.sdn.set_low().map_err(|_| Error::Shutdown)?;
+        self.delay.delay_ms(2).await;
+
+        self.command(cmd::SRES).await?;
+        self.delay.delay_ms(2).await;
+
+        let part_number = self.read_register(reg::DEVICE_INFO1).await?;
+        if part_number != PART_NUMBER {
+            return Err(Error::UnknownPartNumber(part_number));
+        }
+        debug!("S2-LP detected");
+
+        // GPIO0: nIRQ, digital output low power
+        self.write_registers(reg::GPIO0_CONF, &[0x02]).await?;
+        // PD_CLKDIV: the digital clock divider is only needed above 30 MHz
+        let pd_clkdiv = if self.xtal_hz > 30_000_000 { 0x00 } else { 0x10 };
+        self.modify_register(reg::XO_RCO_CONF1, 0x10, pd_clkdiv).await?;
+
+        self.configure(config).await
+    }
+
+    /// Apply a radio and packet configuration. The chip must be in the ready state.
+    pub async fn configure(&mut self, config: &Config) -> Result<(), Error<SPI::Error>> {
+        let (synt, middle_band) =
+            synth_word(config.frequency_in_hz, self.xtal_hz).ok_or(Error::InvalidConfiguration)?;
+        let (dr_m, dr_e) = data_rate_word(config.data_rate, self.fdig()).ok_or(Error::InvalidConfiguration)?;
+        let (fdev_m, fdev_e) =
+            deviation_word(config.deviation_in_hz, self.xtal_hz).ok_or(Error::InvalidConfiguration)?;
+
+        // SYNT3 keeps the charge pump current in its upper bits
+        let synt = synt.to_be_bytes();
+        let synt3 = (synt[0] & 0x0F) | if middle_band { 0x10 } else { 0x00 };
+        self.modify_register(reg::SYNT3, 0x1F, synt3).await?;
+        self.write_registers(reg::SYNT3 + 1, &synt[1..]).await?;
+
+        let dr_m = dr_m.to_be_bytes();
+        self.write_registers(reg::MOD4, &[dr_m[0], dr_m[1], config.modulation.mod_type() << 4 | dr_e])
+            .await?;
+        self.modify_register(reg::MOD1, 0x0F, fdev_e).await?;
+        self.write_registers(reg::MOD0, &[fdev_m]).await?;
+
+        // The preamble length is counted in "01" bit pairs, the sync word is 32 bits long
+        let preamble_pairs = config.preamble_length as u16 * 4;
+        self.write_registers(
+            reg::PCKTCTRL6,
+            &[(32 << 2) | (preamble_pairs >> 8) as u8, preamble_pairs as u8],
+        )
+        .await?;
+        // Basic packet format, variable length, optional CRC 0x1021
+        self.modify_register(reg::PCKTCTRL3, 0xC0, 0x00).await?;
+        self.write_registers(reg::PCKTCTRL2, &[0x01]).await?;
+        self.write_registers(reg::PCKTCTRL1, &[if config.crc_on { 3 << 5 } else { 0 }])
+            .await?;
+        self.write_registers(reg::SYNC3, &config.sync_word.to_be_bytes())
+            .await?;
+
+        // Use the PA_POWER8 slot without ramping
+        self.write_registers(reg::PA_POWER8, &[pa_level(config.output_power)])
+            .await?;
+        self.modify_register(reg::PA_POWER0, 0x07, 0x07).await
+    }
+
+    /// Transmit a packet.
+    pub async fn tx(&mut self, payload: &[u8]) -> Result<(), Error<SPI::Error>> {
+        if payload.len() > MAX_PAYLOAD_LENGTH {
+            return Err(Error::PayloadTooLarge);
+        }
+
+        self.command(cmd::FLUSH_TX_FIFO).await?;
+        self.write_registers(reg::PCKTLEN1, &(payload.len() as u16).to_be_bytes())
+            .await?;
+        self.write_registers(reg::FIFO, payload).await?;
+
+        self.set_irq_mask(irq::TX_DATA_SENT | irq::TX_FIFO_ERROR).await?;
+        self.irq_status().await?;
+        self.command(cmd::TX).await?;
+
+        let status = self.wait_irq().await?;
+        if status & irq::TX_FIFO_ERROR != 0 {
+            self.command(cmd::FLUSH_TX_FIFO).await?;
+            return Err(Error::Discarded);
+        }
+        Ok(())
+    }
+
+    /// Listen until a packet is received, and return its length and reception quality.
+    pub async fn rx(&mut self, buf: &mut [u8]) -> Result<(usize, PacketStatus), Error<SPI::Error>> {
+        self.command(cmd::FLUSH_RX_FIFO).await?;
+        self.set_irq_mask(irq::RX_DATA_READY | irq::RX_DATA_DISC | irq::CRC_ERROR | irq::RX_FIFO_ERROR)
+            .await?;
+        self.irq_status().await?;
+        self.command(cmd::RX).await?;
+
+        let status = self.wait_irq().await?;
+        if status & irq::CRC_ERROR != 0 {
+            self.command(cmd::FLUSH_RX_FIFO).await?;
+            return Err(Error::Crc);
+        }
+        if status & irq::RX_DATA_READY == 0 {
+            self.command(cmd::FLUSH_RX_FIFO).await?;
+            return Err(Error::Discarded);
+        }
+
+        let mut len = [0; 2];
+        self.read_registers(reg::RX_PCKT_LEN1, &mut len).await?;
+        let len = u16::from_be_bytes(len) as usize;
+        if len > buf.len() {
+            self.command(cmd::FLUSH_RX_FIFO).await?;
+            return Err(Error::PayloadTooLarge);
+        }
+        self.read_registers(reg::FIFO, &mut buf[..len]).await?;
+        let rssi = self.read_register(reg::RSSI_LEVEL).await? as i16 - 146;
+
+        Ok((len, PacketStatus { rssi }))
+    }
+
+    /// Abort an ongoing transmission or reception and return to the ready state.
+    pub async fn abort(&mut self) -> Result<(), Error<SPI::Error>> {
+        self.command(cmd::SABORT).await?;
+        self.command(cmd::READY).await
+    }
+
+    /// Put the chip in standby, keeping the configuration but stopping the crystal.
+    pub async fn standby(&mut self) -> Result<(), Error<SPI::Error>> {
+        self.command(cmd::STANDBY).await
+    }
+
+    /// Put the chip in sleep, keeping the configuration with only the RC oscillator running.
+    pub async fn sleep(&mut self) -> Result<(), Error<SPI::Error>> {
+        self.command(cmd::SLEEP).await
+    }
+
+    /// Return to the ready state from standby or sleep.
+    pub async fn wake(&mut self) -> Result<(), Error<SPI::Error>> {
+        self.command(cmd::READY).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn synthesizer() {
+        // 868 MHz with a 50 MHz crystal: 868e6 * 2 * 2^20 / 50e6
+        assert_eq!(synth_word(868_000_000, 50_000_000), Some((36_406_558, false)));
+        assert_eq!(synth_word(433_920_000, 50_000_000), Some((36_399_847, true)));
+        assert_eq!(synth_word(600_000_000, 50_000_000), None);
+    }
+
+    #[test]
+    fn data_rate() {
+        // 38400 bps with a 25 MHz digital clock
+        let (m, e) = data_rate_word(38_400, 25_000_000).unwrap();
+        let actual = (25_000_000u64 * ((65_536 + m as u64) << e)) >> 33;
+        assert_eq!(e, 7);
+        assert!(actual.abs_diff(38_400) < 10);
+    }
+
+    #[test]
+    fn deviation() {
+        let (m, e) = deviation_word(20_000, 50_000_000).unwrap();
+        let actual = (50_000_000u64 * ((256 + m as u64) << (e - 1))) >> 22;
+        assert!(actual.abs_diff(20_000) < 100);
+
+        assert_eq!(deviation_word(1_000, 50_000_000), Some((83, 0)));
+    }
+
+    #[test]
+    fn power() {
+        assert_eq!(pa_level(14), 1);
+        assert_eq!(pa_level(20), 1);
+        assert_eq!(pa_level(0), 29);
+    }
+}
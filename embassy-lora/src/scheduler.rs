@@ -0,0 +1,359 @@
+//! Airtime-fair sharing of a radio between several clients.
+//!
+//! When several logical applications share one radio, e.g. telemetry, firmware updates and a debug console,
+//! [`AirtimeScheduler`] decides which of them transmits next so that each gets a share of the airtime
+//! proportional to its weight. It implements deficit round robin over time on air: every round, each client
+//! with a pending message is credited a quantum of airtime scaled by its weight, and may transmit once its
+//! credit covers the time on air of its message. A client sending long messages therefore waits longer between
+//! transmissions, but is never starved.
+//!
+//! Each client keeps its own [`OutboundQueue`], and [`AirtimeScheduler::flush`] sends their messages to a
+//! [`Sink`] in the order decided by the scheduler. Sinks that do not enforce the duty cycle themselves, such as
+//! point-to-point radios, can be flushed with `flush_with_duty_cycle` instead, which holds every message until
+//! the sub-band of the channel opens and accounts its time on air. The scheduler can also be driven by hand with
+//! [`set_pending`](AirtimeScheduler::set_pending) and [`pick`](AirtimeScheduler::pick).
+
+use embassy_sync::blocking_mutex::raw::RawMutex;
+
+#[cfg(feature = "time")]
+use crate::duty_cycle::DutyCycle;
+use crate::queue::{OutboundQueue, Sink};
+
+/// Handle of a client registered with a scheduler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ClientId(u8);
+
+/// Errors reported by the scheduler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// All client slots are in use.
+    NoFreeSlot,
+    /// A weight of zero was requested.
+    InvalidWeight,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Client {
+    weight: u8,
+    deficit_us: u32,
+    pending_us: Option<u32>,
+}
+
+/// Scheduler arbitrating the airtime of up to `N` clients.
+pub struct AirtimeScheduler<const N: usize> {
+    quantum_us: u32,
+    clients: [Option<Client>; N],
+    cursor: usize,
+    credited: bool,
+}
+
+impl<const N: usize> AirtimeScheduler<N> {
+    /// Create a scheduler crediting `quantum_us` of airtime per unit of weight every round.
+    ///
+    /// The quantum trades fairness over short periods for fewer rounds per decision. It should be in the order
+    /// of the time on air of a typical message.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `quantum_us` is zero, since no client would ever be credited enough airtime to transmit.
+    pub const fn new(quantum_us: u32) -> Self {
+        core::assert!(quantum_us > 0);
+        Self {
+            quantum_us,
+            clients: [None; N],
+            cursor: 0,
+            credited: false,
+        }
+    }
+
+    /// Register a client with the given weight.
+    pub fn register(&mut self, weight: u8) -> Result<ClientId, Error> {
+        if weight == 0 {
+            return Err(Error::InvalidWeight);
+        }
+        let index = self
+            .clients
+            .iter()
+            .position(|client| client.is_none())
+            .ok_or(Error::NoFreeSlot)?;
+        self.clients[index] = Some(Client {
+            weight,
+            deficit_us: 0,
+            pending_us: None,
+        });
+        Ok(ClientId(index as u8))
+    }
+
+    /// Remove a client, freeing its slot.
+    pub fn unregister(&mut self, client: ClientId) {
+        self.clients[client.0 as usize] = None;
+    }
+
+    /// Declare the time on air of the next message of `client`, or `None` if it has nothing to send.
+    pub fn set_pending(&mut self, client: ClientId, time_on_air_us: Option<u32>) {
+        if let Some(client) = &mut self.clients[client.0 as usize] {
+            client.pending_us = time_on_air_us;
+        }
+    }
+
+    /// Pick the client allowed to transmit next.
+    ///
+    /// The pending message of the client returned is considered sent, call [`set_pending`](Self::set_pending)
+    /// again once the client has another one.
+    pub fn pick(&mut self) -> Option<ClientId> {
+        if !self.clients.iter().flatten().any(|client| client.pending_us.is_some()) {
+            return None;
+        }
+
+        let mut visited = 0;
+        loop {
+            if visited == N {
+                self.skip_rounds();
+                visited = 0;
+            }
+            let index = self.cursor;
+            if let Some(client) = &mut self.clients[index] {
+                match client.pending_us {
+                    // Idle clients do not accumulate credit
+                    None => client.deficit_us = 0,
+                    Some(time_on_air_us) => {
+                        if !self.credited {
+                            client.deficit_us = client
+                                .deficit_us
+                                .saturating_add(self.quantum_us.saturating_mul(client.weight as u32));
+                            self.credited = true;
+                        }
+                        if client.deficit_us >= time_on_air_us {
+                            client.deficit_us -= time_on_air_us;
+                            client.pending_us = None;
+                            return Some(ClientId(index as u8));
+                        }
+                    }
+                }
+            }
+            self.cursor = (self.cursor + 1) % N;
+            self.credited = false;
+            visited += 1;
+        }
+    }
+
+    /// Credit at once the rounds in which no client would get enough credit to transmit, so that a message much
+    /// longer than the quantum is picked within a couple of rounds.
+    fn skip_rounds(&mut self) {
+        let rounds = self
+            .clients
+            .iter()
+            .flatten()
+            .filter_map(|client| {
+                let missing_us = client.pending_us?.saturating_sub(client.deficit_us);
+                let quantum_us = self.quantum_us.saturating_mul(client.weight as u32);
+                Some(missing_us.saturating_sub(1) / quantum_us)
+            })
+            .min()
+            .unwrap_or(0);
+        for client in self.clients.iter_mut().flatten() {
+            if client.pending_us.is_some() {
+                let credit_us = rounds.saturating_mul(self.quantum_us.saturating_mul(client.weight as u32));
+                client.deficit_us = client.deficit_us.saturating_add(credit_us);
+            }
+        }
+    }
+
+    /// Give back the airtime of a message picked but not sent, which is pending again.
+    fn refund(&mut self, client: ClientId, time_on_air_us: u32) {
+        if let Some(client) = &mut self.clients[client.0 as usize] {
+            client.deficit_us = client.deficit_us.saturating_add(time_on_air_us);
+            client.pending_us = Some(time_on_air_us);
+        }
+    }
+
+    /// Declare the next message of each queue as pending and pick the queue allowed to transmit next.
+    fn pick_queue<'a, M: RawMutex, const Q: usize, const MTU: usize>(
+        &mut self,
+        queues: &[(ClientId, &'a OutboundQueue<M, Q, MTU>)],
+        time_on_air_us: &impl Fn(&[u8]) -> u32,
+        buf: &mut [u8; MTU],
+    ) -> Option<(ClientId, &'a OutboundQueue<M, Q, MTU>)> {
+        for (client, queue) in queues {
+            let pending_us = queue.peek(buf).map(|(_, _, len)| time_on_air_us(&buf[..len]));
+            self.set_pending(*client, pending_us);
+        }
+        let picked = self.pick()?;
+        queues.iter().find(|(client, _)| *client == picked).copied()
+    }
+
+    /// Send the messages of the clients' queues to `sink` in the order decided by the scheduler, until every
+    /// queue is empty or the sink returns an error.
+    ///
+    /// `queues` pairs each client with its queue, and `time_on_air_us` gives the time on air of a message. Returns
+    /// the number of messages sent. A message the sink fails to send stays queued and keeps its credit.
+    pub async fn flush<M, S, const Q: usize, const MTU: usize>(
+        &mut self,
+        queues: &[(ClientId, &OutboundQueue<M, Q, MTU>)],
+        sink: &mut S,
+        time_on_air_us: impl Fn(&[u8]) -> u32,
+    ) -> Result<usize, S::Error>
+    where
+        M: RawMutex,
+        S: Sink,
+    {
+        let mut buf = [0; MTU];
+        let mut sent = 0;
+        while let Some((client, queue)) = self.pick_queue(queues, &time_on_air_us, &mut buf) {
+            let Some((seq, priority, len)) = queue.peek(&mut buf) else {
+                continue;
+            };
+            if let Err(err) = sink.send(priority, &buf[..len]).await {
+                self.refund(client, time_on_air_us(&buf[..len]));
+                return Err(err);
+            }
+            queue.remove(seq);
+            sent += 1;
+        }
+        Ok(sent)
+    }
+
+    /// Like [`flush`](Self::flush), for sinks transmitting on a single channel without enforcing its duty cycle.
+    ///
+    /// Each message waits until the sub-band of the channel opens for its priority, see
+    /// [`DutyCycle::set_high_priority_reserve`], and its time on air is accounted to `duty_cycle` once sent.
+    #[cfg(feature = "time")]
+    pub async fn flush_with_duty_cycle<M, S, const Q: usize, const MTU: usize>(
+        &mut self,
+        queues: &[(ClientId, &OutboundQueue<M, Q, MTU>)],
+        sink: &mut S,
+        time_on_air_us: impl Fn(&[u8]) -> u32,
+        duty_cycle: &mut DutyCycle,
+        frequency_in_hz: u32,
+        bandwidth_in_hz: u32,
+    ) -> Result<usize, S::Error>
+    where
+        M: RawMutex,
+        S: Sink,
+    {
+        let mut buf = [0; MTU];
+        let mut sent = 0;
+        loop {
+            // Wait before picking, so that the choice accounts for the messages queued meanwhile
+            duty_cycle.wait(frequency_in_hz, bandwidth_in_hz).await;
+            let Some((client, queue)) = self.pick_queue(queues, &time_on_air_us, &mut buf) else {
+                return Ok(sent);
+            };
+            let Some((seq, priority, len)) = queue.peek(&mut buf) else {
+                continue;
+            };
+            // Routine messages may have to leave the airtime reserved for alarms
+            duty_cycle
+                .wait_with_priority(priority, frequency_in_hz, bandwidth_in_hz)
+                .await;
+            let message_us = time_on_air_us(&buf[..len]);
+            if let Err(err) = sink.send(priority, &buf[..len]).await {
+                self.refund(client, message_us);
+                return Err(err);
+            }
+            duty_cycle.record(
+                frequency_in_hz,
+                bandwidth_in_hz,
+                message_us,
+                embassy_time::Instant::now(),
+            );
+            queue.remove(seq);
+            sent += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+    use futures_executor::block_on;
+
+    use super::*;
+    use crate::queue::{OverflowPolicy, TestSink};
+
+    fn run(scheduler: &mut AirtimeScheduler<4>, clients: &[(ClientId, u32)], picks: usize) -> [u32; 4] {
+        let mut airtime = [0; 4];
+        for (client, time_on_air_us) in clients {
+            scheduler.set_pending(*client, Some(*time_on_air_us));
+        }
+        for _ in 0..picks {
+            let picked = scheduler.pick().unwrap();
+            let (_, time_on_air_us) = clients.iter().find(|(client, _)| *client == picked).unwrap();
+            airtime[picked.0 as usize] += time_on_air_us;
+            scheduler.set_pending(picked, Some(*time_on_air_us));
+        }
+        airtime
+    }
+
+    #[test]
+    fn airtime_follows_weights() {
+        let mut scheduler = AirtimeScheduler::<4>::new(50_000);
+        let telemetry = scheduler.register(1).unwrap();
+        let alarms = scheduler.register(3).unwrap();
+        let airtime = run(&mut scheduler, &[(telemetry, 50_000), (alarms, 50_000)], 40);
+        assert_eq!(airtime[0] * 3, airtime[1]);
+    }
+
+    #[test]
+    fn long_messages_are_not_starved() {
+        let mut scheduler = AirtimeScheduler::<4>::new(50_000);
+        let fuota = scheduler.register(1).unwrap();
+        let telemetry = scheduler.register(1).unwrap();
+        let airtime = run(&mut scheduler, &[(fuota, 1_000_000), (telemetry, 40_000)], 100);
+        assert!(airtime[0] >= 1_000_000);
+        assert!(airtime[0].abs_diff(airtime[1]) <= 1_000_000);
+    }
+
+    #[test]
+    fn long_messages_are_picked_without_spinning() {
+        let mut scheduler = AirtimeScheduler::<4>::new(1);
+        let fuota = scheduler.register(1).unwrap();
+        let telemetry = scheduler.register(2).unwrap();
+        scheduler.set_pending(fuota, Some(u32::MAX));
+        scheduler.set_pending(telemetry, Some(u32::MAX / 4));
+        assert_eq!(scheduler.pick(), Some(telemetry));
+        assert_eq!(scheduler.pick(), Some(fuota));
+    }
+
+    #[test]
+    #[should_panic]
+    fn zero_quantum() {
+        AirtimeScheduler::<1>::new(0);
+    }
+
+    #[test]
+    fn flush_queues() {
+        let mut scheduler = AirtimeScheduler::<4>::new(10);
+        let telemetry = scheduler.register(1).unwrap();
+        let alarms = scheduler.register(3).unwrap();
+        let telemetry_queue: OutboundQueue<NoopRawMutex, 4, 1> = OutboundQueue::new(OverflowPolicy::DropNewest);
+        let alarm_queue: OutboundQueue<NoopRawMutex, 4, 1> = OutboundQueue::new(OverflowPolicy::DropNewest);
+        for i in 0..4 {
+            telemetry_queue.push(&[i]).unwrap();
+            alarm_queue.push(&[10 + i]).unwrap();
+        }
+
+        let queues = [(telemetry, &telemetry_queue), (alarms, &alarm_queue)];
+        let mut sink = TestSink::<1>::new(5);
+        assert_eq!(block_on(scheduler.flush(&queues, &mut sink, |_| 10)), Err(()));
+        assert_eq!(sink.sent[..5], [[0], [10], [11], [12], [1]]);
+        assert_eq!(telemetry_queue.len() + alarm_queue.len(), 3);
+
+        sink.budget = 8;
+        assert_eq!(block_on(scheduler.flush(&queues, &mut sink, |_| 10)), Ok(3));
+        assert!(telemetry_queue.is_empty() && alarm_queue.is_empty());
+    }
+
+    #[test]
+    fn registration() {
+        let mut scheduler = AirtimeScheduler::<1>::new(1_000);
+        assert_eq!(scheduler.register(0), Err(Error::InvalidWeight));
+        let client = scheduler.register(1).unwrap();
+        assert_eq!(scheduler.register(1), Err(Error::NoFreeSlot));
+        assert_eq!(scheduler.pick(), None);
+        scheduler.unregister(client);
+        assert!(scheduler.register(2).is_ok());
+    }
+}
@@ -0,0 +1,233 @@
+//! Time synchronization over point-to-point links.
+//!
+//! Outside of LoRaWAN coverage there is no network to provide a common time base. With this module, one node
+//! acts as a master and periodically broadcasts beacons carrying its clock. The other nodes feed the beacons they
+//! receive to a [`SyncedClock`], which estimates the offset and drift of the local clock relative to the master,
+//! so that nodes can for instance sample their sensors at the same time.
+//!
+//! The master time is captured just before the transmission starts and the receiver captures its own time once
+//! the packet is received, so the accuracy is in the order of a millisecond, dominated by the time it takes to
+//! configure the radios. The drift is fitted over the last [`DRIFT_WINDOW`] beacons so that this jitter averages
+//! out instead of being extrapolated.
+
+use embassy_time::Instant;
+use embedded_hal_async::delay::DelayUs;
+use lora_phy::mod_traits::RadioKind;
+
+use crate::p2p::{self, CarrierSense, LinkConfig, P2pRadio};
+
+const BEACON_TAG: u8 = b'T';
+
+/// Length of a time beacon in bytes.
+pub const BEACON_LENGTH: usize = 9;
+/// Number of beacons the drift is estimated from.
+pub const DRIFT_WINDOW: usize = 8;
+
+/// Errors reported by the time synchronization protocol.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The point-to-point link reported an error.
+    Link(p2p::Error),
+    /// The packet received is not a time beacon.
+    InvalidBeacon,
+    /// The buffer is too small to hold a beacon.
+    BufferTooSmall,
+}
+
+impl From<p2p::Error> for Error {
+    fn from(err: p2p::Error) -> Self {
+        Error::Link(err)
+    }
+}
+
+/// Encode a beacon carrying `network_time_us`, and return its length.
+pub fn encode_beacon(network_time_us: u64, buf: &mut [u8]) -> Result<usize, Error> {
+    if buf.len() < BEACON_LENGTH {
+        return Err(Error::BufferTooSmall);
+    }
+    buf[0] = BEACON_TAG;
+    buf[1..BEACON_LENGTH].copy_from_slice(&network_time_us.to_le_bytes());
+    Ok(BEACON_LENGTH)
+}
+
+/// Decode a beacon, and return the network time it carries.
+pub fn decode_beacon(frame: &[u8]) -> Result<u64, Error> {
+    if frame.len() != BEACON_LENGTH || frame[0] != BEACON_TAG {
+        return Err(Error::InvalidBeacon);
+    }
+    let mut time = [0; 8];
+    time.copy_from_slice(&frame[1..]);
+    Ok(u64::from_le_bytes(time))
+}
+
+/// Broadcast a beacon carrying the local time, making this node the master of the network.
+pub async fn broadcast<RK, DLY, CS>(radio: &mut P2pRadio<RK, DLY, CS>, config: &LinkConfig) -> Result<(), Error>
+where
+    RK: RadioKind,
+    DLY: DelayUs,
+    CS: CarrierSense,
+{
+    let mut buf = [0; BEACON_LENGTH];
+    let len = encode_beacon(Instant::now().as_micros(), &mut buf)?;
+    radio.send(config, &buf[..len]).await?;
+    Ok(())
+}
+
+/// Wait for a beacon and synchronize `clock` to it.
+pub async fn receive<RK, DLY, CS>(
+    radio: &mut P2pRadio<RK, DLY, CS>,
+    config: &LinkConfig,
+    clock: &mut SyncedClock,
+) -> Result<(), Error>
+where
+    RK: RadioKind,
+    DLY: DelayUs,
+    CS: CarrierSense,
+{
+    let mut buf = [0; BEACON_LENGTH];
+    let (len, _) = radio.receive(config, &mut buf).await?;
+    let received_at = Instant::now();
+    let network_time_us = decode_beacon(&buf[..len])?;
+    clock.on_beacon(network_time_us, received_at, config.time_on_air_us(len));
+    Ok(())
+}
+
+/// A local clock disciplined by time beacons.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SyncedClock {
+    /// Local and network time of the last beacons, in microseconds, the oldest first once the window is full.
+    beacons: [(u64, u64); DRIFT_WINDOW],
+    /// Number of beacons received, up to [`DRIFT_WINDOW`].
+    count: usize,
+    /// Local time of the last beacon and network time fitted at that instant, in microseconds.
+    reference: Option<(u64, u64)>,
+    /// Rate of the network clock relative to the local one, in parts per billion.
+    drift_ppb: i64,
+}
+
+impl SyncedClock {
+    /// Create a clock that is not synchronized yet.
+    pub const fn new() -> Self {
+        Self {
+            beacons: [(0, 0); DRIFT_WINDOW],
+            count: 0,
+            reference: None,
+            drift_ppb: 0,
+        }
+    }
+
+    /// Synchronize to a beacon sent at `network_time_us` and received at `received_at`.
+    ///
+    /// `time_on_air_us` is the time on air of the beacon, which elapsed between the master capturing its time and
+    /// the end of the reception. The offset and drift are fitted by least squares over the last beacons.
+    pub fn on_beacon(&mut self, network_time_us: u64, received_at: Instant, time_on_air_us: u32) {
+        let local = received_at.as_micros();
+        let network = network_time_us + time_on_air_us as u64;
+        if self.count == DRIFT_WINDOW {
+            self.beacons.rotate_left(1);
+            self.count -= 1;
+        }
+        self.beacons[self.count] = (local, network);
+        self.count += 1;
+
+        // Fit the offset of the network clock against the local time, relative to the first beacon
+        let beacons = &self.beacons[..self.count];
+        let (first_local, first_network) = beacons[0];
+        let n = self.count as i128;
+        let (mut sum_x, mut sum_y, mut sum_xx, mut sum_xy) = (0i128, 0i128, 0i128, 0i128);
+        for (local, network) in beacons {
+            let x = *local as i128 - first_local as i128;
+            let y = (*network as i128 - first_network as i128) - x;
+            sum_x += x;
+            sum_y += y;
+            sum_xx += x * x;
+            sum_xy += x * y;
+        }
+        let denominator = n * sum_xx - sum_x * sum_x;
+        if denominator > 0 {
+            self.drift_ppb = ((n * sum_xy - sum_x * sum_y) * 1_000_000_000 / denominator) as i64;
+        }
+        // Offset of the fitted line at the last beacon
+        let x = local as i128 - first_local as i128;
+        let offset = (sum_y * 1_000_000_000 + (x * n - sum_x) * self.drift_ppb as i128) / (n * 1_000_000_000);
+        let fitted = first_network as i128 + x + offset;
+        trace!("time beacon, drift {} ppb", self.drift_ppb);
+        self.reference = Some((local, fitted.max(0) as u64));
+    }
+
+    /// Returns true once a beacon has been received.
+    pub fn is_synced(&self) -> bool {
+        self.reference.is_some()
+    }
+
+    /// Estimated drift of the network clock relative to the local one, in parts per billion.
+    pub fn drift_ppb(&self) -> i64 {
+        self.drift_ppb
+    }
+
+    /// Network time in microseconds at local time `local`.
+    pub fn network_time_us(&self, local: Instant) -> Option<u64> {
+        let (last_local, last_network) = self.reference?;
+        let elapsed = local.as_micros() as i128 - last_local as i128;
+        let elapsed_network = elapsed + elapsed * self.drift_ppb as i128 / 1_000_000_000;
+        Some((last_network as i128 + elapsed_network).max(0) as u64)
+    }
+
+    /// Local time at which the network clock reaches `network_time_us`, e.g. to schedule a timer.
+    pub fn local_time(&self, network_time_us: u64) -> Option<Instant> {
+        let (last_local, last_network) = self.reference?;
+        let elapsed_network = network_time_us as i128 - last_network as i128;
+        let elapsed = elapsed_network * 1_000_000_000 / (1_000_000_000 + self.drift_ppb as i128);
+        Some(Instant::from_micros((last_local as i128 + elapsed).max(0) as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn beacon_round_trip() {
+        let mut buf = [0; BEACON_LENGTH];
+        let len = encode_beacon(0x0123_4567_89ab_cdef, &mut buf).unwrap();
+        assert_eq!(decode_beacon(&buf[..len]), Ok(0x0123_4567_89ab_cdef));
+        assert_eq!(decode_beacon(&buf[..len - 1]), Err(Error::InvalidBeacon));
+        assert_eq!(encode_beacon(0, &mut buf[..4]), Err(Error::BufferTooSmall));
+    }
+
+    #[test]
+    fn clock_tracks_offset_and_drift() {
+        let mut clock = SyncedClock::new();
+        assert_eq!(clock.network_time_us(Instant::from_micros(0)), None);
+
+        clock.on_beacon(999_000, Instant::from_micros(0), 1_000);
+        assert_eq!(clock.network_time_us(Instant::from_micros(500)), Some(1_000_500));
+
+        // The network clock runs 10 ppm faster than the local one
+        clock.on_beacon(10_999_100, Instant::from_micros(10_000_000), 1_000);
+        assert_eq!(clock.drift_ppb(), 10_000);
+        assert_eq!(
+            clock.network_time_us(Instant::from_micros(20_000_000)),
+            Some(21_000_200)
+        );
+        assert_eq!(clock.local_time(21_000_200), Some(Instant::from_micros(20_000_000)));
+    }
+
+    #[test]
+    fn jittered_beacons() {
+        // The network clock runs 10 ppm faster, the beacons arrive every 10 s with 1 ms of jitter
+        let network = |local: u64| 5_000_000 + local + local / 100_000;
+        let mut clock = SyncedClock::new();
+        for n in 0..DRIFT_WINDOW as u64 {
+            let local = n * 10_000_000;
+            let jitter = if n % 2 == 0 { 1_000 } else { 0 };
+            clock.on_beacon(network(local) - 1_000, Instant::from_micros(local + jitter), 1_000);
+        }
+        // The last two beacons alone would be 100 ppm off
+        assert!((clock.drift_ppb() - 10_000).abs() < 10_000, "{}", clock.drift_ppb());
+        let local = 90_000_000;
+        let error = clock.network_time_us(Instant::from_micros(local)).unwrap() as i64 - network(local) as i64;
+        assert!(error.abs() < 1_000, "{}", error);
+    }
+}
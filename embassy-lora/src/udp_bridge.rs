@@ -0,0 +1,99 @@
+//! Bridge between point-to-point LoRa links and UDP.
+//!
+//! On a device that also has an [`embassy_net`] stack, [`run`] forwards every LoRa packet received to a UDP
+//! endpoint, and transmits every datagram received on the UDP socket over LoRa. This turns the device into a
+//! simple LoRa to IP gateway for applications that do not use LoRaWAN.
+
+use embassy_net::udp::{self, UdpSocket};
+use embassy_net::IpEndpoint;
+use embedded_hal_async::delay::DelayUs;
+use futures::future::{select, Either};
+use futures::pin_mut;
+use lora_phy::mod_traits::RadioKind;
+
+use crate::p2p::{self, CarrierSense, LinkConfig, P2pRadio};
+
+/// Largest payload of a LoRa packet.
+const MAX_PAYLOAD_LENGTH: usize = 255;
+
+/// Errors that stop the bridge.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The point-to-point link reported an error.
+    Link(p2p::Error),
+    /// The UDP socket reported an error.
+    Udp(udp::Error),
+}
+
+impl From<p2p::Error> for Error {
+    fn from(err: p2p::Error) -> Self {
+        Error::Link(err)
+    }
+}
+
+impl From<udp::Error> for Error {
+    fn from(err: udp::Error) -> Self {
+        Error::Udp(err)
+    }
+}
+
+/// Forward packets between `radio` and `socket` until an error occurs.
+///
+/// LoRa packets are sent to `remote`, and datagrams from any sender are transmitted over LoRa. The socket must
+/// already be bound. Datagrams larger than a LoRa packet are truncated.
+///
+/// Transmissions rejected by the radio, because of regulatory limits or a busy channel, are dropped rather than
+/// stopping the bridge.
+pub async fn run<RK, DLY, CS>(
+    radio: &mut P2pRadio<RK, DLY, CS>,
+    config: &LinkConfig,
+    socket: &UdpSocket<'_>,
+    remote: IpEndpoint,
+) -> Error
+where
+    RK: RadioKind,
+    DLY: DelayUs,
+    CS: CarrierSense,
+{
+    let mut lora_buf = [0; MAX_PAYLOAD_LENGTH];
+    let mut udp_buf = [0; MAX_PAYLOAD_LENGTH];
+
+    loop {
+        let event = {
+            let lora_rx = radio.receive(config, &mut lora_buf);
+            let udp_rx = socket.recv_from(&mut udp_buf);
+            pin_mut!(lora_rx);
+            pin_mut!(udp_rx);
+            match select(lora_rx, udp_rx).await {
+                Either::Left((result, _)) => Either::Left(result),
+                Either::Right((result, _)) => Either::Right(result),
+            }
+        };
+
+        let result = match event {
+            Either::Left(Ok((len, status))) => {
+                trace!("forwarding {} bytes from LoRa, rssi {}", len, status.rssi);
+                socket.send_to(&lora_buf[..len], remote).await.map_err(Error::from)
+            }
+            Either::Right(Ok((len, _))) => {
+                trace!("forwarding {} bytes from UDP", len);
+                match radio.send(config, &udp_buf[..len]).await {
+                    Err(p2p::Error::Regulatory(_)) | Err(p2p::Error::ChannelBusy) => {
+                        warn!("datagram not transmitted over LoRa");
+                        Ok(())
+                    }
+                    result => result.map_err(Error::from),
+                }
+            }
+            // Listening simply restarts when the link has a receive timeout
+            Either::Left(Err(p2p::Error::RxTimeout)) => Ok(()),
+            Either::Left(Err(err)) => Err(err.into()),
+            Either::Right(Err(err)) => Err(err.into()),
+        };
+
+        if let Err(err) = result {
+            return err;
+        }
+    }
+}
@@ -36,6 +36,18 @@ pub fn stop_ready(stop_mode: StopMode) -> bool {
     unsafe { EXECUTOR.as_mut().unwrap() }.stop_ready(stop_mode)
 }
 
+/// Keep the executor out of Stop2, for drivers whose peripherals must not be stopped in the middle of an operation.
+///
+/// Every call must be balanced by a call to [`allow_stop2`].
+pub fn block_stop2() {
+    critical_section::with(|_| unsafe { crate::rcc::REFCOUNT_STOP2 += 1 });
+}
+
+/// Release a [`block_stop2`], letting the executor enter Stop2 once nothing else blocks it.
+pub fn allow_stop2() {
+    critical_section::with(|_| unsafe { crate::rcc::REFCOUNT_STOP2 -= 1 });
+}
+
 #[non_exhaustive]
 pub enum StopMode {
     Stop2,